@@ -0,0 +1,24 @@
+-- Baseline schema, formerly db/init.sql.
+-- IF NOT EXISTS lets databases created from that script adopt migrations without being rebuilt.
+CREATE TABLE IF NOT EXISTS users (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR(255) UNIQUE NOT NULL,
+    display_name VARCHAR(255) NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS songs (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR(255) NOT NULL,
+    artist VARCHAR(255) NOT NULL,
+    uri VARCHAR(255) UNIQUE NOT NULL,
+    album_cover_url TEXT NOT NULL,
+    CONSTRAINT unique_name_artist UNIQUE (name, artist)
+);
+
+CREATE TABLE IF NOT EXISTS rankings (
+    user_id INT REFERENCES users(id) ON DELETE CASCADE,
+    song_id INT REFERENCES songs(id) ON DELETE CASCADE,
+    rank INT CHECK (rank >= 1 AND rank <= 10),
+    PRIMARY KEY (user_id, song_id),
+    CONSTRAINT unique_user_rank UNIQUE (user_id, rank)
+);
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS spotify_accounts (
+    user_id INT PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    spotify_user_id VARCHAR(255) NOT NULL,
+    refresh_token TEXT NOT NULL,
+    access_token TEXT,
+    access_token_expires_at TIMESTAMPTZ
+);
@@ -0,0 +1,8 @@
+-- A ranked list frozen at preview time so publishing pushes exactly what was reviewed.
+CREATE TABLE playlist_previews (
+    id SERIAL PRIMARY KEY,
+    created_by INT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    method VARCHAR(32) NOT NULL,
+    songs JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
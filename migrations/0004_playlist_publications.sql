@@ -0,0 +1,16 @@
+-- Which Spotify playlist a preview was published to, and which 100-track batches made it there,
+-- so a failed publish can be rerun without duplicating tracks.
+CREATE TABLE playlist_publications (
+    id SERIAL PRIMARY KEY,
+    preview_id INT NOT NULL UNIQUE REFERENCES playlist_previews(id) ON DELETE CASCADE,
+    playlist_id VARCHAR(255) NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE playlist_upload_batches (
+    publication_id INT NOT NULL REFERENCES playlist_publications(id) ON DELETE CASCADE,
+    batch_index INT NOT NULL,
+    track_count INT NOT NULL,
+    uploaded_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (publication_id, batch_index)
+);
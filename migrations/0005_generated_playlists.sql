@@ -0,0 +1,6 @@
+-- The playlist each user publishes into, reused on every regeneration.
+CREATE TABLE generated_playlists (
+    owner_id INT PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    playlist_id VARCHAR(255) NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
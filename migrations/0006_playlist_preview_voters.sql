@@ -0,0 +1,2 @@
+-- How many ballots went into a preview, for playlist descriptions. Older previews predate the count.
+ALTER TABLE playlist_previews ADD COLUMN voter_count INT NOT NULL DEFAULT 0;
@@ -0,0 +1,6 @@
+-- Argon2 password hashes (PHC strings). Users without a row here have not set a password yet.
+CREATE TABLE user_credentials (
+    user_id INT PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE,
+    password_hash TEXT NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,17 @@
+-- Codes an admin hands out so friends can sign up when registration is invite-only.
+CREATE TABLE invite_codes (
+    code TEXT PRIMARY KEY,
+    created_by INT REFERENCES users(id) ON DELETE SET NULL,
+    max_uses INT NOT NULL CHECK (max_uses > 0),
+    uses INT NOT NULL DEFAULT 0 CHECK (uses <= max_uses),
+    expires_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- Who signed up with which code.
+CREATE TABLE invite_redemptions (
+    code TEXT NOT NULL REFERENCES invite_codes(code) ON DELETE CASCADE,
+    user_id INT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    redeemed_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (code, user_id)
+);
@@ -0,0 +1,53 @@
+-- Friend groups sharing one deployment. Ballots, connections and playlists are all kept per circle.
+CREATE TABLE circles (
+    id SERIAL PRIMARY KEY,
+    name VARCHAR(100) NOT NULL,
+    join_code TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE circle_members (
+    circle_id INT NOT NULL REFERENCES circles(id) ON DELETE CASCADE,
+    user_id INT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    role TEXT NOT NULL DEFAULT 'member' CHECK (role IN ('owner', 'admin', 'member')),
+    joined_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (circle_id, user_id)
+);
+
+CREATE INDEX circle_members_user_id_idx ON circle_members (user_id);
+CREATE UNIQUE INDEX circle_members_one_owner_idx ON circle_members (circle_id) WHERE role = 'owner';
+
+-- Everyone already here ends up in one shared circle, owned by whoever signed up first.
+INSERT INTO circles (name, join_code)
+SELECT 'Everyone', upper(substr(md5(random()::text), 1, 10))
+WHERE EXISTS (SELECT 1 FROM users);
+
+INSERT INTO circle_members (circle_id, user_id, role)
+SELECT c.id, u.id, CASE WHEN u.id = (SELECT MIN(id) FROM users) THEN 'owner' ELSE 'member' END
+FROM circles c
+CROSS JOIN users u;
+
+-- A ballot belongs to a membership, so leaving a circle withdraws it.
+ALTER TABLE rankings ADD COLUMN circle_id INT;
+UPDATE rankings SET circle_id = (SELECT MIN(id) FROM circles);
+ALTER TABLE rankings ALTER COLUMN circle_id SET NOT NULL;
+ALTER TABLE rankings DROP CONSTRAINT rankings_pkey;
+ALTER TABLE rankings DROP CONSTRAINT unique_user_rank;
+ALTER TABLE rankings ADD PRIMARY KEY (circle_id, user_id, song_id);
+ALTER TABLE rankings ADD CONSTRAINT unique_user_rank UNIQUE (circle_id, user_id, rank);
+ALTER TABLE rankings ADD CONSTRAINT rankings_membership_fkey
+    FOREIGN KEY (circle_id, user_id) REFERENCES circle_members(circle_id, user_id) ON DELETE CASCADE;
+
+ALTER TABLE playlist_previews ADD COLUMN circle_id INT REFERENCES circles(id) ON DELETE CASCADE;
+UPDATE playlist_previews SET circle_id = (SELECT MIN(id) FROM circles);
+ALTER TABLE playlist_previews ALTER COLUMN circle_id SET NOT NULL;
+
+-- Each owner keeps one playlist per circle.
+ALTER TABLE generated_playlists ADD COLUMN circle_id INT REFERENCES circles(id) ON DELETE CASCADE;
+UPDATE generated_playlists SET circle_id = (SELECT MIN(id) FROM circles);
+ALTER TABLE generated_playlists ALTER COLUMN circle_id SET NOT NULL;
+ALTER TABLE generated_playlists DROP CONSTRAINT generated_playlists_pkey;
+ALTER TABLE generated_playlists ADD PRIMARY KEY (owner_id, circle_id);
+
+-- Invites can drop the new user straight into a circle.
+ALTER TABLE invite_codes ADD COLUMN circle_id INT REFERENCES circles(id) ON DELETE CASCADE;
@@ -0,0 +1,46 @@
+-- Yearly (or any other length) editions within a circle. Ballots are kept per season, and a season's
+-- ballots stop changing once it closes.
+CREATE TABLE seasons (
+    id SERIAL PRIMARY KEY,
+    circle_id INT NOT NULL REFERENCES circles(id) ON DELETE CASCADE,
+    name VARCHAR(100) NOT NULL,
+    opens_at TIMESTAMPTZ NOT NULL,
+    closes_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    CHECK (closes_at > opens_at),
+    UNIQUE (id, circle_id)
+);
+
+CREATE INDEX seasons_circle_id_idx ON seasons (circle_id, opens_at);
+
+-- Every circle gets this calendar year as its first season, holding the ballots it already has.
+INSERT INTO seasons (circle_id, name, opens_at, closes_at)
+SELECT id, to_char(now(), 'YYYY'), date_trunc('year', now()), date_trunc('year', now()) + interval '1 year'
+FROM circles;
+
+ALTER TABLE rankings ADD COLUMN season_id INT;
+UPDATE rankings r SET season_id = s.id FROM seasons s WHERE s.circle_id = r.circle_id;
+ALTER TABLE rankings ALTER COLUMN season_id SET NOT NULL;
+ALTER TABLE rankings DROP CONSTRAINT rankings_pkey;
+ALTER TABLE rankings DROP CONSTRAINT unique_user_rank;
+ALTER TABLE rankings ADD PRIMARY KEY (season_id, user_id, song_id);
+ALTER TABLE rankings ADD CONSTRAINT unique_user_rank UNIQUE (season_id, user_id, rank);
+ALTER TABLE rankings ADD CONSTRAINT rankings_season_fkey
+    FOREIGN KEY (season_id, circle_id) REFERENCES seasons(id, circle_id) ON DELETE CASCADE;
+
+ALTER TABLE playlist_previews ADD COLUMN season_id INT REFERENCES seasons(id) ON DELETE CASCADE;
+UPDATE playlist_previews p SET season_id = s.id FROM seasons s WHERE s.circle_id = p.circle_id;
+ALTER TABLE playlist_previews ALTER COLUMN season_id SET NOT NULL;
+
+-- Each owner keeps one playlist per season, so publishing a new season never overwrites an earlier one.
+ALTER TABLE generated_playlists ADD COLUMN season_id INT;
+UPDATE generated_playlists g SET season_id = s.id FROM seasons s WHERE s.circle_id = g.circle_id;
+ALTER TABLE generated_playlists ALTER COLUMN season_id SET NOT NULL;
+ALTER TABLE generated_playlists ADD CONSTRAINT generated_playlists_season_fkey
+    FOREIGN KEY (season_id, circle_id) REFERENCES seasons(id, circle_id) ON DELETE CASCADE;
+ALTER TABLE generated_playlists DROP CONSTRAINT generated_playlists_pkey;
+ALTER TABLE generated_playlists ADD PRIMARY KEY (owner_id, circle_id, season_id);
+
+-- Leaving a circle only withdraws ballots in seasons that have not closed, so a ballot can outlive its
+-- author's membership.
+ALTER TABLE rankings DROP CONSTRAINT rankings_membership_fkey;
@@ -0,0 +1,12 @@
+-- Voting phases within a season: ballots are taken until locks_at, the playlist is generated at lock
+-- time, and members get to see it from reveals_at. Existing seasons lock and reveal when they close.
+ALTER TABLE seasons ADD COLUMN locks_at TIMESTAMPTZ;
+ALTER TABLE seasons ADD COLUMN reveals_at TIMESTAMPTZ;
+UPDATE seasons SET locks_at = closes_at, reveals_at = closes_at;
+ALTER TABLE seasons ALTER COLUMN locks_at SET NOT NULL;
+ALTER TABLE seasons ALTER COLUMN reveals_at SET NOT NULL;
+ALTER TABLE seasons ADD CONSTRAINT seasons_phase_order_check
+    CHECK (opens_at <= locks_at AND locks_at <= reveals_at AND reveals_at <= closes_at);
+
+-- The preview generated when voting locked.
+ALTER TABLE seasons ADD COLUMN generated_preview_id INT REFERENCES playlist_previews(id) ON DELETE SET NULL;
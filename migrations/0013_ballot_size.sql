@@ -0,0 +1,7 @@
+-- How many songs a ballot holds, per season. Ranks run from 1 to the season's ballot size, which the app
+-- checks on save; the table only keeps ranks positive.
+ALTER TABLE seasons ADD COLUMN ballot_size INT NOT NULL DEFAULT 10
+    CHECK (ballot_size >= 1 AND ballot_size <= 100);
+
+ALTER TABLE rankings DROP CONSTRAINT rankings_rank_check;
+ALTER TABLE rankings ADD CONSTRAINT rankings_rank_check CHECK (rank >= 1);
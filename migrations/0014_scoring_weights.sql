@@ -0,0 +1,18 @@
+-- The weights behind compatibility scores and the classic playlist ranking. A row with no season holds the
+-- circle's weights, which new seasons copy; a season row holds that season's own. Without a row the
+-- built-in defaults apply, so existing seasons keep scoring the way they always have.
+CREATE TABLE scoring_weights (
+    id SERIAL PRIMARY KEY,
+    circle_id INT NOT NULL REFERENCES circles(id) ON DELETE CASCADE,
+    season_id INT REFERENCES seasons(id) ON DELETE CASCADE,
+    shared_song_points DOUBLE PRECISION NOT NULL,
+    shared_artist_points DOUBLE PRECISION NOT NULL,
+    artist_rank_diff_weight DOUBLE PRECISION NOT NULL,
+    rank_bonus DOUBLE PRECISION NOT NULL,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    CHECK (shared_song_points >= 0 AND shared_artist_points >= 0
+        AND artist_rank_diff_weight >= 0 AND rank_bonus >= 0)
+);
+
+CREATE UNIQUE INDEX scoring_weights_circle_idx ON scoring_weights (circle_id) WHERE season_id IS NULL;
+CREATE UNIQUE INDEX scoring_weights_season_idx ON scoring_weights (season_id);
@@ -1,9 +1,28 @@
+use crate::api::credentials::{self, AuthConfig, Registration};
 use crate::api::db;
-use crate::api::types::{AuthResponse, ErrorResponse, LoginRequest, SignupRequest};
+use crate::api::error::ApiError;
+use crate::api::sessions::{self, AdminUser, AuthenticatedUser, Session, UserAgent, SESSION_COOKIE};
+use crate::api::spotify_token::SpotifyTokenManager;
+use crate::api::types::{
+    AuthResponse, CreateInviteRequest, FieldError, InviteInfo, LoginRequest,
+    LogoutEverywhereResponse, SessionInfo, SignupRequest, SpotifyCallbackQuery,
+};
 use crate::DB_POOL;
-use rocket::http::{Cookie, CookieJar, Status};
+use base64::engine::general_purpose;
+use base64::Engine;
+use rand::RngCore;
+use reqwest::Client;
+use rocket::http::{Cookie, CookieJar, SameSite};
+use rocket::response::Redirect;
 use rocket::serde::json::Json;
+use rocket::time::format_description::well_known::Rfc3339;
 use rocket::time::Duration;
+use rocket::State;
+use sqlx::types::time::OffsetDateTime;
+use std::sync::Arc;
+
+const MAX_INVITE_USES: i32 = 1000;
+const MAX_INVITE_DAYS: i64 = 365;
 
 fn is_valid_username(username: &str) -> bool {
     !username.is_empty()
@@ -17,49 +36,68 @@ fn is_valid_username(username: &str) -> bool {
 pub async fn login(
     cookies: &CookieJar<'_>,
     request: Json<LoginRequest>,
-) -> Result<Json<AuthResponse>, (Status, Json<ErrorResponse>)> {
+    auth_config: &State<AuthConfig>,
+    user_agent: UserAgent,
+) -> Result<Json<AuthResponse>, ApiError> {
     let db_pool = DB_POOL.get().unwrap();
     let username = request.username.trim();
 
     if !is_valid_username(username) {
-        return Err((
-            Status::BadRequest,
-            Json(ErrorResponse {
-                error: "Username can only contain letters, numbers, and underscores".to_string(),
-            }),
+        return Err(ApiError::invalid_field(
+            "username",
+            "Username can only contain letters, numbers, and underscores",
         ));
     }
 
     let user = db::get_user_by_username(db_pool, username)
-        .await
-        .map_err(|err| {
-            (
-                Status::InternalServerError,
-                Json(ErrorResponse {
-                    error: format!("Database error: {}", err),
-                }),
-            )
-        })?;
+        .await?;
 
     match user {
         Some(u) => {
-            cookies.add_private(
-                Cookie::build(("user", u.name.clone()))
-                    .http_only(true)
-                    .max_age(Duration::days(30)),
-            );
+            check_password(&u, request.password.as_deref(), auth_config).await?;
+
+            sessions::start(cookies, &u, &user_agent).await?;
             Ok(Json(AuthResponse {
                 success: true,
                 username: Some(u.name),
                 display_name: None,
             }))
         }
-        None => Err((
-            Status::NotFound,
-            Json(ErrorResponse {
-                error: "Username not found".to_string(),
-            }),
+        None => Err(ApiError::NotFound("Username not found".to_string())),
+    }
+}
+
+/// Lets `user` in if `password` matches their stored hash. A user who has no password yet
+/// either gets in without one or, when a password is given, has it set as theirs.
+async fn check_password(
+    user: &db::User,
+    password: Option<&str>,
+    auth_config: &AuthConfig,
+) -> Result<(), ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    let stored = db::get_password_hash(db_pool, &user.id)
+        .await?;
+
+    match (stored, password) {
+        (Some(password_hash), Some(password)) => {
+            if credentials::verify_password(password.to_string(), password_hash).await? {
+                Ok(())
+            } else {
+                Err(ApiError::NotAuthenticated("Incorrect password".to_string()))
+            }
+        }
+        (Some(_), None) => Err(ApiError::NotAuthenticated("Password required".to_string())),
+        (None, Some(password)) => {
+            credentials::validate_password(password)?;
+            let password_hash = credentials::hash_password(password.to_string()).await?;
+            db::set_password_hash(db_pool, &user.id, &password_hash).await?;
+            Ok(())
+        }
+        (None, None) if auth_config.require_passwords => Err(ApiError::NotAuthenticated(
+            "This account has no password yet; enter one to set it and log in".to_string(),
         )),
+        (None, None) => Ok(()),
     }
 }
 
@@ -67,68 +105,105 @@ pub async fn login(
 pub async fn signup(
     cookies: &CookieJar<'_>,
     request: Json<SignupRequest>,
-) -> Result<Json<AuthResponse>, (Status, Json<ErrorResponse>)> {
+    auth_config: &State<AuthConfig>,
+    user_agent: UserAgent,
+) -> Result<Json<AuthResponse>, ApiError> {
     let db_pool = DB_POOL.get().unwrap();
     let username = request.username.trim();
     let first_name = request.first_name.trim();
     let last_name = request.last_name.trim();
 
     if !is_valid_username(username) {
-        return Err((
-            Status::BadRequest,
-            Json(ErrorResponse {
-                error: "Username can only contain letters, numbers, and underscores".to_string(),
-            }),
+        return Err(ApiError::invalid_field(
+            "username",
+            "Username can only contain letters, numbers, and underscores",
         ));
     }
 
-    if first_name.is_empty() || last_name.is_empty() {
-        return Err((
-            Status::BadRequest,
-            Json(ErrorResponse {
-                error: "Please fill in all fields".to_string(),
-            }),
+    let mut missing = Vec::new();
+    if first_name.is_empty() {
+        missing.push(FieldError {
+            field: "first_name".to_string(),
+            message: "First name is required".to_string(),
+        });
+    }
+    if last_name.is_empty() {
+        missing.push(FieldError {
+            field: "last_name".to_string(),
+            message: "Last name is required".to_string(),
+        });
+    }
+    if !missing.is_empty() {
+        return Err(ApiError::Validation {
+            message: "Please fill in all fields".to_string(),
+            fields: missing,
+        });
+    }
+
+    let invite_code = request
+        .invite_code
+        .as_deref()
+        .map(|code| code.trim().to_ascii_uppercase())
+        .filter(|code| !code.is_empty());
+    if invite_code.is_none() && auth_config.registration == Registration::InviteOnly {
+        return Err(ApiError::invalid_field(
+            "invite_code",
+            "Signing up needs an invite code",
         ));
     }
 
+    let password = match request.password.as_deref() {
+        Some(password) => {
+            credentials::validate_password(password)?;
+            Some(password)
+        }
+        None if auth_config.require_passwords => {
+            return Err(ApiError::invalid_field("password", "Please choose a password"))
+        }
+        None => None,
+    };
+
     let existing = db::get_user_by_username(db_pool, username)
-        .await
-        .map_err(|err| {
-            (
-                Status::InternalServerError,
-                Json(ErrorResponse {
-                    error: format!("Database error: {}", err),
-                }),
-            )
-        })?;
+        .await?;
 
     if existing.is_some() {
-        return Err((
-            Status::Conflict,
-            Json(ErrorResponse {
-                error: "Username already taken".to_string(),
-            }),
-        ));
+        return Err(ApiError::Conflict("Username already taken".to_string()));
     }
 
-    let user = db::create_user(db_pool, username, first_name, last_name)
-        .await
-        .map_err(|err| {
-            (
-                Status::InternalServerError,
-                Json(ErrorResponse {
-                    error: format!("Failed to create user: {}", err),
-                }),
-            )
-        })?;
+    // Hashing is deliberately slow, so it waits until the username is known to be free.
+    let password_hash = match password {
+        Some(password) => Some(credentials::hash_password(password.to_string()).await?),
+        None => None,
+    };
+
+    // A code is still redeemed when registration is open, so it keeps counting who joined with it.
+    let password_hash = password_hash.as_deref();
+    let created = match invite_code {
+        Some(code) => {
+            db::create_invited_user(db_pool, &code, username, first_name, last_name, password_hash).await
+        }
+        None => db::create_user(db_pool, username, first_name, last_name, password_hash)
+            .await
+            .map(Some),
+    };
+    let user = match created {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            return Err(ApiError::invalid_field(
+                "invite_code",
+                "This invite code is invalid, expired or used up",
+            ))
+        }
+        // Someone else took the name since the check above.
+        Err(err) if db::is_unique_violation(&err) => {
+            return Err(ApiError::Conflict("Username already taken".to_string()))
+        }
+        Err(err) => return Err(err.into()),
+    };
 
     let display_name = format!("{} {}", first_name, last_name);
 
-    cookies.add_private(
-        Cookie::build(("user", user.name.clone()))
-            .http_only(true)
-            .max_age(Duration::days(30)),
-    );
+    sessions::start(cookies, &user, &user_agent).await?;
 
     Ok(Json(AuthResponse {
         success: true,
@@ -138,12 +213,218 @@ pub async fn signup(
 }
 
 #[post("/api/logout")]
-pub async fn logout(cookies: &CookieJar<'_>) -> Json<AuthResponse> {
-    cookies.remove_private("user");
-    // Keep api_token for Spotify API access (client credentials)
-    Json(AuthResponse {
+pub async fn logout(
+    cookies: &CookieJar<'_>,
+    session: Option<Session>,
+) -> Result<Json<AuthResponse>, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    if let Some(session) = session {
+        db::delete_session(db_pool, &session.id)
+            .await?;
+    }
+    cookies.remove_private(SESSION_COOKIE);
+
+    Ok(Json(AuthResponse {
+        success: true,
+        username: None,
+        display_name: None,
+    }))
+}
+
+/// Ends every session the user has, including this one, e.g. after a cookie may have leaked.
+#[post("/api/logout-everywhere")]
+pub async fn logout_everywhere(
+    cookies: &CookieJar<'_>,
+    session: Session,
+) -> Result<Json<LogoutEverywhereResponse>, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    let sessions_ended = db::delete_user_sessions(db_pool, &session.user.id)
+        .await?;
+    cookies.remove_private(SESSION_COOKIE);
+
+    Ok(Json(LogoutEverywhereResponse {
+        success: true,
+        sessions_ended,
+    }))
+}
+
+#[get("/api/sessions")]
+pub async fn list_sessions(
+    session: Session,
+) -> Result<Json<Vec<SessionInfo>>, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    let rows = db::get_user_sessions(db_pool, &session.user.id)
+        .await?;
+
+    let timestamp = |at: OffsetDateTime| at.format(&Rfc3339).unwrap_or_default();
+    Ok(Json(
+        rows.into_iter()
+            .map(|row| SessionInfo {
+                current: row.id == session.id,
+                user_agent: row.user_agent,
+                created_at: timestamp(row.created_at),
+                last_seen_at: timestamp(row.last_seen_at),
+                expires_at: timestamp(row.expires_at),
+            })
+            .collect(),
+    ))
+}
+
+fn invite_info(invite: db::InviteCode) -> InviteInfo {
+    let timestamp = |at: OffsetDateTime| at.format(&Rfc3339).unwrap_or_default();
+    InviteInfo {
+        code: invite.code,
+        circle_id: invite.circle_id,
+        max_uses: invite.max_uses,
+        uses: invite.uses,
+        expires_at: invite.expires_at.map(timestamp),
+        created_at: timestamp(invite.created_at),
+    }
+}
+
+#[post("/api/invites", format = "json", data = "<request>")]
+pub async fn create_invite(
+    admin: AdminUser,
+    request: Json<CreateInviteRequest>,
+) -> Result<Json<InviteInfo>, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    let max_uses = request.max_uses.unwrap_or(1);
+    if !(1..=MAX_INVITE_USES).contains(&max_uses) {
+        return Err(ApiError::invalid_field(
+            "max_uses",
+            format!("An invite can be used 1 to {} times", MAX_INVITE_USES),
+        ));
+    }
+
+    let expires_at = match request.expires_in_days {
+        Some(days) if (1..=MAX_INVITE_DAYS).contains(&days) => {
+            Some(OffsetDateTime::now_utc() + Duration::days(days))
+        }
+        Some(_) => {
+            return Err(ApiError::invalid_field(
+                "expires_in_days",
+                format!("An invite can last 1 to {} days", MAX_INVITE_DAYS),
+            ))
+        }
+        None => None,
+    };
+
+    if let Some(circle_id) = request.circle_id {
+        if db::get_circle(db_pool, &circle_id).await?.is_none() {
+            return Err(ApiError::invalid_field("circle_id", "No such circle"));
+        }
+    }
+
+    let invite = db::insert_invite_code(
+        db_pool,
+        &credentials::new_code(),
+        &admin.id,
+        request.circle_id,
+        max_uses,
+        expires_at,
+    )
+    .await?;
+
+    Ok(Json(invite_info(invite)))
+}
+
+#[get("/api/invites")]
+pub async fn list_invites(_admin: AdminUser) -> Result<Json<Vec<InviteInfo>>, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    let invites = db::get_invite_codes(db_pool).await?;
+
+    Ok(Json(invites.into_iter().map(invite_info).collect()))
+}
+
+/// Revokes a code. People who already signed up with it keep their accounts.
+#[delete("/api/invites/<code>")]
+pub async fn delete_invite(_admin: AdminUser, code: &str) -> Result<Json<AuthResponse>, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    if !db::delete_invite_code(db_pool, &code.to_ascii_uppercase()).await? {
+        return Err(ApiError::NotFound("Invite code not found".to_string()));
+    }
+
+    Ok(Json(AuthResponse {
         success: true,
         username: None,
         display_name: None,
-    })
+    }))
+}
+
+#[get("/api/spotify/login")]
+pub async fn spotify_login(
+    cookies: &CookieJar<'_>,
+    user: Option<AuthenticatedUser>,
+    tokens: &State<Arc<SpotifyTokenManager>>,
+) -> Redirect {
+    let user_name = match user {
+        Some(user) => user.0.name,
+        None => return Redirect::to("/login"),
+    };
+
+    let mut state_bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut state_bytes);
+    let state = general_purpose::URL_SAFE_NO_PAD.encode(state_bytes);
+
+    // The session cookie is SameSite=Strict and will not survive the redirect back from Spotify,
+    // so the state cookie also remembers who started the flow. Usernames never contain ':'.
+    cookies.add_private(
+        Cookie::build(("spotify_oauth_state", format!("{}:{}", state, user_name)))
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .max_age(Duration::minutes(10)),
+    );
+
+    Redirect::to(tokens.authorize_url(&state))
+}
+
+#[get("/api/spotify/callback?<query..>")]
+pub async fn spotify_callback(
+    cookies: &CookieJar<'_>,
+    query: SpotifyCallbackQuery,
+    client: &State<Client>,
+    tokens: &State<Arc<SpotifyTokenManager>>,
+) -> Result<Redirect, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    let oauth_cookie = cookies
+        .get_private("spotify_oauth_state")
+        .map(|cookie| cookie.value().to_string());
+    cookies.remove_private("spotify_oauth_state");
+
+    let (expected_state, user_name) = match oauth_cookie.as_deref().and_then(|v| v.split_once(':')) {
+        Some((state, user_name)) => (state.to_string(), user_name.to_string()),
+        None => return Err(ApiError::validation("Spotify login expired, please try again")),
+    };
+
+    if query.state.as_deref() != Some(expected_state.as_str()) {
+        return Err(ApiError::validation(
+            "Spotify login state did not match, please try again",
+        ));
+    }
+
+    if let Some(error) = query.error {
+        return Err(ApiError::Forbidden(format!(
+            "Spotify login was not completed: {}",
+            error
+        )));
+    }
+
+    let code = query
+        .code
+        .ok_or_else(|| ApiError::validation("Missing authorization code"))?;
+
+    let user = db::get_user(db_pool, &user_name)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("User not found".to_string()))?;
+
+    tokens.link_account(client, db_pool, &user.id, &code).await?;
+
+    Ok(Redirect::to("/main"))
 }
@@ -0,0 +1,487 @@
+use crate::api::circles::{self, CircleRole};
+use crate::api::credentials;
+use crate::api::db::{self, Membership, SeasonWindow};
+use crate::api::error::ApiError;
+use crate::api::previews;
+use crate::api::scoring::{self, ScoringWeights};
+use crate::api::seasons::{self, SeasonStatus};
+use crate::api::sessions::AuthenticatedUser;
+use crate::api::types::{
+    CircleDetails, CircleMember, CircleNameRequest, CircleSummary, CreateSeasonRequest,
+    JoinCircleRequest, PlaylistPreview, ScoringWeightsRequest, SeasonInfo, SetCircleRoleRequest,
+    UpdateSeasonRequest, WeightsPreview,
+};
+use crate::DB_POOL;
+use rocket::serde::json::Json;
+use rocket::time::format_description::well_known::Rfc3339;
+use sqlx::types::time::OffsetDateTime;
+
+async fn circle_details(membership: &Membership) -> Result<CircleDetails, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    let circle = db::get_circle(db_pool, &membership.circle_id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("Circle not found".to_string()))?;
+    let members = db::get_circle_members(db_pool, &circle.id).await?;
+
+    Ok(CircleDetails {
+        id: circle.id,
+        name: circle.name,
+        role: membership.role,
+        join_code: membership.role.can_manage().then_some(circle.join_code),
+        members: members
+            .into_iter()
+            .map(|member| CircleMember {
+                username: member.name,
+                display_name: member.display_name,
+                role: member.role,
+            })
+            .collect(),
+    })
+}
+
+/// Fails with `Forbidden` unless the user owns or administers the circle.
+fn require_manager(membership: &Membership) -> Result<(), ApiError> {
+    if membership.role.can_manage() {
+        Ok(())
+    } else {
+        Err(ApiError::Forbidden(
+            "Only the circle's owner and admins can do that".to_string(),
+        ))
+    }
+}
+
+#[get("/api/circles")]
+pub async fn list_circles(user: AuthenticatedUser) -> Result<Json<Vec<CircleSummary>>, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    let circles = db::get_user_circles(db_pool, &user.id).await?;
+
+    Ok(Json(
+        circles
+            .into_iter()
+            .map(|circle| CircleSummary {
+                id: circle.id,
+                name: circle.name,
+                role: circle.role,
+                member_count: circle.member_count,
+            })
+            .collect(),
+    ))
+}
+
+#[post("/api/circles", format = "json", data = "<request>")]
+pub async fn create_circle(
+    user: AuthenticatedUser,
+    request: Json<CircleNameRequest>,
+) -> Result<Json<CircleDetails>, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+    let name = request.name.trim();
+
+    circles::validate_name(name)?;
+
+    let circle = db::create_circle(db_pool, name, &credentials::new_code(), &user.id).await?;
+
+    let membership = Membership {
+        circle_id: circle.id,
+        role: CircleRole::Owner,
+    };
+    Ok(Json(circle_details(&membership).await?))
+}
+
+#[get("/api/circles/<id>")]
+pub async fn get_circle(user: AuthenticatedUser, id: i32) -> Result<Json<CircleDetails>, ApiError> {
+    let membership = circles::membership(&user, Some(id)).await?;
+
+    Ok(Json(circle_details(&membership).await?))
+}
+
+#[put("/api/circles/<id>", format = "json", data = "<request>")]
+pub async fn rename_circle(
+    user: AuthenticatedUser,
+    id: i32,
+    request: Json<CircleNameRequest>,
+) -> Result<Json<CircleDetails>, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+    let name = request.name.trim();
+
+    let membership = circles::membership(&user, Some(id)).await?;
+    require_manager(&membership)?;
+    circles::validate_name(name)?;
+
+    db::rename_circle(db_pool, &id, name).await?;
+
+    Ok(Json(circle_details(&membership).await?))
+}
+
+#[post("/api/circles/join", format = "json", data = "<request>")]
+pub async fn join_circle(
+    user: AuthenticatedUser,
+    request: Json<JoinCircleRequest>,
+) -> Result<Json<CircleDetails>, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+    let join_code = request.join_code.trim().to_ascii_uppercase();
+
+    let circle = db::get_circle_by_join_code(db_pool, &join_code)
+        .await?
+        .ok_or_else(|| ApiError::invalid_field("join_code", "No circle has that join code"))?;
+
+    if !db::add_circle_member(db_pool, &circle.id, &user.id).await? {
+        return Err(ApiError::Conflict("You are already in this circle".to_string()));
+    }
+
+    let membership = Membership {
+        circle_id: circle.id,
+        role: CircleRole::Member,
+    };
+    Ok(Json(circle_details(&membership).await?))
+}
+
+/// Replaces the join code, e.g. after it was shared too widely. Existing members stay.
+#[post("/api/circles/<id>/join-code")]
+pub async fn rotate_join_code(
+    user: AuthenticatedUser,
+    id: i32,
+) -> Result<Json<CircleDetails>, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    let membership = circles::membership(&user, Some(id)).await?;
+    require_manager(&membership)?;
+
+    db::set_circle_join_code(db_pool, &id, &credentials::new_code()).await?;
+
+    Ok(Json(circle_details(&membership).await?))
+}
+
+/// Leaves the circle, withdrawing the user's ballots in seasons still taking votes. An owner has to hand
+/// the circle over first, unless they are its last member, in which case the circle is deleted.
+#[post("/api/circles/<id>/leave")]
+pub async fn leave_circle(user: AuthenticatedUser, id: i32) -> Result<(), ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    let membership = circles::membership(&user, Some(id)).await?;
+
+    if membership.role == CircleRole::Owner {
+        let members = db::get_circle_members(db_pool, &id).await?;
+        if members.len() > 1 {
+            return Err(ApiError::Conflict(
+                "Make another member the owner before leaving".to_string(),
+            ));
+        }
+        db::delete_circle(db_pool, &id).await?;
+    } else {
+        db::remove_circle_member(db_pool, &id, &user.id).await?;
+    }
+
+    Ok(())
+}
+
+#[put("/api/circles/<id>/members/<username>", format = "json", data = "<request>")]
+pub async fn set_member_role(
+    user: AuthenticatedUser,
+    id: i32,
+    username: &str,
+    request: Json<SetCircleRoleRequest>,
+) -> Result<Json<CircleDetails>, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    let membership = circles::membership(&user, Some(id)).await?;
+    if membership.role != CircleRole::Owner {
+        return Err(ApiError::Forbidden(
+            "Only the circle's owner can change roles".to_string(),
+        ));
+    }
+
+    let member = circles::find_member(&id, username).await?;
+    if member.user_id == user.id {
+        return Err(ApiError::validation("You cannot change your own role"));
+    }
+
+    if request.role == CircleRole::Owner {
+        db::transfer_circle_ownership(db_pool, &id, &user.id, &member.user_id).await?;
+    } else {
+        db::set_circle_member_role(db_pool, &id, &member.user_id, request.role).await?;
+    }
+
+    let membership = circles::membership(&user, Some(id)).await?;
+    Ok(Json(circle_details(&membership).await?))
+}
+
+/// Removes someone else from the circle, withdrawing their ballots in seasons still taking votes. Admins
+/// can only remove plain members.
+#[delete("/api/circles/<id>/members/<username>")]
+pub async fn remove_member(
+    user: AuthenticatedUser,
+    id: i32,
+    username: &str,
+) -> Result<Json<CircleDetails>, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    let membership = circles::membership(&user, Some(id)).await?;
+    require_manager(&membership)?;
+
+    let member = circles::find_member(&id, username).await?;
+    if member.user_id == user.id {
+        return Err(ApiError::validation("Leave the circle instead of removing yourself"));
+    }
+    let allowed = match member.role {
+        CircleRole::Owner => false,
+        CircleRole::Admin => membership.role == CircleRole::Owner,
+        CircleRole::Member => true,
+    };
+    if !allowed {
+        return Err(ApiError::Forbidden(
+            "You cannot remove someone with that role".to_string(),
+        ));
+    }
+
+    db::remove_circle_member(db_pool, &id, &member.user_id).await?;
+
+    Ok(Json(circle_details(&membership).await?))
+}
+
+fn season_info(season: db::Season) -> SeasonInfo {
+    let timestamp = |at: OffsetDateTime| at.format(&Rfc3339).unwrap_or_default();
+    SeasonInfo {
+        status: seasons::status(&season),
+        id: season.id,
+        name: season.name,
+        opens_at: timestamp(season.opens_at),
+        locks_at: timestamp(season.locks_at),
+        reveals_at: timestamp(season.reveals_at),
+        closes_at: timestamp(season.closes_at),
+        ballot_size: season.ballot_size,
+        playlist_generated: season.generated_preview_id.is_some(),
+    }
+}
+
+fn parse_timestamp(field: &str, value: &str) -> Result<OffsetDateTime, ApiError> {
+    OffsetDateTime::parse(value, &Rfc3339).map_err(|_| {
+        ApiError::invalid_field(field, "Use an RFC 3339 timestamp, e.g. 2025-01-01T00:00:00Z")
+    })
+}
+
+/// Fails if the season would overlap another of the circle's seasons.
+async fn check_overlap(
+    circle_id: &i32,
+    except_id: Option<i32>,
+    window: &SeasonWindow<'_>,
+) -> Result<(), ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    if db::season_overlaps(db_pool, circle_id, except_id, window.opens_at, window.closes_at).await? {
+        return Err(ApiError::Conflict(
+            "That overlaps another of this circle's seasons".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// The circle's seasons, newest first.
+#[get("/api/circles/<id>/seasons")]
+pub async fn list_seasons(user: AuthenticatedUser, id: i32) -> Result<Json<Vec<SeasonInfo>>, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    let membership = circles::membership(&user, Some(id)).await?;
+    let seasons = db::get_circle_seasons(db_pool, &membership.circle_id).await?;
+
+    Ok(Json(seasons.into_iter().map(season_info).collect()))
+}
+
+#[post("/api/circles/<id>/seasons", format = "json", data = "<request>")]
+pub async fn create_season(
+    user: AuthenticatedUser,
+    id: i32,
+    request: Json<CreateSeasonRequest>,
+) -> Result<Json<SeasonInfo>, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    let membership = circles::membership(&user, Some(id)).await?;
+    require_manager(&membership)?;
+
+    let closes_at = parse_timestamp("closes_at", &request.closes_at)?;
+    let optional = |field: &str, value: &Option<String>| match value {
+        Some(value) => parse_timestamp(field, value),
+        None => Ok(closes_at),
+    };
+    let window = SeasonWindow {
+        name: request.name.trim(),
+        opens_at: parse_timestamp("opens_at", &request.opens_at)?,
+        locks_at: optional("locks_at", &request.locks_at)?,
+        reveals_at: optional("reveals_at", &request.reveals_at)?,
+        closes_at,
+        ballot_size: request.ballot_size.unwrap_or(seasons::DEFAULT_BALLOT_SIZE),
+    };
+    seasons::validate_window(&window)?;
+    check_overlap(&id, None, &window).await?;
+
+    let season = db::insert_season(db_pool, &id, &window).await?;
+
+    Ok(Json(season_info(season)))
+}
+
+/// Moves a season's phases, renames it or resizes its ballots. Once voting has locked, when it opened and
+/// locked and the ballot size are history and can no longer change.
+#[put("/api/circles/<id>/seasons/<season_id>", format = "json", data = "<request>")]
+pub async fn update_season(
+    user: AuthenticatedUser,
+    id: i32,
+    season_id: i32,
+    request: Json<UpdateSeasonRequest>,
+) -> Result<Json<SeasonInfo>, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    let membership = circles::membership(&user, Some(id)).await?;
+    require_manager(&membership)?;
+    let season = seasons::resolve(&membership, Some(season_id)).await?;
+
+    let locked = !matches!(
+        seasons::status(&season),
+        SeasonStatus::Upcoming | SeasonStatus::Open
+    );
+    if locked
+        && (request.opens_at.is_some() || request.locks_at.is_some() || request.ballot_size.is_some())
+    {
+        return Err(ApiError::Conflict(
+            "Voting in this season has already locked".to_string(),
+        ));
+    }
+
+    let optional = |field: &str, value: &Option<String>, current: OffsetDateTime| match value {
+        Some(value) => parse_timestamp(field, value),
+        None => Ok(current),
+    };
+    let window = SeasonWindow {
+        name: request.name.as_deref().map_or(season.name.as_str(), str::trim),
+        opens_at: optional("opens_at", &request.opens_at, season.opens_at)?,
+        locks_at: optional("locks_at", &request.locks_at, season.locks_at)?,
+        reveals_at: optional("reveals_at", &request.reveals_at, season.reveals_at)?,
+        closes_at: optional("closes_at", &request.closes_at, season.closes_at)?,
+        ballot_size: request.ballot_size.unwrap_or(season.ballot_size),
+    };
+    seasons::validate_window(&window)?;
+    check_overlap(&id, Some(season.id), &window).await?;
+    if let Some(highest) = db::get_highest_rank(db_pool, &season.id).await? {
+        if highest > window.ballot_size {
+            return Err(ApiError::Conflict(format!(
+                "Ballots in this season already rank up to {} songs",
+                highest
+            )));
+        }
+    }
+
+    let season = db::update_season(db_pool, &season.id, &window).await?;
+
+    Ok(Json(season_info(season)))
+}
+
+/// The playlist generated when the season's voting locked. Owners and admins can see it straight away,
+/// members once it is revealed.
+#[get("/api/circles/<id>/seasons/<season_id>/playlist")]
+pub async fn get_season_playlist(
+    user: AuthenticatedUser,
+    id: i32,
+    season_id: i32,
+) -> Result<Json<PlaylistPreview>, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    let membership = circles::membership(&user, Some(id)).await?;
+    let season = seasons::resolve(&membership, Some(season_id)).await?;
+    if !membership.role.can_manage() {
+        seasons::require_revealed(&season)?;
+    }
+
+    let not_generated = || ApiError::NotFound("This season's playlist has not been generated yet".to_string());
+    let preview_id = season.generated_preview_id.ok_or_else(not_generated)?;
+    let row = db::get_playlist_preview(db_pool, &preview_id)
+        .await?
+        .ok_or_else(not_generated)?;
+
+    Ok(Json(previews::from_row(row)?))
+}
+
+/// The weights the circle's new seasons start with.
+#[get("/api/circles/<id>/weights")]
+pub async fn get_circle_weights(user: AuthenticatedUser, id: i32) -> Result<Json<ScoringWeights>, ApiError> {
+    let membership = circles::membership(&user, Some(id)).await?;
+
+    Ok(Json(scoring::for_circle(&membership.circle_id).await?))
+}
+
+/// Changes the weights new seasons start with. Seasons that already exist keep theirs.
+#[put("/api/circles/<id>/weights", format = "json", data = "<request>")]
+pub async fn update_circle_weights(
+    user: AuthenticatedUser,
+    id: i32,
+    request: Json<ScoringWeightsRequest>,
+) -> Result<Json<ScoringWeights>, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    let membership = circles::membership(&user, Some(id)).await?;
+    require_manager(&membership)?;
+
+    let weights = scoring::for_circle(&membership.circle_id).await?.apply(&request);
+    weights.validate()?;
+    db::set_circle_weights(db_pool, &membership.circle_id, &weights).await?;
+
+    Ok(Json(weights))
+}
+
+#[get("/api/circles/<id>/seasons/<season_id>/weights")]
+pub async fn get_season_weights(
+    user: AuthenticatedUser,
+    id: i32,
+    season_id: i32,
+) -> Result<Json<ScoringWeights>, ApiError> {
+    let membership = circles::membership(&user, Some(id)).await?;
+    let season = seasons::resolve(&membership, Some(season_id)).await?;
+
+    Ok(Json(scoring::for_season(&season).await?))
+}
+
+/// Rescores the season's connections with new weights. A playlist already generated keeps its order.
+/// Closed seasons are history and keep theirs.
+#[put("/api/circles/<id>/seasons/<season_id>/weights", format = "json", data = "<request>")]
+pub async fn update_season_weights(
+    user: AuthenticatedUser,
+    id: i32,
+    season_id: i32,
+    request: Json<ScoringWeightsRequest>,
+) -> Result<Json<ScoringWeights>, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    let membership = circles::membership(&user, Some(id)).await?;
+    require_manager(&membership)?;
+    let season = seasons::resolve(&membership, Some(season_id)).await?;
+    if seasons::status(&season) == SeasonStatus::Closed {
+        return Err(ApiError::Conflict(
+            "This season has closed; its scores can no longer change".to_string(),
+        ));
+    }
+
+    let weights = scoring::for_season(&season).await?.apply(&request);
+    weights.validate()?;
+    db::set_season_weights(db_pool, &season, &weights).await?;
+
+    Ok(Json(weights))
+}
+
+/// How the season's compatibility leaderboard and playlist would change under the weights given, without
+/// saving them.
+#[post("/api/circles/<id>/seasons/<season_id>/weights/preview", format = "json", data = "<request>")]
+pub async fn preview_season_weights(
+    user: AuthenticatedUser,
+    id: i32,
+    season_id: i32,
+    request: Json<ScoringWeightsRequest>,
+) -> Result<Json<WeightsPreview>, ApiError> {
+    let membership = circles::membership(&user, Some(id)).await?;
+    require_manager(&membership)?;
+    let season = seasons::resolve(&membership, Some(season_id)).await?;
+
+    let current = scoring::for_season(&season).await?;
+    let proposed = current.apply(&request);
+    proposed.validate()?;
+
+    Ok(Json(scoring::preview(&season, current, proposed).await?))
+}
@@ -0,0 +1,63 @@
+//! Circles: separate friend groups on one deployment. Ballots, connections and playlists all belong to a
+//! circle, and only its members see them.
+
+use crate::api::db::{self, CircleMemberRow, Membership, User};
+use crate::api::error::ApiError;
+use crate::DB_POOL;
+use rocket::serde::{Deserialize, Serialize};
+
+pub const MAX_CIRCLE_NAME_LENGTH: usize = 100;
+
+#[derive(sqlx::Type, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[sqlx(type_name = "text", rename_all = "snake_case")]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum CircleRole {
+    /// Created the circle, or had it handed over. Exactly one per circle.
+    Owner,
+    /// Can see the join code and remove plain members.
+    Admin,
+    Member,
+}
+
+impl CircleRole {
+    pub fn can_manage(self) -> bool {
+        matches!(self, CircleRole::Owner | CircleRole::Admin)
+    }
+}
+
+/// The circle a request is about: `circle` when given, otherwise the first one the user joined.
+/// Circles the user is not in are reported as missing.
+pub async fn membership(user: &User, circle: Option<i32>) -> Result<Membership, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    match circle {
+        Some(circle_id) => db::get_membership(db_pool, &user.id, &circle_id)
+            .await?
+            .ok_or_else(|| ApiError::NotFound("Circle not found".to_string())),
+        None => db::get_first_membership(db_pool, &user.id)
+            .await?
+            .ok_or_else(|| ApiError::Forbidden("Create or join a circle first".to_string())),
+    }
+}
+
+/// A member of the circle by username, in any case.
+pub async fn find_member(circle_id: &i32, username: &str) -> Result<CircleMemberRow, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    let username = username.to_lowercase();
+    db::get_circle_members(db_pool, circle_id)
+        .await?
+        .into_iter()
+        .find(|member| member.name == username)
+        .ok_or_else(|| ApiError::NotFound("Not a member of this circle".to_string()))
+}
+
+pub fn validate_name(name: &str) -> Result<(), ApiError> {
+    if name.is_empty() || name.chars().count() > MAX_CIRCLE_NAME_LENGTH {
+        return Err(ApiError::invalid_field(
+            "name",
+            format!("Circle name must be 1 to {} characters", MAX_CIRCLE_NAME_LENGTH),
+        ));
+    }
+    Ok(())
+}
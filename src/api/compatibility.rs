@@ -0,0 +1,898 @@
+//! How well two users' music taste matches, worked out from one season's ballots.
+//!
+//! For every pair of voters, with the season's [`ScoringWeights`]:
+//!
+//! - each song on both ballots is worth the season's song points, less the average rank difference over
+//!   those songs;
+//! - each artist on both ballots adds the shared artist points;
+//! - the average rank difference over every pair of songs by a shared artist, times its weight, is taken
+//!   off.
+//!
+//! With the default weights on a top 10 that is
+//! `overlap*10 - avg_rank_diff + shared_artists*3 - artist_rank_diff*0.5`. Pairs with no song or artist in
+//! common are left out.
+
+use crate::api::db::{self, CircleMemberRow, Season, Voter};
+use crate::api::error::ApiError;
+use crate::api::scoring::{self, ScoringWeights};
+use crate::api::similarity::{self, Similarity, SimilarityMetric};
+use crate::api::types::{
+    MusicTasteIndividual, MusicTasteOverview, MusicTastePair, MusicTastePairs, PairComparison, SeasonRef,
+    VennCounts,
+};
+use crate::DB_POOL;
+use rocket::serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashSet};
+use std::hash::Hash;
+
+pub const DEFAULT_PAGE_SIZE: usize = 20;
+pub const MAX_PAGE_SIZE: usize = 100;
+
+/// What a list of pairs is ordered by.
+#[derive(FromFormField, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum PairSort {
+    /// The score of the chosen similarity metric.
+    #[default]
+    Score,
+    /// The combined score, whatever the metric.
+    Combined,
+    #[field(value = "overlapping_songs")]
+    OverlappingSongs,
+    #[field(value = "overlapping_artists")]
+    OverlappingArtists,
+    #[field(value = "song_rank_diff")]
+    SongRankDiff,
+    #[field(value = "artist_rank_diff")]
+    ArtistRankDiff,
+}
+
+#[derive(FromFormField, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum SortOrder {
+    #[default]
+    Desc,
+    Asc,
+}
+
+/// Which pairs to list and in what order. Filters are inclusive; `min_score` applies to the metric's score.
+#[derive(Default)]
+pub struct PairListing<'a> {
+    pub metric: SimilarityMetric,
+    pub sort: PairSort,
+    pub order: SortOrder,
+    pub min_score: Option<f64>,
+    /// Fewest songs the two ballots need in common.
+    pub min_overlap: Option<i64>,
+    /// Only pairs with this voter, by username.
+    pub user: Option<&'a str>,
+}
+
+impl PairSort {
+    fn key(self, score: &PairScore, similarity: &Similarity) -> f64 {
+        match self {
+            PairSort::Score => similarity.score(),
+            PairSort::Combined => score.combined,
+            PairSort::OverlappingSongs => score.overlapping_songs as f64,
+            PairSort::OverlappingArtists => score.shared_artists as f64,
+            PairSort::SongRankDiff => score.song_rank_diff,
+            PairSort::ArtistRankDiff => score.artist_rank_diff,
+        }
+    }
+}
+
+/// One song on one user's ballot.
+pub struct Pick {
+    pub song_id: i32,
+    pub name: String,
+    pub artist: String,
+    pub rank: i32,
+}
+
+/// A song on both ballots.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct SongMatch {
+    pub song_name: String,
+    pub artist: String,
+    pub rank_a: i32,
+    pub rank_b: i32,
+    pub rank_difference: i32,
+}
+
+/// Two songs, one on each ballot, by the same artist. The same song on both ballots counts too.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct ArtistMatch {
+    pub artist: String,
+    pub song_a: String,
+    pub rank_a: i32,
+    pub song_b: String,
+    pub rank_b: i32,
+    pub rank_difference: i32,
+}
+
+/// The score between two ballots, `a` and `b`, and what it is made of. Averages are 0 when there is
+/// nothing to average.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PairScore {
+    pub overlapping_songs: i64,
+    pub song_rank_diff: f64,
+    pub song_strength: f64,
+    pub shared_artists: i64,
+    pub artist_song_overlaps: i64,
+    pub artist_rank_diff: f64,
+    pub combined: f64,
+    /// Closest ranks first.
+    pub songs: Vec<SongMatch>,
+    /// Closest ranks first.
+    pub artists: Vec<ArtistMatch>,
+}
+
+impl PairScore {
+    pub fn has_overlap(&self) -> bool {
+        self.overlapping_songs > 0 || self.shared_artists > 0
+    }
+}
+
+fn average(values: impl Iterator<Item = i32>) -> f64 {
+    let (sum, count) = values.fold((0i64, 0i64), |(sum, count), value| (sum + value as i64, count + 1));
+    if count == 0 {
+        0.0
+    } else {
+        sum as f64 / count as f64
+    }
+}
+
+/// Rounds half away from zero to two decimals, the way the scores are reported.
+pub fn round2(value: f64) -> f64 {
+    (value * 100.0).round() / 100.0
+}
+
+pub fn score_pair(a: &[Pick], b: &[Pick], ballot_size: i32, weights: &ScoringWeights) -> PairScore {
+    let mut songs: Vec<SongMatch> = Vec::new();
+    let mut artists: Vec<ArtistMatch> = Vec::new();
+    for pick_a in a {
+        for pick_b in b {
+            let rank_difference = (pick_a.rank - pick_b.rank).abs();
+            if pick_a.song_id == pick_b.song_id {
+                songs.push(SongMatch {
+                    song_name: pick_a.name.clone(),
+                    artist: pick_a.artist.clone(),
+                    rank_a: pick_a.rank,
+                    rank_b: pick_b.rank,
+                    rank_difference,
+                });
+            }
+            if pick_a.artist == pick_b.artist {
+                artists.push(ArtistMatch {
+                    artist: pick_a.artist.clone(),
+                    song_a: pick_a.name.clone(),
+                    rank_a: pick_a.rank,
+                    song_b: pick_b.name.clone(),
+                    rank_b: pick_b.rank,
+                    rank_difference,
+                });
+            }
+        }
+    }
+    songs.sort_by_key(|song| (song.rank_difference, song.rank_a));
+    artists.sort_by_key(|artist| (artist.rank_difference, artist.rank_a));
+
+    let overlapping_songs = songs.len() as i64;
+    let song_rank_diff = average(songs.iter().map(|song| song.rank_difference));
+    let song_strength = if songs.is_empty() {
+        0.0
+    } else {
+        overlapping_songs as f64 * weights.song_points(ballot_size) - song_rank_diff
+    };
+
+    let shared_artists = artists
+        .iter()
+        .map(|artist| artist.artist.as_str())
+        .collect::<HashSet<_>>()
+        .len() as i64;
+    let artist_rank_diff = average(artists.iter().map(|artist| artist.rank_difference));
+
+    let combined = song_strength + shared_artists as f64 * weights.shared_artist_points
+        - artist_rank_diff * weights.artist_rank_diff_weight;
+
+    PairScore {
+        overlapping_songs,
+        song_rank_diff,
+        song_strength,
+        shared_artists,
+        artist_song_overlaps: artists.len() as i64,
+        artist_rank_diff,
+        combined,
+        songs,
+        artists,
+    }
+}
+
+fn venn<T: Eq + Hash>(a: &HashSet<T>, b: &HashSet<T>) -> VennCounts {
+    let both = a.intersection(b).count();
+    VennCounts {
+        only_a: a.len() - both,
+        both,
+        only_b: b.len() - both,
+    }
+}
+
+/// Best score first; ties keep their order.
+fn by_score_desc(a: f64, b: f64) -> Ordering {
+    b.partial_cmp(&a).unwrap_or(Ordering::Equal)
+}
+
+/// Everyone's ballot for one season, loaded once and scored in memory.
+pub struct SeasonBallots {
+    ballot_size: i32,
+    weights: ScoringWeights,
+    voters: BTreeMap<i32, Voter>,
+    picks: BTreeMap<i32, Vec<Pick>>,
+}
+
+impl SeasonBallots {
+    pub fn new(
+        ballot_size: i32,
+        weights: ScoringWeights,
+        voters: Vec<Voter>,
+        picks: Vec<(i32, Pick)>,
+    ) -> Self {
+        let mut by_user: BTreeMap<i32, Vec<Pick>> = BTreeMap::new();
+        for (user_id, pick) in picks {
+            by_user.entry(user_id).or_default().push(pick);
+        }
+        for ballot in by_user.values_mut() {
+            ballot.sort_by_key(|pick| pick.rank);
+        }
+
+        SeasonBallots {
+            ballot_size,
+            weights,
+            voters: voters.into_iter().map(|voter| (voter.id, voter)).collect(),
+            picks: by_user,
+        }
+    }
+
+    pub async fn load(season: &Season) -> Result<Self, ApiError> {
+        let db_pool = DB_POOL.get().unwrap();
+
+        let weights = scoring::for_season(season).await?;
+        let voters = db::get_season_voters(db_pool, &season.id).await?;
+        let picks = db::get_ballots(db_pool, &season.id)
+            .await?
+            .into_iter()
+            .map(|row| {
+                (
+                    row.user_id,
+                    Pick {
+                        song_id: row.song_id,
+                        name: row.name,
+                        artist: row.artist,
+                        rank: row.rank,
+                    },
+                )
+            })
+            .collect();
+
+        Ok(SeasonBallots::new(season.ballot_size, weights, voters, picks))
+    }
+
+    /// Scores everything from now on with `weights` instead of the season's.
+    pub fn set_weights(&mut self, weights: ScoringWeights) {
+        self.weights = weights;
+    }
+
+    /// The voter's display name, falling back to their username.
+    pub fn voter_name(&self, user_id: i32) -> String {
+        self.voters
+            .get(&user_id)
+            .map(|voter| voter.display_name.clone().unwrap_or_else(|| voter.name.clone()))
+            .unwrap_or_default()
+    }
+
+    fn ballot(&self, user_id: i32) -> &[Pick] {
+        self.picks.get(&user_id).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    fn measure(&self, metric: SimilarityMetric, user_a: i32, user_b: i32, score: &PairScore) -> Similarity {
+        similarity::measure(
+            metric,
+            self.ballot(user_a),
+            self.ballot(user_b),
+            self.ballot_size,
+            score,
+        )
+    }
+
+    /// The user's score with every other voter, 0 where nothing overlaps. Empty if the user has not voted.
+    pub fn scores(&self, user_id: i32) -> Vec<(&Voter, PairScore)> {
+        if self.ballot(user_id).is_empty() {
+            return Vec::new();
+        }
+        self.picks
+            .keys()
+            .filter(|&&other_id| other_id != user_id)
+            .filter_map(|other_id| {
+                let voter = self.voters.get(other_id)?;
+                let score = score_pair(
+                    self.ballot(user_id),
+                    self.ballot(*other_id),
+                    self.ballot_size,
+                    &self.weights,
+                );
+                Some((voter, score))
+            })
+            .collect()
+    }
+
+    /// The user's connections: everyone they share a song or artist with, best match by `metric` first.
+    pub fn connections(&self, user_id: i32, metric: SimilarityMetric) -> Vec<MusicTasteIndividual> {
+        let mut scores: Vec<(&Voter, PairScore, similarity::Similarity)> = self
+            .scores(user_id)
+            .into_iter()
+            .filter(|(_, score)| score.has_overlap())
+            .map(|(voter, score)| {
+                let measured = self.measure(metric, user_id, voter.id, &score);
+                (voter, score, measured)
+            })
+            .collect();
+        scores.sort_by(|(_, a, a_measured), (_, b, b_measured)| {
+            by_score_desc(a_measured.score(), b_measured.score())
+                .then_with(|| by_score_desc(a.combined, b.combined))
+        });
+
+        scores
+            .into_iter()
+            .map(|(voter, score, similarity)| MusicTasteIndividual {
+                other_user_name: voter.display_name.clone().unwrap_or_else(|| voter.name.clone()),
+                overlapping_songs: Some(score.overlapping_songs),
+                song_rank_diff: Some(round2(score.song_rank_diff)),
+                song_relationship_strength: Some(round2(score.song_strength)),
+                overlapping_artists: Some(score.shared_artists),
+                total_songs_shared_artists: Some(score.artist_song_overlaps),
+                artist_rank_diff: Some(round2(score.artist_rank_diff)),
+                combined_score: Some(round2(score.combined)),
+                overlapping_song_details: Some(individual_song_details(&score.songs)),
+                overlapping_artist_details: Some(individual_artist_details(&score.artists)),
+                similarity,
+            })
+            .collect()
+    }
+
+    /// Every pair of voters with a song or artist in common as user ids and their score, best match
+    /// first. The user with the lower id comes first.
+    pub fn pairs(&self) -> Vec<(i32, i32, PairScore)> {
+        let mut pairs: Vec<(i32, i32, PairScore)> = Vec::new();
+        for (user_1, ballot_1) in &self.picks {
+            for (user_2, ballot_2) in self.picks.range(user_1 + 1..) {
+                let score = score_pair(ballot_1, ballot_2, self.ballot_size, &self.weights);
+                if score.has_overlap() {
+                    pairs.push((*user_1, *user_2, score));
+                }
+            }
+        }
+        pairs.sort_by(|(_, _, a), (_, _, b)| {
+            by_score_desc(a.combined, b.combined)
+                .then_with(|| b.overlapping_songs.cmp(&a.overlapping_songs))
+                .then_with(|| b.shared_artists.cmp(&a.shared_artists))
+        });
+        pairs
+    }
+
+    /// One page of the pairs matching `listing`, counted from 1, along with how many pairs there are. The
+    /// user with the lower id is `user_1`; pairs that sort equal stay best match first.
+    pub fn pair_page(&self, listing: &PairListing, page: usize, per_page: usize) -> MusicTastePairs {
+        let all_pairs = self.pairs();
+        let total_pairs = all_pairs.len();
+
+        // A username without a ballot this season matches no pair.
+        let user_id = listing.user.map(|username| {
+            self.voters
+                .values()
+                .find(|voter| voter.name == username)
+                .map(|voter| voter.id)
+        });
+        let mut pairs: Vec<(i32, i32, PairScore, Similarity)> = all_pairs
+            .into_iter()
+            .filter(|(user_1, user_2, score)| {
+                listing.min_overlap.is_none_or(|min| score.overlapping_songs >= min)
+                    && user_id.is_none_or(|id| id == Some(*user_1) || id == Some(*user_2))
+            })
+            .map(|(user_1, user_2, score)| {
+                let measured = self.measure(listing.metric, user_1, user_2, &score);
+                (user_1, user_2, score, measured)
+            })
+            .filter(|(_, _, _, measured)| listing.min_score.is_none_or(|min| measured.score() >= min))
+            .collect();
+        pairs.sort_by(|(_, _, a, a_measured), (_, _, b, b_measured)| {
+            let (a, b) = (listing.sort.key(a, a_measured), listing.sort.key(b, b_measured));
+            match listing.order {
+                SortOrder::Desc => by_score_desc(a, b),
+                SortOrder::Asc => by_score_desc(b, a),
+            }
+        });
+
+        let matching_pairs = pairs.len();
+        // Any page number is accepted, so a huge one must come out empty rather than overflow.
+        let skip = page.saturating_sub(1).saturating_mul(per_page);
+        MusicTastePairs {
+            total_pairs,
+            matching_pairs,
+            page,
+            per_page,
+            pages: matching_pairs.div_ceil(per_page),
+            pairs: pairs
+                .into_iter()
+                .enumerate()
+                .skip(skip)
+                .take(per_page)
+                .map(|(index, (user_1, user_2, score, similarity))| MusicTastePair {
+                    position: index + 1,
+                    user_1: self.voter_name(user_1),
+                    user_2: self.voter_name(user_2),
+                    overlapping_songs: score.overlapping_songs,
+                    song_rank_diff: round2(score.song_rank_diff),
+                    song_relationship_strength: round2(score.song_strength),
+                    overlapping_artists: score.shared_artists,
+                    total_songs_shared_artists: score.artist_song_overlaps,
+                    artist_rank_diff: round2(score.artist_rank_diff),
+                    combined_score: round2(score.combined),
+                    overlapping_song_details: overview_song_details(&score.songs),
+                    overlapping_artist_details: overview_artist_details(&score.artists),
+                    similarity,
+                })
+                .collect(),
+        }
+    }
+
+    /// The full breakdown for two members, whether or not they have anything in common. A member without
+    /// a ballot compares as an empty one.
+    pub fn compare(&self, season: &Season, a: &CircleMemberRow, b: &CircleMemberRow) -> PairComparison {
+        let (ballot_a, ballot_b) = (self.ballot(a.user_id), self.ballot(b.user_id));
+        let score = score_pair(ballot_a, ballot_b, self.ballot_size, &self.weights);
+        let songs = |ballot: &[Pick]| ballot.iter().map(|pick| pick.song_id).collect::<HashSet<_>>();
+        let artists = |ballot: &[Pick]| {
+            ballot
+                .iter()
+                .map(|pick| pick.artist.clone())
+                .collect::<HashSet<_>>()
+        };
+
+        PairComparison {
+            season: SeasonRef {
+                id: season.id,
+                name: season.name.clone(),
+            },
+            user_a: a.name.clone(),
+            user_a_name: a.display_name.clone(),
+            user_b: b.name.clone(),
+            user_b_name: b.display_name.clone(),
+            overlapping_songs: score.overlapping_songs,
+            song_rank_diff: round2(score.song_rank_diff),
+            song_relationship_strength: round2(score.song_strength),
+            overlapping_artists: score.shared_artists,
+            total_songs_shared_artists: score.artist_song_overlaps,
+            artist_rank_diff: round2(score.artist_rank_diff),
+            combined_score: round2(score.combined),
+            songs: venn(&songs(ballot_a), &songs(ballot_b)),
+            artists: venn(&artists(ballot_a), &artists(ballot_b)),
+            overlapping_song_details: overview_song_details(&score.songs),
+            overlapping_artist_details: overview_artist_details(&score.artists),
+            metrics: SimilarityMetric::ALL
+                .iter()
+                .map(|&metric| self.measure(metric, a.user_id, b.user_id, &score))
+                .collect(),
+        }
+    }
+
+    /// Every pair of voters with a song or artist in common, best match first. The user with the lower
+    /// id is `user_1`.
+    pub fn overview(&self) -> Vec<MusicTasteOverview> {
+        let display_name = |user_id: i32| {
+            self.voters
+                .get(&user_id)
+                .and_then(|voter| voter.display_name.clone())
+        };
+        self.pairs()
+            .into_iter()
+            .map(|(user_1, user_2, score)| MusicTasteOverview {
+                user_1: display_name(user_1),
+                user_2: display_name(user_2),
+                overlapping_songs: Some(score.overlapping_songs),
+                song_rank_diff: Some(round2(score.song_rank_diff)),
+                song_relationship_strength: Some(round2(score.song_strength)),
+                overlapping_artists: Some(score.shared_artists),
+                total_songs_shared_artists: Some(score.artist_song_overlaps),
+                artist_rank_diff: Some(round2(score.artist_rank_diff)),
+                combined_score: Some(round2(score.combined)),
+                overlapping_song_details: Some(overview_song_details(&score.songs)),
+                overlapping_artist_details: Some(overview_artist_details(&score.artists)),
+            })
+            .collect()
+    }
+}
+
+fn individual_song_details(songs: &[SongMatch]) -> serde_json::Value {
+    songs
+        .iter()
+        .map(|song| {
+            serde_json::json!({
+                "song_name": song.song_name,
+                "artist": song.artist,
+                "active_user_rank": song.rank_a,
+                "other_user_rank": song.rank_b,
+                "rank_difference": song.rank_difference,
+            })
+        })
+        .collect()
+}
+
+fn individual_artist_details(artists: &[ArtistMatch]) -> serde_json::Value {
+    artists
+        .iter()
+        .map(|artist| {
+            serde_json::json!({
+                "artist": artist.artist,
+                "active_user_song": artist.song_a,
+                "active_user_rank": artist.rank_a,
+                "other_user_song": artist.song_b,
+                "other_user_rank": artist.rank_b,
+                "rank_difference": artist.rank_difference,
+            })
+        })
+        .collect()
+}
+
+fn overview_song_details(songs: &[SongMatch]) -> serde_json::Value {
+    songs
+        .iter()
+        .map(|song| {
+            serde_json::json!({
+                "song_name": song.song_name,
+                "artist": song.artist,
+                "user1_rank": song.rank_a,
+                "user2_rank": song.rank_b,
+                "rank_difference": song.rank_difference,
+            })
+        })
+        .collect()
+}
+
+fn overview_artist_details(artists: &[ArtistMatch]) -> serde_json::Value {
+    artists
+        .iter()
+        .map(|artist| {
+            serde_json::json!({
+                "artist": artist.artist,
+                "user1_song": artist.song_a,
+                "user1_rank": artist.rank_a,
+                "user2_song": artist.song_b,
+                "user2_rank": artist.rank_b,
+                "rank_difference": artist.rank_difference,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pick(song_id: i32, artist: &str, rank: i32) -> Pick {
+        Pick {
+            song_id,
+            name: format!("Song {}", song_id),
+            artist: artist.to_string(),
+            rank,
+        }
+    }
+
+    fn voter(id: i32, name: &str, display_name: Option<&str>) -> Voter {
+        Voter {
+            id,
+            name: name.to_string(),
+            display_name: display_name.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn pins_the_top_10_formula() {
+        let a = [pick(1, "X", 1), pick(2, "Y", 2), pick(3, "Z", 3)];
+        let b = [pick(1, "X", 3), pick(4, "Y", 1)];
+
+        let score = score_pair(&a, &b, 10, &ScoringWeights::default());
+
+        // One shared song two places apart: 1*10 - 2.
+        assert_eq!(score.overlapping_songs, 1);
+        assert_eq!(score.song_rank_diff, 2.0);
+        assert_eq!(score.song_strength, 8.0);
+        // X (ranks 1 and 3) and Y (ranks 2 and 1): 2 artists, 1.5 places apart on average.
+        assert_eq!(score.shared_artists, 2);
+        assert_eq!(score.artist_song_overlaps, 2);
+        assert_eq!(score.artist_rank_diff, 1.5);
+        // 8 + 2*3 - 1.5*0.5
+        assert_eq!(score.combined, 13.25);
+    }
+
+    #[test]
+    fn song_points_follow_the_ballot_size() {
+        let a = [pick(1, "X", 1), pick(2, "Y", 2)];
+        let b = [pick(1, "X", 1), pick(2, "Y", 2)];
+
+        assert_eq!(score_pair(&a, &b, 10, &ScoringWeights::default()).combined, 2.0 * 10.0 + 2.0 * 3.0);
+        assert_eq!(score_pair(&a, &b, 5, &ScoringWeights::default()).combined, 2.0 * 5.0 + 2.0 * 3.0);
+    }
+
+    #[test]
+    fn weights_replace_the_default_points() {
+        let a = [pick(1, "X", 1), pick(2, "Y", 2), pick(3, "Z", 3)];
+        let b = [pick(1, "X", 3), pick(4, "Y", 1)];
+        let weights = ScoringWeights {
+            shared_song_points: 20.0,
+            shared_artist_points: 1.0,
+            artist_rank_diff_weight: 2.0,
+            ..ScoringWeights::default()
+        };
+
+        // 1*20 - 2 + 2*1 - 1.5*2 on a top 10, and song points halve on a top 5.
+        assert_eq!(score_pair(&a, &b, 10, &weights).combined, 17.0);
+        assert_eq!(score_pair(&a, &b, 5, &weights).combined, 7.0);
+    }
+
+    #[test]
+    fn artists_count_once_but_every_song_pair_is_compared() {
+        let a = [pick(1, "X", 1), pick(2, "X", 2)];
+        let b = [pick(3, "X", 1), pick(4, "X", 4)];
+
+        let score = score_pair(&a, &b, 10, &ScoringWeights::default());
+
+        assert_eq!(score.overlapping_songs, 0);
+        assert_eq!(score.song_strength, 0.0);
+        assert_eq!(score.shared_artists, 1);
+        assert_eq!(score.artist_song_overlaps, 4);
+        // |1-1|, |1-4|, |2-1|, |2-4| averages to 1.5.
+        assert_eq!(score.artist_rank_diff, 1.5);
+        assert_eq!(score.combined, 3.0 - 0.75);
+        let differences: Vec<i32> = score.artists.iter().map(|artist| artist.rank_difference).collect();
+        assert_eq!(differences, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn scores_are_reported_to_two_decimals() {
+        // Shared songs 0, 1 and 1 places apart: 3*10 - 2/3.
+        let a = [pick(1, "X", 1), pick(2, "Y", 2), pick(3, "Z", 3)];
+        let b = [pick(1, "X", 1), pick(2, "Y", 3), pick(3, "Z", 2)];
+        let ballots = SeasonBallots::new(
+            10,
+            ScoringWeights::default(),
+            vec![voter(1, "ann", None), voter(2, "ben", None)],
+            a.into_iter().map(|pick| (1, pick)).chain(b.into_iter().map(|pick| (2, pick))).collect(),
+        );
+
+        let connection = &ballots.connections(1, SimilarityMetric::Combined)[0];
+
+        assert_eq!(connection.song_rank_diff, Some(0.67));
+        assert_eq!(connection.song_relationship_strength, Some(29.33));
+        assert_eq!(connection.combined_score, Some(38.0));
+        assert_eq!(round2(0.125), 0.13);
+    }
+
+    #[test]
+    fn connections_leave_out_users_without_overlap() {
+        let ballots = SeasonBallots::new(
+            10,
+            ScoringWeights::default(),
+            vec![
+                voter(1, "ann", Some("Ann")),
+                voter(2, "ben", None),
+                voter(3, "cat", Some("Cat")),
+                voter(4, "dan", Some("Dan")),
+            ],
+            vec![
+                (1, pick(1, "X", 1)),
+                (1, pick(2, "Y", 2)),
+                (2, pick(3, "Y", 1)),
+                (3, pick(1, "X", 1)),
+                (4, pick(9, "W", 1)),
+            ],
+        );
+
+        let connections = ballots.connections(1, SimilarityMetric::Combined);
+
+        let names: Vec<&str> = connections
+            .iter()
+            .map(|connection| connection.other_user_name.as_str())
+            .collect();
+        // Cat shares a song, ben (no display name) only an artist, Dan nothing.
+        assert_eq!(names, vec!["Cat", "ben"]);
+        assert_eq!(connections[0].combined_score, Some(13.0));
+        assert_eq!(connections[1].combined_score, Some(2.5));
+        assert!(ballots.connections(5, SimilarityMetric::Combined).is_empty());
+    }
+
+    #[test]
+    fn scores_include_every_other_voter() {
+        let ballots = SeasonBallots::new(
+            10,
+            ScoringWeights::default(),
+            vec![voter(1, "ann", None), voter(2, "ben", None), voter(3, "cat", None)],
+            vec![(1, pick(1, "X", 1)), (2, pick(1, "X", 1)), (3, pick(2, "Y", 1))],
+        );
+
+        let scores: Vec<(i32, f64)> = ballots
+            .scores(1)
+            .into_iter()
+            .map(|(voter, score)| (voter.id, score.combined))
+            .collect();
+
+        assert_eq!(scores, vec![(2, 13.0), (3, 0.0)]);
+    }
+
+    #[test]
+    fn overview_pairs_lower_id_first_and_best_first() {
+        let ballots = SeasonBallots::new(
+            10,
+            ScoringWeights::default(),
+            vec![
+                voter(1, "ann", Some("Ann")),
+                voter(2, "ben", Some("Ben")),
+                voter(3, "cat", None),
+            ],
+            vec![
+                (1, pick(1, "X", 1)),
+                (2, pick(2, "X", 3)),
+                (3, pick(1, "X", 1)),
+                (3, pick(2, "X", 2)),
+            ],
+        );
+
+        let overview = ballots.overview();
+        let mut reweighted = ballots;
+        reweighted.set_weights(ScoringWeights {
+            shared_song_points: 0.0,
+            ..ScoringWeights::default()
+        });
+        let reweighted: Vec<(i32, i32, f64)> = reweighted
+            .pairs()
+            .into_iter()
+            .map(|(user_1, user_2, score)| (user_1, user_2, score.combined))
+            .collect();
+
+        let pairs: Vec<(Option<&str>, Option<&str>, Option<f64>)> = overview
+            .iter()
+            .map(|pair| (pair.user_1.as_deref(), pair.user_2.as_deref(), pair.combined_score))
+            .collect();
+        assert_eq!(
+            pairs,
+            vec![
+                // Ann and cat share song 1 at the same rank, plus X songs 0 and 1 places apart.
+                (Some("Ann"), None, Some(12.75)),
+                // Ben and cat share song 2 one place apart, plus X songs 2 and 1 places apart.
+                (Some("Ben"), None, Some(11.25)),
+                (Some("Ann"), Some("Ben"), Some(2.0)),
+            ]
+        );
+        // Without song points a shared song only costs its rank difference, which drops Ben and cat last.
+        assert_eq!(reweighted, vec![(1, 3, 2.75), (1, 2, 2.0), (2, 3, 1.25)]);
+    }
+
+    #[test]
+    fn pair_pages_filter_sort_and_count() {
+        let ballots = SeasonBallots::new(
+            10,
+            ScoringWeights::default(),
+            vec![
+                voter(1, "ann", Some("Ann")),
+                voter(2, "ben", Some("Ben")),
+                voter(3, "cat", None),
+            ],
+            vec![
+                (1, pick(1, "X", 1)),
+                (2, pick(2, "X", 3)),
+                (3, pick(1, "X", 1)),
+                (3, pick(2, "X", 2)),
+            ],
+        );
+        let names = |pairs: &MusicTastePairs| -> Vec<(usize, String, String)> {
+            pairs
+                .pairs
+                .iter()
+                .map(|pair| (pair.position, pair.user_1.clone(), pair.user_2.clone()))
+                .collect()
+        };
+        let pair = |position: usize, user_1: &str, user_2: &str| {
+            (position, user_1.to_string(), user_2.to_string())
+        };
+
+        let first = ballots.pair_page(&PairListing::default(), 1, 2);
+        assert_eq!((first.total_pairs, first.matching_pairs, first.pages), (3, 3, 2));
+        assert_eq!(names(&first), vec![pair(1, "Ann", "cat"), pair(2, "Ben", "cat")]);
+        let second = ballots.pair_page(&PairListing::default(), 2, 2);
+        assert_eq!(names(&second), vec![pair(3, "Ann", "Ben")]);
+        assert!(ballots.pair_page(&PairListing::default(), 3, 2).pairs.is_empty());
+        let far = ballots.pair_page(&PairListing::default(), usize::MAX, MAX_PAGE_SIZE);
+        assert!(far.pairs.is_empty());
+        assert_eq!((far.page, far.matching_pairs), (usize::MAX, 3));
+
+        // Ann and Ben only share an artist.
+        let sharing_songs = PairListing {
+            min_overlap: Some(1),
+            ..PairListing::default()
+        };
+        let sharing_songs = ballots.pair_page(&sharing_songs, 1, 10);
+        assert_eq!((sharing_songs.total_pairs, sharing_songs.matching_pairs), (3, 2));
+
+        let with_ben = PairListing {
+            user: Some("ben"),
+            sort: PairSort::Combined,
+            order: SortOrder::Asc,
+            ..PairListing::default()
+        };
+        assert_eq!(
+            names(&ballots.pair_page(&with_ben, 1, 10)),
+            vec![pair(1, "Ann", "Ben"), pair(2, "Ben", "cat")]
+        );
+
+        let above_12 = PairListing {
+            min_score: Some(12.0),
+            ..PairListing::default()
+        };
+        assert_eq!(names(&ballots.pair_page(&above_12, 1, 10)), vec![pair(1, "Ann", "cat")]);
+    }
+
+    #[test]
+    fn compare_breaks_down_any_two_members() {
+        use crate::api::circles::CircleRole;
+        use sqlx::types::time::OffsetDateTime;
+
+        let member = |user_id: i32, name: &str| CircleMemberRow {
+            user_id,
+            name: name.to_string(),
+            display_name: name.to_uppercase(),
+            role: CircleRole::Member,
+        };
+        let season = Season {
+            id: 7,
+            circle_id: 1,
+            name: "2025".to_string(),
+            opens_at: OffsetDateTime::UNIX_EPOCH,
+            locks_at: OffsetDateTime::UNIX_EPOCH,
+            reveals_at: OffsetDateTime::UNIX_EPOCH,
+            closes_at: OffsetDateTime::UNIX_EPOCH,
+            ballot_size: 10,
+            generated_preview_id: None,
+        };
+        let ballots = SeasonBallots::new(
+            10,
+            ScoringWeights::default(),
+            vec![voter(1, "ann", None), voter(2, "ben", None)],
+            vec![
+                (1, pick(1, "X", 1)),
+                (1, pick(2, "Y", 2)),
+                (1, pick(3, "Z", 3)),
+                (2, pick(1, "X", 3)),
+                (2, pick(4, "Y", 1)),
+                (2, pick(5, "W", 2)),
+            ],
+        );
+
+        let comparison = ballots.compare(&season, &member(1, "ann"), &member(2, "ben"));
+
+        assert_eq!((comparison.user_a_name.as_str(), comparison.season.name.as_str()), ("ANN", "2025"));
+        // The same pair as the top 10 formula test.
+        assert_eq!(comparison.combined_score, 13.25);
+        assert_eq!(comparison.songs, VennCounts { only_a: 2, both: 1, only_b: 2 });
+        assert_eq!(comparison.artists, VennCounts { only_a: 1, both: 2, only_b: 1 });
+        assert_eq!(comparison.overlapping_song_details[0]["user1_rank"], 1);
+        assert_eq!(comparison.overlapping_song_details[0]["user2_rank"], 3);
+        assert_eq!(comparison.metrics.len(), SimilarityMetric::ALL.len());
+
+        // Someone who has not voted compares as an empty ballot.
+        let empty = ballots.compare(&season, &member(1, "ann"), &member(3, "cat"));
+        assert_eq!(empty.combined_score, 0.0);
+        assert_eq!(empty.songs, VennCounts { only_a: 3, both: 0, only_b: 0 });
+    }
+}
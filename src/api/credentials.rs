@@ -0,0 +1,92 @@
+//! Password hashing, shareable codes, and the server-side auth settings: whether passwords are mandatory,
+//! who may sign up and who administers the instance.
+
+use crate::api::error::ApiError;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use rand::Rng;
+use rocket::serde::Deserialize;
+use rocket::tokio::task;
+
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+// No 0/O or 1/I, so codes survive being read out or copied by hand.
+const CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+const CODE_LENGTH: usize = 10;
+
+/// Read from the `auth` table of the Rocket config.
+#[derive(Deserialize, Clone, Default)]
+#[serde(crate = "rocket::serde", default)]
+pub struct AuthConfig {
+    /// Refuse password-less logins and signups. Existing users without a password can still log in
+    /// by choosing one on their next login.
+    pub require_passwords: bool,
+    pub registration: Registration,
+    /// Usernames allowed to manage invite codes.
+    pub admins: Vec<String>,
+}
+
+impl AuthConfig {
+    pub fn is_admin(&self, username: &str) -> bool {
+        self.admins
+            .iter()
+            .any(|admin| admin.eq_ignore_ascii_case(username))
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum Registration {
+    /// Anyone can sign up.
+    #[default]
+    Open,
+    /// Signing up needs an invite code from an admin.
+    InviteOnly,
+}
+
+pub fn validate_password(password: &str) -> Result<(), ApiError> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(ApiError::invalid_field(
+            "password",
+            format!("Password must be at least {} characters", MIN_PASSWORD_LENGTH),
+        ));
+    }
+    Ok(())
+}
+
+/// Hashes with Argon2id and a fresh salt, returning the PHC string to store.
+pub async fn hash_password(password: String) -> Result<String, ApiError> {
+    // Argon2 is deliberately slow; keep it off the async workers.
+    task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+    })
+    .await
+    .map_err(|err| ApiError::Internal(format!("Password hashing failed: {}", err)))?
+    .map_err(|err| ApiError::Internal(format!("Password hashing failed: {}", err)))
+}
+
+pub async fn verify_password(password: String, password_hash: String) -> Result<bool, ApiError> {
+    task::spawn_blocking(move || {
+        let parsed = PasswordHash::new(&password_hash)?;
+        match Argon2::default().verify_password(password.as_bytes(), &parsed) {
+            Ok(()) => Ok(true),
+            Err(argon2::password_hash::Error::Password) => Ok(false),
+            Err(err) => Err(err),
+        }
+    })
+    .await
+    .map_err(|err| ApiError::Internal(format!("Password check failed: {}", err)))?
+    .map_err(|err| ApiError::Internal(format!("Password check failed: {}", err)))
+}
+
+/// A random code for people to pass around, such as an invite or a circle's join code.
+pub fn new_code() -> String {
+    let mut rng = rand::thread_rng();
+    (0..CODE_LENGTH)
+        .map(|_| CODE_ALPHABET[rng.gen_range(0..CODE_ALPHABET.len())] as char)
+        .collect()
+}
@@ -1,7 +1,9 @@
-use crate::api::types::{MusicTasteOverview, Song};
-use rocket::serde::Serialize;
+use crate::api::circles::CircleRole;
+use crate::api::scoring::ScoringWeights;
+use crate::api::types::Song;
+use sqlx::types::time::OffsetDateTime;
 use sqlx::{FromRow, Transaction};
-use sqlx_postgres::{PgPool, Postgres};
+use sqlx_postgres::{PgConnection, PgPool, Postgres};
 use std::option::Option;
 
 #[derive(FromRow)]
@@ -10,92 +12,1057 @@ pub struct User {
     pub name: String,
 }
 
-#[derive(FromRow, Serialize)]
-pub struct MusicTasteIndividual {
-    pub other_user_name: String,
-    pub overlapping_songs: Option<i64>,
-    pub song_rank_diff: Option<f64>,
-    pub song_relationship_strength: Option<f64>,
-    pub overlapping_artists: Option<i64>,
-    pub total_songs_shared_artists: Option<i64>,
-    pub artist_rank_diff: Option<f64>,
-    pub combined_score: Option<f64>,
-    pub overlapping_song_details: Option<serde_json::Value>,
-    pub overlapping_artist_details: Option<serde_json::Value>,
-}
-
-pub async fn get_or_insert_user(pool: &PgPool, name: &str) -> Result<User, sqlx::Error> {
-    // Try to find the user first
-    if let Some(user) = get_user(pool, name).await? {
-        return Ok(user); // Return the existing user
+pub async fn get_user(pool: &PgPool, name: &str) -> Result<Option<User>, sqlx::Error> {
+    // Check if the user already exists
+    let row = sqlx::query_as!(User, "SELECT id, name FROM users WHERE name = $1", name)
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(row) // Return Option<User>: Some(user) if found, None if not
+}
+
+pub async fn get_user_by_username(pool: &PgPool, username: &str) -> Result<Option<User>, sqlx::Error> {
+    let normalized = username.to_lowercase();
+    sqlx::query_as!(
+        User,
+        "SELECT id, name FROM users WHERE LOWER(name) = $1",
+        normalized
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Creates the user along with their password, if they chose one, in one transaction.
+pub async fn create_user(
+    pool: &PgPool,
+    username: &str,
+    first_name: &str,
+    last_name: &str,
+    password_hash: Option<&str>,
+) -> Result<User, sqlx::Error> {
+    let mut tx: Transaction<'_, Postgres> = pool.begin().await?;
+
+    let normalized_username = username.to_lowercase();
+    let display_name = format!("{} {}", first_name.trim(), last_name.trim());
+
+    let row = sqlx::query!(
+        "INSERT INTO users (name, display_name) VALUES ($1, $2) RETURNING id, name",
+        normalized_username,
+        display_name
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    if let Some(password_hash) = password_hash {
+        insert_password_hash(&mut tx, &row.id, password_hash).await?;
+    }
+
+    tx.commit().await?;
+
+    Ok(User {
+        id: row.id,
+        name: row.name,
+    })
+}
+
+async fn insert_password_hash(
+    conn: &mut PgConnection,
+    user_id: &i32,
+    password_hash: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO user_credentials (user_id, password_hash) VALUES ($1, $2)",
+        user_id,
+        password_hash
+    )
+    .execute(conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Whether `err` is a unique constraint refusing a duplicate.
+pub fn is_unique_violation(err: &sqlx::Error) -> bool {
+    err.as_database_error()
+        .is_some_and(|err| err.is_unique_violation())
+}
+
+pub async fn get_password_hash(
+    pool: &PgPool,
+    user_id: &i32,
+) -> Result<Option<String>, sqlx::Error> {
+    let row = sqlx::query!(
+        "SELECT password_hash FROM user_credentials WHERE user_id = $1",
+        user_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.map(|row| row.password_hash))
+}
+
+pub async fn set_password_hash(
+    pool: &PgPool,
+    user_id: &i32,
+    password_hash: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO user_credentials (user_id, password_hash)
+        VALUES ($1, $2)
+        ON CONFLICT (user_id) DO UPDATE SET
+            password_hash = EXCLUDED.password_hash,
+            updated_at = now()
+        "#,
+        user_id,
+        password_hash
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn insert_session(
+    pool: &PgPool,
+    id: &str,
+    user_id: &i32,
+    user_agent: Option<&str>,
+    expires_at: OffsetDateTime,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "INSERT INTO sessions (id, user_id, user_agent, expires_at) VALUES ($1, $2, $3, $4)",
+        id,
+        user_id,
+        user_agent,
+        expires_at
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Resolves a live session to its user, marking it as just seen.
+pub async fn touch_session(pool: &PgPool, id: &str) -> Result<Option<User>, sqlx::Error> {
+    sqlx::query_as!(
+        User,
+        r#"
+        UPDATE sessions s SET last_seen_at = now()
+        FROM users u
+        WHERE s.id = $1 AND s.user_id = u.id AND s.expires_at > now()
+        RETURNING u.id, u.name
+        "#,
+        id
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+#[derive(FromRow)]
+pub struct SessionRow {
+    pub id: String,
+    pub user_agent: Option<String>,
+    pub created_at: OffsetDateTime,
+    pub last_seen_at: OffsetDateTime,
+    pub expires_at: OffsetDateTime,
+}
+
+pub async fn get_user_sessions(pool: &PgPool, user_id: &i32) -> Result<Vec<SessionRow>, sqlx::Error> {
+    sqlx::query_as!(
+        SessionRow,
+        r#"
+        SELECT id, user_agent, created_at, last_seen_at, expires_at
+        FROM sessions
+        WHERE user_id = $1 AND expires_at > now()
+        ORDER BY last_seen_at DESC
+        "#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub async fn delete_session(pool: &PgPool, id: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM sessions WHERE id = $1", id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Ends every session the user has, returning how many there were.
+pub async fn delete_user_sessions(pool: &PgPool, user_id: &i32) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!("DELETE FROM sessions WHERE user_id = $1", user_id)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+pub async fn delete_expired_sessions(pool: &PgPool) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!("DELETE FROM sessions WHERE expires_at <= now()")
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected())
+}
+
+#[derive(FromRow)]
+pub struct InviteCode {
+    pub code: String,
+    pub circle_id: Option<i32>,
+    pub max_uses: i32,
+    pub uses: i32,
+    pub expires_at: Option<OffsetDateTime>,
+    pub created_at: OffsetDateTime,
+}
+
+pub async fn insert_invite_code(
+    pool: &PgPool,
+    code: &str,
+    created_by: &i32,
+    circle_id: Option<i32>,
+    max_uses: i32,
+    expires_at: Option<OffsetDateTime>,
+) -> Result<InviteCode, sqlx::Error> {
+    sqlx::query_as!(
+        InviteCode,
+        r#"
+        INSERT INTO invite_codes (code, created_by, circle_id, max_uses, expires_at)
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING code, circle_id, max_uses, uses, expires_at, created_at
+        "#,
+        code,
+        created_by,
+        circle_id,
+        max_uses,
+        expires_at
+    )
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn get_invite_codes(pool: &PgPool) -> Result<Vec<InviteCode>, sqlx::Error> {
+    sqlx::query_as!(
+        InviteCode,
+        r#"
+        SELECT code, circle_id, max_uses, uses, expires_at, created_at
+        FROM invite_codes
+        ORDER BY created_at DESC
+        "#
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Returns whether there was such a code to delete.
+pub async fn delete_invite_code(pool: &PgPool, code: &str) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!("DELETE FROM invite_codes WHERE code = $1", code)
+        .execute(pool)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Creates the user and uses up one redemption of `invite_code` together, so a signup that fails
+/// never costs the code a use. The user joins the invite's circle, if it has one. Returns `None`,
+/// creating nobody, when the code is unknown, expired or used up.
+pub async fn create_invited_user(
+    pool: &PgPool,
+    invite_code: &str,
+    username: &str,
+    first_name: &str,
+    last_name: &str,
+    password_hash: Option<&str>,
+) -> Result<Option<User>, sqlx::Error> {
+    let mut tx: Transaction<'_, Postgres> = pool.begin().await?;
+
+    let claimed = sqlx::query!(
+        r#"
+        UPDATE invite_codes SET uses = uses + 1
+        WHERE code = $1 AND uses < max_uses AND (expires_at IS NULL OR expires_at > now())
+        RETURNING circle_id
+        "#,
+        invite_code
+    )
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let circle_id = match claimed {
+        Some(claimed) => claimed.circle_id,
+        None => return Ok(None),
+    };
+
+    let normalized_username = username.to_lowercase();
+    let display_name = format!("{} {}", first_name.trim(), last_name.trim());
+    let user = sqlx::query_as!(
+        User,
+        "INSERT INTO users (name, display_name) VALUES ($1, $2) RETURNING id, name",
+        normalized_username,
+        display_name
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "INSERT INTO invite_redemptions (code, user_id) VALUES ($1, $2)",
+        invite_code,
+        user.id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    if let Some(password_hash) = password_hash {
+        insert_password_hash(&mut tx, &user.id, password_hash).await?;
+    }
+
+    if let Some(circle_id) = circle_id {
+        sqlx::query!(
+            "INSERT INTO circle_members (circle_id, user_id) VALUES ($1, $2)",
+            circle_id,
+            user.id
+        )
+        .execute(&mut *tx)
+        .await?;
     }
 
-    // If the user doesn't exist, insert the user and return the new user
+    tx.commit().await?;
+
+    Ok(Some(user))
+}
+
+/// A user's place in one circle.
+#[derive(FromRow)]
+pub struct Membership {
+    pub circle_id: i32,
+    pub role: CircleRole,
+}
+
+pub async fn get_membership(
+    pool: &PgPool,
+    user_id: &i32,
+    circle_id: &i32,
+) -> Result<Option<Membership>, sqlx::Error> {
+    sqlx::query_as!(
+        Membership,
+        r#"
+        SELECT circle_id, role AS "role: CircleRole"
+        FROM circle_members
+        WHERE user_id = $1 AND circle_id = $2
+        "#,
+        user_id,
+        circle_id
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// The circle the user joined first, used when a request does not name one.
+pub async fn get_first_membership(
+    pool: &PgPool,
+    user_id: &i32,
+) -> Result<Option<Membership>, sqlx::Error> {
+    sqlx::query_as!(
+        Membership,
+        r#"
+        SELECT circle_id, role AS "role: CircleRole"
+        FROM circle_members
+        WHERE user_id = $1
+        ORDER BY joined_at, circle_id
+        LIMIT 1
+        "#,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+#[derive(FromRow)]
+pub struct CircleSummaryRow {
+    pub id: i32,
+    pub name: String,
+    pub role: CircleRole,
+    pub member_count: i64,
+}
+
+pub async fn get_user_circles(
+    pool: &PgPool,
+    user_id: &i32,
+) -> Result<Vec<CircleSummaryRow>, sqlx::Error> {
+    sqlx::query_as!(
+        CircleSummaryRow,
+        r#"
+        SELECT
+            c.id,
+            c.name,
+            m.role AS "role: CircleRole",
+            (SELECT COUNT(*) FROM circle_members all_m WHERE all_m.circle_id = c.id) AS "member_count!"
+        FROM circle_members m
+        JOIN circles c ON c.id = m.circle_id
+        WHERE m.user_id = $1
+        ORDER BY m.joined_at, c.id
+        "#,
+        user_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+#[derive(FromRow)]
+pub struct Circle {
+    pub id: i32,
+    pub name: String,
+    pub join_code: String,
+}
+
+pub async fn get_circle(pool: &PgPool, id: &i32) -> Result<Option<Circle>, sqlx::Error> {
+    sqlx::query_as!(
+        Circle,
+        "SELECT id, name, join_code FROM circles WHERE id = $1",
+        id
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn get_circle_by_join_code(
+    pool: &PgPool,
+    join_code: &str,
+) -> Result<Option<Circle>, sqlx::Error> {
+    sqlx::query_as!(
+        Circle,
+        "SELECT id, name, join_code FROM circles WHERE join_code = $1",
+        join_code
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Creates the circle with `owner_id` as its only member and the current calendar year as its first season.
+pub async fn create_circle(
+    pool: &PgPool,
+    name: &str,
+    join_code: &str,
+    owner_id: &i32,
+) -> Result<Circle, sqlx::Error> {
+    let mut tx: Transaction<'_, Postgres> = pool.begin().await?;
+
+    let circle = sqlx::query_as!(
+        Circle,
+        "INSERT INTO circles (name, join_code) VALUES ($1, $2) RETURNING id, name, join_code",
+        name,
+        join_code
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "INSERT INTO circle_members (circle_id, user_id, role) VALUES ($1, $2, 'owner')",
+        circle.id,
+        owner_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO seasons (circle_id, name, opens_at, locks_at, reveals_at, closes_at)
+        SELECT $1, to_char(now(), 'YYYY'), year_start, year_start + interval '1 year',
+            year_start + interval '1 year', year_start + interval '1 year'
+        FROM date_trunc('year', now()) AS year_start
+        "#,
+        circle.id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(circle)
+}
+
+pub async fn rename_circle(pool: &PgPool, circle_id: &i32, name: &str) -> Result<(), sqlx::Error> {
+    sqlx::query!("UPDATE circles SET name = $2 WHERE id = $1", circle_id, name)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+pub async fn set_circle_join_code(
+    pool: &PgPool,
+    circle_id: &i32,
+    join_code: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE circles SET join_code = $2 WHERE id = $1",
+        circle_id,
+        join_code
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_circle_owner(pool: &PgPool, circle_id: &i32) -> Result<Option<User>, sqlx::Error> {
+    sqlx::query_as!(
+        User,
+        r#"
+        SELECT u.id, u.name
+        FROM circle_members m
+        JOIN users u ON u.id = m.user_id
+        WHERE m.circle_id = $1 AND m.role = 'owner'
+        "#,
+        circle_id
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn delete_circle(pool: &PgPool, circle_id: &i32) -> Result<(), sqlx::Error> {
+    sqlx::query!("DELETE FROM circles WHERE id = $1", circle_id)
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Adds the user as a plain member. Returns false if they already belonged to the circle.
+pub async fn add_circle_member(
+    pool: &PgPool,
+    circle_id: &i32,
+    user_id: &i32,
+) -> Result<bool, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+        INSERT INTO circle_members (circle_id, user_id)
+        VALUES ($1, $2)
+        ON CONFLICT (circle_id, user_id) DO NOTHING
+        "#,
+        circle_id,
+        user_id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+#[derive(FromRow)]
+pub struct CircleMemberRow {
+    pub user_id: i32,
+    pub name: String,
+    pub display_name: String,
+    pub role: CircleRole,
+}
+
+pub async fn get_circle_members(
+    pool: &PgPool,
+    circle_id: &i32,
+) -> Result<Vec<CircleMemberRow>, sqlx::Error> {
+    sqlx::query_as!(
+        CircleMemberRow,
+        r#"
+        SELECT u.id AS user_id, u.name, u.display_name, m.role AS "role: CircleRole"
+        FROM circle_members m
+        JOIN users u ON u.id = m.user_id
+        WHERE m.circle_id = $1
+        ORDER BY m.joined_at, u.id
+        "#,
+        circle_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Makes the member an admin or a plain member. Ownership changes go through `transfer_circle_ownership`.
+pub async fn set_circle_member_role(
+    pool: &PgPool,
+    circle_id: &i32,
+    user_id: &i32,
+    role: CircleRole,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "UPDATE circle_members SET role = $3 WHERE circle_id = $1 AND user_id = $2",
+        circle_id,
+        user_id,
+        role as CircleRole
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Hands the circle to another member; the old owner stays on as an admin.
+pub async fn transfer_circle_ownership(
+    pool: &PgPool,
+    circle_id: &i32,
+    from_user_id: &i32,
+    to_user_id: &i32,
+) -> Result<(), sqlx::Error> {
+    let mut tx: Transaction<'_, Postgres> = pool.begin().await?;
+
+    // Demote first: a circle can only have one owner at a time.
+    sqlx::query!(
+        "UPDATE circle_members SET role = 'admin' WHERE circle_id = $1 AND user_id = $2",
+        circle_id,
+        from_user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "UPDATE circle_members SET role = 'owner' WHERE circle_id = $1 AND user_id = $2",
+        circle_id,
+        to_user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Removes the member along with their ballots in seasons whose voting has not locked yet. Ballots in
+/// locked and closed seasons stay, so those results never change.
+pub async fn remove_circle_member(
+    pool: &PgPool,
+    circle_id: &i32,
+    user_id: &i32,
+) -> Result<(), sqlx::Error> {
+    let mut tx: Transaction<'_, Postgres> = pool.begin().await?;
+
+    sqlx::query!(
+        r#"
+        DELETE FROM rankings
+        WHERE circle_id = $1 AND user_id = $2
+            AND season_id IN (SELECT id FROM seasons WHERE circle_id = $1 AND locks_at > now())
+        "#,
+        circle_id,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "DELETE FROM circle_members WHERE circle_id = $1 AND user_id = $2",
+        circle_id,
+        user_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+#[derive(FromRow)]
+pub struct Season {
+    pub id: i32,
+    pub circle_id: i32,
+    pub name: String,
+    pub opens_at: OffsetDateTime,
+    pub locks_at: OffsetDateTime,
+    pub reveals_at: OffsetDateTime,
+    pub closes_at: OffsetDateTime,
+    /// Ranks run from 1 to this.
+    pub ballot_size: i32,
+    pub generated_preview_id: Option<i32>,
+}
+
+pub async fn get_season(
+    pool: &PgPool,
+    circle_id: &i32,
+    season_id: &i32,
+) -> Result<Option<Season>, sqlx::Error> {
+    sqlx::query_as!(
+        Season,
+        r#"
+        SELECT id, circle_id, name, opens_at, locks_at, reveals_at, closes_at, ballot_size, generated_preview_id
+        FROM seasons
+        WHERE circle_id = $1 AND id = $2
+        "#,
+        circle_id,
+        season_id
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// The season open right now, or failing that the latest one to have opened.
+pub async fn get_current_season(
+    pool: &PgPool,
+    circle_id: &i32,
+) -> Result<Option<Season>, sqlx::Error> {
+    sqlx::query_as!(
+        Season,
+        r#"
+        SELECT id, circle_id, name, opens_at, locks_at, reveals_at, closes_at, ballot_size, generated_preview_id
+        FROM seasons
+        WHERE circle_id = $1 AND opens_at <= now()
+        ORDER BY (closes_at > now()) DESC, opens_at DESC
+        LIMIT 1
+        "#,
+        circle_id
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn get_circle_seasons(pool: &PgPool, circle_id: &i32) -> Result<Vec<Season>, sqlx::Error> {
+    sqlx::query_as!(
+        Season,
+        r#"
+        SELECT id, circle_id, name, opens_at, locks_at, reveals_at, closes_at, ballot_size, generated_preview_id
+        FROM seasons
+        WHERE circle_id = $1
+        ORDER BY opens_at DESC
+        "#,
+        circle_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Whether any of the circle's seasons, other than `except_id`, overlaps `opens_at..closes_at`.
+pub async fn season_overlaps(
+    pool: &PgPool,
+    circle_id: &i32,
+    except_id: Option<i32>,
+    opens_at: OffsetDateTime,
+    closes_at: OffsetDateTime,
+) -> Result<bool, sqlx::Error> {
     let row = sqlx::query!(
-        "INSERT INTO users (name) VALUES ($1) RETURNING id, name",
-        name
+        r#"
+        SELECT EXISTS (
+            SELECT 1 FROM seasons
+            WHERE circle_id = $1 AND id IS DISTINCT FROM $2 AND opens_at < $4 AND closes_at > $3
+        ) AS "overlaps!"
+        "#,
+        circle_id,
+        except_id,
+        opens_at,
+        closes_at
     )
     .fetch_one(pool)
     .await?;
 
-    // Return the newly inserted user
-    Ok(User {
-        id: row.id,
-        name: row.name,
-    })
+    Ok(row.overlaps)
 }
 
-pub async fn get_user(pool: &PgPool, name: &str) -> Result<Option<User>, sqlx::Error> {
-    // Check if the user already exists
-    let row = sqlx::query_as!(User, "SELECT id, name FROM users WHERE name = $1", name)
-        .fetch_optional(pool)
-        .await?;
+/// A season's name, phase boundaries (in order) and ballot size.
+pub struct SeasonWindow<'a> {
+    pub name: &'a str,
+    pub opens_at: OffsetDateTime,
+    pub locks_at: OffsetDateTime,
+    pub reveals_at: OffsetDateTime,
+    pub closes_at: OffsetDateTime,
+    pub ballot_size: i32,
+}
 
-    Ok(row) // Return Option<User>: Some(user) if found, None if not
+/// Adds the season, scoring with the circle's weights as they are now.
+pub async fn insert_season(
+    pool: &PgPool,
+    circle_id: &i32,
+    window: &SeasonWindow<'_>,
+) -> Result<Season, sqlx::Error> {
+    let mut tx: Transaction<'_, Postgres> = pool.begin().await?;
+
+    let season = sqlx::query_as!(
+        Season,
+        r#"
+        INSERT INTO seasons (circle_id, name, opens_at, locks_at, reveals_at, closes_at, ballot_size)
+        VALUES ($1, $2, $3, $4, $5, $6, $7)
+        RETURNING id, circle_id, name, opens_at, locks_at, reveals_at, closes_at, ballot_size, generated_preview_id
+        "#,
+        circle_id,
+        window.name,
+        window.opens_at,
+        window.locks_at,
+        window.reveals_at,
+        window.closes_at,
+        window.ballot_size
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        r#"
+        INSERT INTO scoring_weights
+            (circle_id, season_id, shared_song_points, shared_artist_points, artist_rank_diff_weight, rank_bonus)
+        SELECT circle_id, $2, shared_song_points, shared_artist_points, artist_rank_diff_weight, rank_bonus
+        FROM scoring_weights
+        WHERE circle_id = $1 AND season_id IS NULL
+        "#,
+        circle_id,
+        season.id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(season)
 }
 
-pub async fn get_user_by_username(pool: &PgPool, username: &str) -> Result<Option<User>, sqlx::Error> {
-    let normalized = username.to_lowercase();
+pub async fn update_season(
+    pool: &PgPool,
+    season_id: &i32,
+    window: &SeasonWindow<'_>,
+) -> Result<Season, sqlx::Error> {
     sqlx::query_as!(
-        User,
-        "SELECT id, name FROM users WHERE LOWER(name) = $1",
-        normalized
+        Season,
+        r#"
+        UPDATE seasons
+        SET name = $2, opens_at = $3, locks_at = $4, reveals_at = $5, closes_at = $6, ballot_size = $7
+        WHERE id = $1
+        RETURNING id, circle_id, name, opens_at, locks_at, reveals_at, closes_at, ballot_size, generated_preview_id
+        "#,
+        season_id,
+        window.name,
+        window.opens_at,
+        window.locks_at,
+        window.reveals_at,
+        window.closes_at,
+        window.ballot_size
+    )
+    .fetch_one(pool)
+    .await
+}
+
+/// The highest rank anyone has saved in the season, if anyone has voted.
+pub async fn get_highest_rank(pool: &PgPool, season_id: &i32) -> Result<Option<i32>, sqlx::Error> {
+    let row = sqlx::query!(
+        "SELECT MAX(rank) AS highest FROM rankings WHERE season_id = $1",
+        season_id
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(row.highest)
+}
+
+/// Seasons whose voting has locked but whose playlist has not been generated yet.
+pub async fn get_seasons_awaiting_playlist(pool: &PgPool) -> Result<Vec<Season>, sqlx::Error> {
+    sqlx::query_as!(
+        Season,
+        r#"
+        SELECT id, circle_id, name, opens_at, locks_at, reveals_at, closes_at, ballot_size, generated_preview_id
+        FROM seasons
+        WHERE locks_at <= now() AND generated_preview_id IS NULL
+        ORDER BY locks_at
+        "#
+    )
+    .fetch_all(pool)
+    .await
+}
+
+/// Saves a preview as the season's generated playlist, both in one transaction. The season row is locked
+/// first, so if its playlist was already generated nothing is saved and `None` comes back.
+pub async fn insert_generated_preview(
+    pool: &PgPool,
+    season: &Season,
+    user_id: &i32,
+    method: &str,
+    songs: &serde_json::Value,
+    voter_count: &i32,
+) -> Result<Option<i32>, sqlx::Error> {
+    let mut tx: Transaction<'_, Postgres> = pool.begin().await?;
+
+    let existing = sqlx::query_scalar!(
+        "SELECT generated_preview_id FROM seasons WHERE id = $1 FOR UPDATE",
+        season.id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+    if existing.is_some() {
+        return Ok(None);
+    }
+
+    let id = sqlx::query_scalar!(
+        "INSERT INTO playlist_previews (circle_id, season_id, created_by, method, songs, voter_count) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+        season.circle_id,
+        season.id,
+        user_id,
+        method,
+        songs,
+        voter_count
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "UPDATE seasons SET generated_preview_id = $2 WHERE id = $1",
+        season.id,
+        id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+    Ok(Some(id))
+}
+
+pub async fn get_circle_weights(
+    pool: &PgPool,
+    circle_id: &i32,
+) -> Result<Option<ScoringWeights>, sqlx::Error> {
+    sqlx::query_as!(
+        ScoringWeights,
+        r#"
+        SELECT shared_song_points, shared_artist_points, artist_rank_diff_weight, rank_bonus
+        FROM scoring_weights
+        WHERE circle_id = $1 AND season_id IS NULL
+        "#,
+        circle_id
     )
     .fetch_optional(pool)
     .await
 }
 
-pub async fn create_user(
+pub async fn get_season_weights(
     pool: &PgPool,
-    username: &str,
-    first_name: &str,
-    last_name: &str,
-) -> Result<User, sqlx::Error> {
-    let normalized_username = username.to_lowercase();
-    let display_name = format!("{} {}", first_name.trim(), last_name.trim());
+    season_id: &i32,
+) -> Result<Option<ScoringWeights>, sqlx::Error> {
+    sqlx::query_as!(
+        ScoringWeights,
+        r#"
+        SELECT shared_song_points, shared_artist_points, artist_rank_diff_weight, rank_bonus
+        FROM scoring_weights
+        WHERE season_id = $1
+        "#,
+        season_id
+    )
+    .fetch_optional(pool)
+    .await
+}
 
-    let row = sqlx::query!(
-        "INSERT INTO users (name, display_name) VALUES ($1, $2) RETURNING id, name",
-        normalized_username,
-        display_name
+pub async fn set_circle_weights(
+    pool: &PgPool,
+    circle_id: &i32,
+    weights: &ScoringWeights,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO scoring_weights
+            (circle_id, shared_song_points, shared_artist_points, artist_rank_diff_weight, rank_bonus)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (circle_id) WHERE season_id IS NULL DO UPDATE
+        SET shared_song_points = EXCLUDED.shared_song_points,
+            shared_artist_points = EXCLUDED.shared_artist_points,
+            artist_rank_diff_weight = EXCLUDED.artist_rank_diff_weight,
+            rank_bonus = EXCLUDED.rank_bonus,
+            updated_at = now()
+        "#,
+        circle_id,
+        weights.shared_song_points,
+        weights.shared_artist_points,
+        weights.artist_rank_diff_weight,
+        weights.rank_bonus
     )
-    .fetch_one(pool)
+    .execute(pool)
     .await?;
 
-    Ok(User {
-        id: row.id,
-        name: row.name,
-    })
+    Ok(())
+}
+
+pub async fn set_season_weights(
+    pool: &PgPool,
+    season: &Season,
+    weights: &ScoringWeights,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO scoring_weights
+            (circle_id, season_id, shared_song_points, shared_artist_points, artist_rank_diff_weight, rank_bonus)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (season_id) DO UPDATE
+        SET shared_song_points = EXCLUDED.shared_song_points,
+            shared_artist_points = EXCLUDED.shared_artist_points,
+            artist_rank_diff_weight = EXCLUDED.artist_rank_diff_weight,
+            rank_bonus = EXCLUDED.rank_bonus,
+            updated_at = now()
+        "#,
+        season.circle_id,
+        season.id,
+        weights.shared_song_points,
+        weights.shared_artist_points,
+        weights.artist_rank_diff_weight,
+        weights.rank_bonus
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+#[derive(FromRow)]
+pub struct SpotifyAccount {
+    pub spotify_user_id: String,
+    pub refresh_token: String,
+    pub access_token: Option<String>,
+    pub access_token_expires_at: Option<OffsetDateTime>,
+}
+
+pub async fn get_spotify_account(
+    pool: &PgPool,
+    user_id: &i32,
+) -> Result<Option<SpotifyAccount>, sqlx::Error> {
+    sqlx::query_as!(
+        SpotifyAccount,
+        r#"
+        SELECT spotify_user_id, refresh_token, access_token, access_token_expires_at
+        FROM spotify_accounts
+        WHERE user_id = $1
+        "#,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+pub async fn upsert_spotify_account(
+    pool: &PgPool,
+    user_id: &i32,
+    spotify_user_id: &str,
+    refresh_token: &str,
+    access_token: &str,
+    access_token_expires_at: OffsetDateTime,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO spotify_accounts (user_id, spotify_user_id, refresh_token, access_token, access_token_expires_at)
+        VALUES ($1, $2, $3, $4, $5)
+        ON CONFLICT (user_id) DO UPDATE SET
+            spotify_user_id = EXCLUDED.spotify_user_id,
+            refresh_token = EXCLUDED.refresh_token,
+            access_token = EXCLUDED.access_token,
+            access_token_expires_at = EXCLUDED.access_token_expires_at
+        "#,
+        user_id,
+        spotify_user_id,
+        refresh_token,
+        access_token,
+        access_token_expires_at
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
 }
 
 pub async fn insert_or_update_songs(
     pool: &PgPool,
+    season: &Season,
     user_id: &i32,
     songs: &Vec<Song>,
 ) -> Result<(), sqlx::Error> {
     let mut tx: Transaction<'_, Postgres> = pool.begin().await?;
 
-    for (song) in songs {
+    for song in songs {
         // Ensure the song exists in the database, insert it if not
         let song_id = sqlx::query!(
             r#"
@@ -118,11 +1085,13 @@ pub async fn insert_or_update_songs(
         // Insert or update the user's ranking for the song
         sqlx::query!(
             r#"
-            INSERT INTO rankings (user_id, song_id, rank)
-            VALUES ($1, $2, $3)
-            ON CONFLICT (user_id, rank) DO UPDATE SET
+            INSERT INTO rankings (circle_id, season_id, user_id, song_id, rank)
+            VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (season_id, user_id, rank) DO UPDATE SET
                 song_id = EXCLUDED.song_id
             "#,
+            season.circle_id,
+            season.id,
             user_id,
             song_id,
             song.rank.unwrap()
@@ -139,7 +1108,6 @@ pub async fn insert_or_update_songs(
 
 #[derive(sqlx::FromRow)]
 struct SongRow {
-    id: i32,
     name: String,
     uri: String,
     artist: String,
@@ -149,18 +1117,20 @@ struct SongRow {
 
 pub async fn get_songs_for_user_name(
     pool: &PgPool,
+    season_id: &i32,
     name: &String,
 ) -> Result<Vec<Song>, sqlx::Error> {
     // Check if the user already exists
     let rows = sqlx::query_as!(
         SongRow,
         r#"
-            SELECT songs.*, rankings.rank FROM songs
+            SELECT songs.name, songs.uri, songs.artist, songs.album_cover_url, rankings.rank FROM songs
             JOIN rankings ON songs.id = rankings.song_id
             JOIN users ON rankings.user_id = users.id
-            WHERE users."name" = $1
+            WHERE users."name" = $1 AND rankings.season_id = $2
         "#,
-        name
+        name,
+        season_id
     )
     .fetch_all(pool)
     .await?;
@@ -181,311 +1151,242 @@ pub async fn get_songs_for_user_name(
     Ok(songs)
 }
 
-#[derive(sqlx::FromRow, Debug)]
-struct Uri {
-    uri: String,
+/// A single ranking with the song it points at, the raw input to the ranking engine.
+#[derive(sqlx::FromRow)]
+pub struct BallotRow {
+    pub user_id: i32,
+    pub rank: i32,
+    pub song_id: i32,
+    pub name: String,
+    pub artist: String,
+    pub uri: String,
+    pub album_cover_url: String,
 }
 
-pub async fn get_song_rankings(pool: &PgPool) -> Result<Vec<String>, sqlx::Error> {
-    let rows = sqlx::query_as!(
-        Uri,
+pub async fn get_ballots(pool: &PgPool, season_id: &i32) -> Result<Vec<BallotRow>, sqlx::Error> {
+    sqlx::query_as!(
+        BallotRow,
         r#"
         SELECT
-            s.uri as URI
-        FROM
-            songs s
-        LEFT JOIN
-            rankings r ON s.id = r.song_id
-        GROUP BY
-            s.id, s.name
-        ORDER BY
-            COUNT(r.user_id) + COALESCE(0.15 * (11-AVG(r.rank)), 0) ASC, s.name
+            r.user_id,
+            r.rank AS "rank!",
+            s.id AS song_id,
+            s.name,
+            s.artist,
+            s.uri,
+            s.album_cover_url
+        FROM rankings r
+        JOIN songs s ON s.id = r.song_id
+        WHERE r.season_id = $1
+        ORDER BY r.user_id, r.rank
+        "#,
+        season_id
+    )
+    .fetch_all(pool)
+    .await
+}
+
+pub struct Voter {
+    pub id: i32,
+    pub name: String,
+    pub display_name: Option<String>,
+}
+
+/// Everyone with a ballot in the season.
+pub async fn get_season_voters(pool: &PgPool, season_id: &i32) -> Result<Vec<Voter>, sqlx::Error> {
+    sqlx::query_as!(
+        Voter,
+        r#"
+        SELECT id, name, display_name
+        FROM users
+        WHERE id IN (SELECT user_id FROM rankings WHERE season_id = $1)
+        ORDER BY id
         "#,
+        season_id
     )
     .fetch_all(pool)
+    .await
+}
+
+#[derive(FromRow)]
+pub struct PlaylistPreviewRow {
+    pub id: i32,
+    pub circle_id: i32,
+    pub season_id: i32,
+    pub created_by: i32,
+    pub method: String,
+    pub songs: serde_json::Value,
+    pub voter_count: i32,
+}
+
+pub async fn insert_playlist_preview(
+    pool: &PgPool,
+    season: &Season,
+    user_id: &i32,
+    method: &str,
+    songs: &serde_json::Value,
+    voter_count: &i32,
+) -> Result<i32, sqlx::Error> {
+    let row = sqlx::query!(
+        "INSERT INTO playlist_previews (circle_id, season_id, created_by, method, songs, voter_count) VALUES ($1, $2, $3, $4, $5, $6) RETURNING id",
+        season.circle_id,
+        season.id,
+        user_id,
+        method,
+        songs,
+        voter_count
+    )
+    .fetch_one(pool)
     .await?;
 
-    Ok(rows.into_iter().map(|song| song.uri).collect())
+    Ok(row.id)
 }
 
-pub async fn get_music_taste_overview(
+pub async fn get_playlist_preview(
     pool: &PgPool,
-) -> Result<Vec<MusicTasteOverview>, sqlx::Error> {
-    let rows = sqlx::query_as!(
-        MusicTasteOverview,
-        r#"
-        WITH user_pairs AS (
-    SELECT 
-        r1.user_id AS user1_id,
-        r2.user_id AS user2_id,
-        r1.song_id,
-        r1.rank AS user1_rank,
-        r2.rank AS user2_rank,
-        ABS(r1.rank - r2.rank) AS rank_difference,
-        s.artist
-    FROM rankings r1
-    JOIN rankings r2 
-        ON r1.song_id = r2.song_id 
-        AND r1.user_id < r2.user_id
-    JOIN songs s ON r1.song_id = s.id
-),
-song_overlap AS (
-    SELECT 
-        user1_id,
-        user2_id,
-        COUNT(*) AS overlapping_songs,
-        COUNT(DISTINCT artist) AS artists_in_overlap,
-        AVG(rank_difference) AS avg_rank_difference,
-        COUNT(*) * 10.0 - AVG(rank_difference) AS song_relationship_strength
-    FROM user_pairs
-    GROUP BY user1_id, user2_id
-),
-artist_overlap AS (
-    SELECT 
-        r1.user_id AS user1_id,
-        r2.user_id AS user2_id,
-        COUNT(DISTINCT s1.artist) AS shared_artists,
-        COUNT(*) AS total_artist_overlaps,
-        AVG(ABS(r1.rank - r2.rank)) AS avg_artist_rank_diff
-    FROM rankings r1
-    JOIN rankings r2 ON r1.user_id < r2.user_id
-    JOIN songs s1 ON r1.song_id = s1.id
-    JOIN songs s2 ON r2.song_id = s2.id
-    WHERE s1.artist = s2.artist
-    GROUP BY r1.user_id, r2.user_id
-),
-overlapping_song_details AS (
-    -- Get the song details for each pair with full information
-    SELECT 
-        up.user1_id,
-        up.user2_id,
-        JSON_AGG(
-            JSON_BUILD_OBJECT(
-                'song_name', s.name,
-                'artist', s.artist,
-                'user1_rank', up.user1_rank,
-                'user2_rank', up.user2_rank,
-                'rank_difference', up.rank_difference
-            ) ORDER BY up.rank_difference ASC, up.user1_rank ASC
-        ) AS songs
-    FROM user_pairs up
-    JOIN songs s ON up.song_id = s.id
-    GROUP BY up.user1_id, up.user2_id
-),
-artist_detail_pairs AS (
-    -- Get all song pairs by the same artist for each user pair
-    SELECT 
-        r1.user_id AS user1_id,
-        r2.user_id AS user2_id,
-        s1.artist,
-        s1.name AS user1_song,
-        r1.rank AS user1_rank,
-        s2.name AS user2_song,
-        r2.rank AS user2_rank,
-        ABS(r1.rank - r2.rank) AS rank_difference
-    FROM rankings r1
-    JOIN rankings r2 ON r1.user_id < r2.user_id
-    JOIN songs s1 ON r1.song_id = s1.id
-    JOIN songs s2 ON r2.song_id = s2.id
-    WHERE s1.artist = s2.artist
-),
-artist_overlap_details AS (
-    -- Aggregate artist details with all song combinations
-    SELECT 
-        user1_id,
-        user2_id,
-        JSON_AGG(
-            JSON_BUILD_OBJECT(
-                'artist', artist,
-                'user1_song', user1_song,
-                'user1_rank', user1_rank,
-                'user2_song', user2_song,
-                'user2_rank', user2_rank,
-                'rank_difference', rank_difference
-            ) ORDER BY rank_difference ASC, user1_rank ASC
-        ) AS artist_details
-    FROM artist_detail_pairs
-    GROUP BY user1_id, user2_id
-),
-combined_metrics AS (
-    SELECT 
-        COALESCE(so.user1_id, ao.user1_id) AS user1_id,
-        COALESCE(so.user2_id, ao.user2_id) AS user2_id,
-        -- Song metrics
-        COALESCE(so.overlapping_songs, 0) AS overlapping_songs,
-        COALESCE(so.avg_rank_difference, 0) AS avg_song_rank_diff,
-        COALESCE(so.song_relationship_strength, 0) AS song_strength,
-        -- Artist metrics
-        COALESCE(ao.shared_artists, 0) AS shared_artists,
-        COALESCE(ao.total_artist_overlaps, 0) AS artist_song_overlaps,
-        COALESCE(ao.avg_artist_rank_diff, 0) AS avg_artist_rank_diff,
-        -- Combined compatibility score
-        COALESCE(so.song_relationship_strength, 0) + 
-        (COALESCE(ao.shared_artists, 0) * 3.0) - 
-        COALESCE(ao.avg_artist_rank_diff, 0) * 0.5 AS combined_compatibility_score
-    FROM song_overlap so
-    FULL OUTER JOIN artist_overlap ao
-        ON so.user1_id = ao.user1_id 
-        AND so.user2_id = ao.user2_id
-    WHERE COALESCE(so.overlapping_songs, 0) > 0 
-       OR COALESCE(ao.shared_artists, 0) > 0
-)
-SELECT 
-    u1.display_name AS user_1,
-    u2.display_name AS user_2,
-    cm.overlapping_songs AS overlapping_songs,
-    CAST(ROUND(cm.avg_song_rank_diff, 2) AS DOUBLE PRECISION) AS song_rank_diff,
-    CAST(ROUND(cm.song_strength, 2) AS DOUBLE PRECISION) AS song_relationship_strength,
-    cm.shared_artists AS overlapping_artists,
-    cm.artist_song_overlaps AS total_songs_shared_artists,
-    CAST(ROUND(cm.avg_artist_rank_diff, 2) AS DOUBLE PRECISION) AS artist_rank_diff,
-    CAST(ROUND(cm.combined_compatibility_score, 2) AS DOUBLE PRECISION) AS combined_score,
-    -- Detailed JSON for HTML input
-    COALESCE(osd.songs, '[]'::json) AS overlapping_song_details,
-    COALESCE(aod.artist_details, '[]'::json) AS overlapping_artist_details
-FROM combined_metrics cm
-JOIN users u1 ON cm.user1_id = u1.id
-JOIN users u2 ON cm.user2_id = u2.id
-LEFT JOIN overlapping_song_details osd
-    ON cm.user1_id = osd.user1_id
-    AND cm.user2_id = osd.user2_id
-LEFT JOIN artist_overlap_details aod
-    ON cm.user1_id = aod.user1_id
-    AND cm.user2_id = aod.user2_id
-ORDER BY 
-    cm.combined_compatibility_score DESC,
-    cm.overlapping_songs DESC,
-    cm.shared_artists DESC
-LIMIT 5;
+    id: &i32,
+) -> Result<Option<PlaylistPreviewRow>, sqlx::Error> {
+    sqlx::query_as!(
+        PlaylistPreviewRow,
+        "SELECT id, circle_id, season_id, created_by, method, songs, voter_count FROM playlist_previews WHERE id = $1",
+        id
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+#[derive(FromRow)]
+pub struct PlaylistPublication {
+    pub id: i32,
+    pub playlist_id: String,
+}
+
+pub async fn get_playlist_publication(
+    pool: &PgPool,
+    preview_id: &i32,
+) -> Result<Option<PlaylistPublication>, sqlx::Error> {
+    sqlx::query_as!(
+        PlaylistPublication,
+        "SELECT id, playlist_id FROM playlist_publications WHERE preview_id = $1",
+        preview_id
+    )
+    .fetch_optional(pool)
+    .await
+}
+
+/// Starts a transaction holding the preview's row lock, so publishes of the same preview run one at a time.
+/// `NO KEY UPDATE` still lets other connections insert rows that reference the preview. The lock is
+/// released when the transaction ends, committed or dropped.
+pub async fn lock_preview(
+    pool: &PgPool,
+    preview_id: &i32,
+) -> Result<Transaction<'static, Postgres>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    sqlx::query!(
+        "SELECT id FROM playlist_previews WHERE id = $1 FOR NO KEY UPDATE",
+        preview_id
+    )
+    .fetch_one(&mut *tx)
+    .await?;
+
+    Ok(tx)
+}
+
+pub async fn insert_playlist_publication(
+    pool: &PgPool,
+    preview_id: &i32,
+    playlist_id: &str,
+) -> Result<PlaylistPublication, sqlx::Error> {
+    sqlx::query_as!(
+        PlaylistPublication,
+        "INSERT INTO playlist_publications (preview_id, playlist_id) VALUES ($1, $2) RETURNING id, playlist_id",
+        preview_id,
+        playlist_id
+    )
+    .fetch_one(pool)
+    .await
+}
+
+pub async fn get_generated_playlist(
+    pool: &PgPool,
+    owner_id: &i32,
+    circle_id: &i32,
+    season_id: &i32,
+) -> Result<Option<String>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+        SELECT playlist_id FROM generated_playlists
+        WHERE owner_id = $1 AND circle_id = $2 AND season_id = $3
+        "#,
+        owner_id,
+        circle_id,
+        season_id
+    )
+    .fetch_optional(pool)
+    .await?;
+
+    Ok(row.map(|row| row.playlist_id))
+}
+
+pub async fn upsert_generated_playlist(
+    pool: &PgPool,
+    owner_id: &i32,
+    circle_id: &i32,
+    season_id: &i32,
+    playlist_id: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO generated_playlists (owner_id, circle_id, season_id, playlist_id)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (owner_id, circle_id, season_id) DO UPDATE SET
+            playlist_id = EXCLUDED.playlist_id,
+            updated_at = now()
         "#,
+        owner_id,
+        circle_id,
+        season_id,
+        playlist_id
+    )
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_uploaded_batches(
+    pool: &PgPool,
+    publication_id: &i32,
+) -> Result<Vec<i32>, sqlx::Error> {
+    let rows = sqlx::query!(
+        "SELECT batch_index FROM playlist_upload_batches WHERE publication_id = $1 ORDER BY batch_index",
+        publication_id
     )
     .fetch_all(pool)
     .await?;
 
-    Ok(rows)
+    Ok(rows.into_iter().map(|row| row.batch_index).collect())
 }
 
-pub async fn get_music_taste_user(pool: &PgPool, active_user_id: &i32) -> Result<Vec<MusicTasteIndividual>, sqlx::Error> {
+pub async fn record_uploaded_batch(
+    pool: &PgPool,
+    publication_id: &i32,
+    batch_index: i32,
+    track_count: i32,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+        INSERT INTO playlist_upload_batches (publication_id, batch_index, track_count)
+        VALUES ($1, $2, $3)
+        ON CONFLICT (publication_id, batch_index) DO NOTHING
+        "#,
+        publication_id,
+        batch_index,
+        track_count
+    )
+    .execute(pool)
+    .await?;
 
-    let rows = sqlx::query_as!(
-        MusicTasteIndividual,
-        r#"
-        WITH active_user_songs AS (
-    SELECT song_id, rank
-    FROM rankings
-    WHERE user_id = $1
-),
-other_users_songs AS (
-    SELECT user_id, song_id, rank
-    FROM rankings
-    WHERE user_id != $1
-),
-song_overlap AS (
-    SELECT 
-        ous.user_id AS other_user_id,
-        COUNT(*) AS overlapping_songs,
-        COUNT(DISTINCT s.artist) AS artists_in_overlap,
-        AVG(ABS(aus.rank - ous.rank)) AS avg_rank_difference,
-        COUNT(*) * 10.0 - AVG(ABS(aus.rank - ous.rank)) AS song_relationship_strength
-    FROM active_user_songs aus
-    JOIN other_users_songs ous ON aus.song_id = ous.song_id
-    JOIN songs s ON aus.song_id = s.id
-    GROUP BY ous.user_id
-),
-active_user_artists AS (
-    SELECT DISTINCT s.artist, r.rank, s.name as song_name
-    FROM rankings r
-    JOIN songs s ON r.song_id = s.id
-    WHERE r.user_id = $1
-),
-other_users_artists AS (
-    SELECT r.user_id, s.artist, r.rank, s.name as song_name
-    FROM rankings r
-    JOIN songs s ON r.song_id = s.id
-    WHERE r.user_id != $1
-),
-artist_overlap AS (
-    SELECT 
-        oua.user_id AS other_user_id,
-        COUNT(DISTINCT aua.artist) AS shared_artists,
-        COUNT(*) AS total_artist_overlaps,
-        AVG(ABS(aua.rank - oua.rank)) AS avg_artist_rank_diff
-    FROM active_user_artists aua
-    JOIN other_users_artists oua ON aua.artist = oua.artist
-    GROUP BY oua.user_id
-),
-overlapping_song_details AS (
-    SELECT 
-        ous.user_id AS other_user_id,
-        JSON_AGG(
-            JSON_BUILD_OBJECT(
-                'song_name', s.name,
-                'artist', s.artist,
-                'active_user_rank', aus.rank,
-                'other_user_rank', ous.rank,
-                'rank_difference', ABS(aus.rank - ous.rank)
-            ) ORDER BY ABS(aus.rank - ous.rank) ASC, aus.rank ASC
-        ) AS songs
-    FROM active_user_songs aus
-    JOIN other_users_songs ous ON aus.song_id = ous.song_id
-    JOIN songs s ON aus.song_id = s.id
-    GROUP BY ous.user_id
-),
-artist_overlap_details AS (
-    SELECT 
-        oua.user_id AS other_user_id,
-        JSON_AGG(
-            JSON_BUILD_OBJECT(
-                'artist', aua.artist,
-                'active_user_song', aua.song_name,
-                'active_user_rank', aua.rank,
-                'other_user_song', oua.song_name,
-                'other_user_rank', oua.rank,
-                'rank_difference', ABS(aua.rank - oua.rank)
-            ) ORDER BY ABS(aua.rank - oua.rank) ASC, aua.rank ASC
-        ) AS artist_details
-    FROM active_user_artists aua
-    JOIN other_users_artists oua ON aua.artist = oua.artist
-    GROUP BY oua.user_id
-),
-combined_metrics AS (
-    SELECT 
-        COALESCE(so.other_user_id, ao.other_user_id) AS other_user_id,
-        COALESCE(so.overlapping_songs, 0) AS overlapping_songs,
-        COALESCE(so.avg_rank_difference, 0) AS avg_song_rank_diff,
-        COALESCE(so.song_relationship_strength, 0) AS song_strength,
-        COALESCE(ao.shared_artists, 0) AS shared_artists,
-        COALESCE(ao.total_artist_overlaps, 0) AS artist_song_overlaps,
-        COALESCE(ao.avg_artist_rank_diff, 0) AS avg_artist_rank_diff,
-        COALESCE(so.song_relationship_strength, 0) + 
-        (COALESCE(ao.shared_artists, 0) * 3.0) - 
-        COALESCE(ao.avg_artist_rank_diff, 0) * 0.5 AS combined_compatibility_score
-    FROM song_overlap so
-    FULL OUTER JOIN artist_overlap ao ON so.other_user_id = ao.other_user_id
-    WHERE COALESCE(so.overlapping_songs, 0) > 0 
-       OR COALESCE(ao.shared_artists, 0) > 0
-)
-SELECT
-    COALESCE(u.display_name, u.name) AS "other_user_name!",
-    cm.overlapping_songs,
-    CAST(ROUND(cm.avg_song_rank_diff, 2) AS DOUBLE PRECISION) AS song_rank_diff,
-    CAST(ROUND(cm.song_strength, 2) AS DOUBLE PRECISION) AS song_relationship_strength,
-    cm.shared_artists AS overlapping_artists,
-    cm.artist_song_overlaps AS total_songs_shared_artists,
-    CAST(ROUND(cm.avg_artist_rank_diff, 2) AS DOUBLE PRECISION) AS artist_rank_diff,
-    CAST(ROUND(cm.combined_compatibility_score, 2) AS DOUBLE PRECISION) AS combined_score,
-    COALESCE(osd.songs, '[]'::json) AS overlapping_song_details,
-    COALESCE(aod.artist_details, '[]'::json) AS overlapping_artist_details
-FROM combined_metrics cm
-JOIN users u ON cm.other_user_id = u.id
-LEFT JOIN overlapping_song_details osd ON cm.other_user_id = osd.other_user_id
-LEFT JOIN artist_overlap_details aod ON cm.other_user_id = aod.other_user_id
-ORDER BY
-    cm.combined_compatibility_score DESC
-LIMIT 10
-        "#,
-active_user_id
-    ).fetch_all(pool).await?;
-
-    Ok(rows)
+    Ok(())
 }
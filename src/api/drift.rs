@@ -0,0 +1,246 @@
+//! How one user's ballot changed from one season to another: the artists that stayed, the songs that were
+//! carried over and a single drift figure summing it up, plus how their compatibility with everyone else
+//! moved.
+
+use crate::api::compatibility::round2;
+use crate::api::db::Voter;
+use crate::api::types::{CarriedOverSong, CompatibilityChange, PersistedArtist, Song};
+use std::collections::{HashMap, HashSet};
+
+pub struct BallotDrift {
+    pub persisted_artists: Vec<PersistedArtist>,
+    pub carried_over_songs: Vec<CarriedOverSong>,
+    /// 0 when both ballots hold the same songs in the same order, 1 when they share no song or artist.
+    pub drift: f64,
+}
+
+/// Each artist's best (lowest) rank on the ballot.
+fn best_artist_ranks(ballot: &[Song], ballot_size: i32) -> HashMap<&str, i32> {
+    let mut ranks: HashMap<&str, i32> = HashMap::new();
+    for song in ballot {
+        let rank = song.rank.unwrap_or(ballot_size);
+        ranks
+            .entry(song.artist.as_str())
+            .and_modify(|best| *best = (*best).min(rank))
+            .or_insert(rank);
+    }
+    ranks
+}
+
+/// Compares the ballot from the earlier season (`from`) with the one from the later season (`to`).
+/// `ballot_size` is the larger of the two seasons' ballot sizes.
+///
+/// Drift averages two distances: one over songs, where a carried-over song counts for less the further it
+/// moved, and the Jaccard distance between the two sets of artists.
+pub fn compare_ballots(from: &[Song], to: &[Song], ballot_size: i32) -> BallotDrift {
+    let from_songs: HashMap<&str, &Song> = from.iter().map(|song| (song.uri.as_str(), song)).collect();
+
+    let mut carried_over_songs: Vec<CarriedOverSong> = to
+        .iter()
+        .filter_map(|song| {
+            let earlier = from_songs.get(song.uri.as_str())?;
+            Some(CarriedOverSong {
+                name: song.name.clone(),
+                artist: song.artist.clone(),
+                from_rank: earlier.rank.unwrap_or(ballot_size),
+                to_rank: song.rank.unwrap_or(ballot_size),
+            })
+        })
+        .collect();
+    carried_over_songs.sort_by_key(|song| song.to_rank);
+
+    let from_artists = best_artist_ranks(from, ballot_size);
+    let to_artists = best_artist_ranks(to, ballot_size);
+    let mut persisted_artists: Vec<PersistedArtist> = to_artists
+        .iter()
+        .filter_map(|(artist, to_rank)| {
+            Some(PersistedArtist {
+                artist: artist.to_string(),
+                from_rank: *from_artists.get(artist)?,
+                to_rank: *to_rank,
+            })
+        })
+        .collect();
+    persisted_artists.sort_by(|a, b| a.to_rank.cmp(&b.to_rank).then_with(|| a.artist.cmp(&b.artist)));
+
+    let ballot_length = from.len().max(to.len());
+    let song_similarity = if ballot_length == 0 {
+        0.0
+    } else {
+        carried_over_songs
+            .iter()
+            .map(|song| {
+                // Ranks can run past `ballot_size` if a season's size was cut after voting.
+                let moved = (song.from_rank - song.to_rank).abs() as f64 / ballot_size.max(1) as f64;
+                (1.0 - moved).clamp(0.0, 1.0)
+            })
+            .sum::<f64>()
+            / ballot_length as f64
+    };
+
+    let from_set: HashSet<&str> = from_artists.keys().copied().collect();
+    let to_set: HashSet<&str> = to_artists.keys().copied().collect();
+    let artist_union = from_set.union(&to_set).count();
+    let artist_similarity = if artist_union == 0 {
+        0.0
+    } else {
+        from_set.intersection(&to_set).count() as f64 / artist_union as f64
+    };
+
+    let drift = 1.0 - (song_similarity + artist_similarity) / 2.0;
+
+    BallotDrift {
+        persisted_artists,
+        carried_over_songs,
+        drift: round2(drift),
+    }
+}
+
+/// Pairs up the user's combined compatibility scores from the earlier season (`from`) and the later one
+/// (`to`) by friend. Biggest gain first; friends missing from either season come last, by username.
+pub fn compatibility_changes(from: &[(&Voter, f64)], to: &[(&Voter, f64)]) -> Vec<CompatibilityChange> {
+    let mut friends: HashMap<i32, CompatibilityChange> = HashMap::new();
+    for (is_from, scores) in [(true, from), (false, to)] {
+        for (voter, combined) in scores {
+            let friend = friends.entry(voter.id).or_insert_with(|| CompatibilityChange {
+                username: voter.name.clone(),
+                display_name: voter.display_name.clone(),
+                from_score: None,
+                to_score: None,
+                change: None,
+            });
+            let combined = Some(round2(*combined));
+            if is_from {
+                friend.from_score = combined;
+            } else {
+                friend.to_score = combined;
+            }
+        }
+    }
+
+    let mut changes: Vec<CompatibilityChange> = friends
+        .into_values()
+        .map(|mut friend| {
+            friend.change = friend
+                .from_score
+                .zip(friend.to_score)
+                .map(|(from, to)| round2(to - from));
+            friend
+        })
+        .collect();
+    changes.sort_by(|a, b| {
+        b.change
+            .unwrap_or(f64::NEG_INFINITY)
+            .total_cmp(&a.change.unwrap_or(f64::NEG_INFINITY))
+            .then_with(|| a.username.cmp(&b.username))
+    });
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn song(name: &str, artist: &str, rank: i32) -> Song {
+        Song {
+            key: None,
+            name: name.to_string(),
+            uri: format!("spotify:track:{}", name),
+            artist: artist.to_string(),
+            album_cover_url: String::new(),
+            rank: Some(rank),
+        }
+    }
+
+    fn voter(id: i32, name: &str) -> Voter {
+        Voter {
+            id,
+            name: name.to_string(),
+            display_name: None,
+        }
+    }
+
+    #[test]
+    fn identical_ballots_do_not_drift() {
+        let ballot = vec![song("a", "X", 1), song("b", "Y", 2)];
+        let drift = compare_ballots(&ballot, &ballot, 2);
+        assert_eq!(drift.drift, 0.0);
+        assert_eq!(drift.carried_over_songs.len(), 2);
+        assert_eq!(drift.persisted_artists.len(), 2);
+    }
+
+    #[test]
+    fn moved_songs_drift_by_how_far_they_moved() {
+        let from = vec![song("a", "X", 1), song("b", "Y", 2)];
+        let to = vec![song("b", "Y", 1), song("a", "X", 2)];
+        let drift = compare_ballots(&from, &to, 2);
+        let moves: Vec<(&str, i32, i32)> = drift
+            .carried_over_songs
+            .iter()
+            .map(|song| (song.name.as_str(), song.from_rank, song.to_rank))
+            .collect();
+        assert_eq!(moves, vec![("b", 2, 1), ("a", 1, 2)]);
+        // Each song keeps half its weight after moving one of two places; the artists all stay.
+        assert_eq!(drift.drift, 0.25);
+    }
+
+    #[test]
+    fn added_and_dropped_songs_are_not_carried_over() {
+        let from = vec![song("a", "X", 1), song("b", "Y", 2)];
+        let to = vec![song("a", "X", 1), song("c", "Z", 2)];
+        let drift = compare_ballots(&from, &to, 2);
+        assert_eq!(drift.carried_over_songs.len(), 1);
+        assert_eq!(drift.carried_over_songs[0].name, "a");
+        let artists: Vec<&str> = drift.persisted_artists.iter().map(|a| a.artist.as_str()).collect();
+        assert_eq!(artists, vec!["X"]);
+        // Songs: one of two stayed put; artists: one of three in common.
+        assert_eq!(drift.drift, 0.58);
+    }
+
+    #[test]
+    fn disjoint_and_empty_ballots_drift_completely() {
+        let from = vec![song("a", "X", 1)];
+        let to = vec![song("b", "Y", 1)];
+        assert_eq!(compare_ballots(&from, &to, 1).drift, 1.0);
+        assert_eq!(compare_ballots(&from, &[], 1).drift, 1.0);
+        let empty = compare_ballots(&[], &[], 10);
+        assert_eq!(empty.drift, 1.0);
+        assert!(empty.carried_over_songs.is_empty());
+        assert!(empty.persisted_artists.is_empty());
+    }
+
+    #[test]
+    fn songs_moved_past_the_ballot_size_drift_at_most_fully() {
+        let from = vec![song("a", "X", 1), song("b", "Y", 2)];
+        let to = vec![song("a", "X", 12), song("b", "Y", 2)];
+        // "a" moved further than a top 2 reaches, so it counts as fully moved rather than below zero.
+        assert_eq!(compare_ballots(&from, &to, 2).drift, 0.25);
+        let drift = compare_ballots(&from, &to, 0).drift;
+        assert!(drift.is_finite() && (0.0..=1.0).contains(&drift));
+    }
+
+    #[test]
+    fn compatibility_changes_pair_friends_up_across_seasons() {
+        let (bob, carol, dave) = (voter(2, "bob"), voter(3, "carol"), voter(4, "dave"));
+        let from = [(&bob, 10.0), (&carol, 20.004)];
+        let to = [(&bob, 15.5), (&carol, 12.0), (&dave, 7.0)];
+        let changes = compatibility_changes(&from, &to);
+        let rows: Vec<_> = changes
+            .iter()
+            .map(|change| (change.username.as_str(), change.from_score, change.to_score, change.change))
+            .collect();
+        assert_eq!(
+            rows,
+            vec![
+                ("bob", Some(10.0), Some(15.5), Some(5.5)),
+                ("carol", Some(20.0), Some(12.0), Some(-8.0)),
+                ("dave", None, Some(7.0), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn compatibility_changes_without_scores_are_empty() {
+        assert!(compatibility_changes(&[], &[]).is_empty());
+    }
+}
@@ -0,0 +1,212 @@
+//! The one error type every route returns.
+//!
+//! Each variant maps to a fixed status and a machine-readable `code`. Client mistakes carry a message meant
+//! for the user; server-side failures (database, upstream, internal) are logged with a correlation id and
+//! only that id is sent back, never the underlying error text.
+
+use crate::api::types::{ErrorResponse, FieldError};
+use rocket::http::{Header, Status, StatusClass};
+use rocket::request::Request;
+use rocket::response::{self, Responder};
+use rocket::serde::json::Json;
+
+#[derive(Debug)]
+pub enum ApiError {
+    NotAuthenticated(String),
+    /// Logged in, but not allowed to do this yet (e.g. no Spotify account linked).
+    Forbidden(String),
+    NotFound(String),
+    Conflict(String),
+    /// The season's voting window has locked, so ballots can no longer change.
+    Locked(String),
+    Validation {
+        message: String,
+        fields: Vec<FieldError>,
+    },
+    /// The music provider failed or answered with something we could not use.
+    Upstream(String),
+    /// The music provider could not be reached or had a server error; trying again later may work.
+    Unavailable(String),
+    Database(sqlx::Error),
+    RateLimited {
+        /// Seconds the client should wait, when the upstream told us.
+        retry_after: Option<u64>,
+    },
+    /// Any other server-side failure.
+    Internal(String),
+}
+
+impl ApiError {
+    /// A validation error that is not about one particular field.
+    pub fn validation(message: impl Into<String>) -> Self {
+        ApiError::Validation {
+            message: message.into(),
+            fields: Vec::new(),
+        }
+    }
+
+    /// A validation error pinned to one request field.
+    pub fn invalid_field(field: &str, message: impl Into<String>) -> Self {
+        let message = message.into();
+        ApiError::Validation {
+            fields: vec![FieldError {
+                field: field.to_string(),
+                message: message.clone(),
+            }],
+            message,
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        match self {
+            ApiError::NotAuthenticated(_) => Status::Unauthorized,
+            ApiError::Forbidden(_) => Status::Forbidden,
+            ApiError::NotFound(_) => Status::NotFound,
+            ApiError::Conflict(_) => Status::Conflict,
+            ApiError::Locked(_) => Status::new(423),
+            ApiError::Validation { .. } => Status::BadRequest,
+            ApiError::Upstream(_) => Status::BadGateway,
+            ApiError::Unavailable(_) => Status::ServiceUnavailable,
+            ApiError::Database(_) | ApiError::Internal(_) => Status::InternalServerError,
+            ApiError::RateLimited { .. } => Status::TooManyRequests,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::NotAuthenticated(_) => "not_authenticated",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::Locked(_) => "locked",
+            ApiError::Validation { .. } => "validation_failed",
+            ApiError::Upstream(_) => "upstream_error",
+            ApiError::Unavailable(_) => "upstream_unavailable",
+            ApiError::Database(_) => "database_error",
+            ApiError::RateLimited { .. } => "rate_limited",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    /// Whether the same call may succeed if tried again.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, ApiError::Unavailable(_) | ApiError::RateLimited { .. })
+    }
+
+    /// The detail that must stay in the logs, for errors whose cause is on our side.
+    fn internal_detail(&self) -> Option<String> {
+        match self {
+            ApiError::Upstream(detail) | ApiError::Unavailable(detail) | ApiError::Internal(detail) => {
+                Some(detail.clone())
+            }
+            ApiError::Database(err) => Some(err.to_string()),
+            _ => None,
+        }
+    }
+}
+
+impl From<sqlx::Error> for ApiError {
+    fn from(err: sqlx::Error) -> Self {
+        ApiError::Database(err)
+    }
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let status = self.status();
+        let code = self.code();
+
+        let correlation_id = self.internal_detail().map(|detail| {
+            let id = format!("{:016x}", rand::random::<u64>());
+            rocket::error!(
+                "[{}] {} {} failed with {}: {}",
+                id,
+                request.method(),
+                request.uri(),
+                code,
+                detail
+            );
+            id
+        });
+
+        let retry_after = match &self {
+            ApiError::RateLimited { retry_after } => *retry_after,
+            _ => None,
+        };
+
+        let (error, fields) = match self {
+            ApiError::NotAuthenticated(message)
+            | ApiError::Forbidden(message)
+            | ApiError::NotFound(message)
+            | ApiError::Conflict(message)
+            | ApiError::Locked(message) => (message, Vec::new()),
+            ApiError::Validation { message, fields } => (message, fields),
+            ApiError::Upstream(_) => (
+                "The music service could not complete the request".to_string(),
+                Vec::new(),
+            ),
+            ApiError::Unavailable(_) => (
+                "The music service is unavailable, try again shortly".to_string(),
+                Vec::new(),
+            ),
+            ApiError::Database(_) | ApiError::Internal(_) => {
+                ("Something went wrong on our side".to_string(), Vec::new())
+            }
+            ApiError::RateLimited { .. } => (
+                "Too many requests to the music service, try again shortly".to_string(),
+                Vec::new(),
+            ),
+        };
+
+        let body = ErrorResponse {
+            error,
+            code: code.to_string(),
+            fields,
+            correlation_id,
+        };
+
+        let mut response = Json(body).respond_to(request)?;
+        response.set_status(status);
+        if let Some(seconds) = retry_after {
+            response.set_header(Header::new("Retry-After", seconds.to_string()));
+        }
+        Ok(response)
+    }
+}
+
+// Rocket's own failures (no matching route, a body that does not parse, a panicking handler) get the same JSON
+// body as every other error, keeping the status Rocket chose. Server-side ones are logged under a
+// correlation id like any `Internal` error.
+
+#[catch(400)]
+pub fn bad_request() -> ApiError {
+    ApiError::validation("The request is malformed")
+}
+
+#[catch(404)]
+pub fn not_found() -> ApiError {
+    ApiError::NotFound("Nothing here".to_string())
+}
+
+#[catch(422)]
+pub fn unprocessable_entity() -> (Status, ApiError) {
+    (
+        Status::UnprocessableEntity,
+        ApiError::validation("The request body could not be read"),
+    )
+}
+
+#[catch(500)]
+pub fn internal_error() -> ApiError {
+    ApiError::Internal("The request handler failed".to_string())
+}
+
+#[catch(default)]
+pub fn any_error(status: Status, _request: &Request<'_>) -> (Status, ApiError) {
+    let error = if status.class() == StatusClass::ServerError {
+        ApiError::Internal(format!("The request failed with {}", status))
+    } else {
+        ApiError::validation(status.reason().unwrap_or("The request could not be handled"))
+    };
+    (status, error)
+}
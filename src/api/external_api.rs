@@ -1,364 +1,327 @@
-use crate::api::types::{
-    AccessTokenResponse, AddSongsToPlaylistBody, CreatePlaylistBody, ErrorResponse,
-    SearchSongsQuery, Song,
-};
-use base64::Engine;
+use crate::api::db::User;
+use crate::api::error::ApiError;
+use crate::api::music_provider::MusicProvider;
+use crate::api::spotify_token::{SpotifyTokenManager, SpotifyUserToken};
+use crate::api::types::{AddSongsToPlaylistBody, CreatePlaylistBody, Track};
+use crate::DB_POOL;
 use base64::engine::general_purpose;
-use reqwest::Client;
-use rocket::http::{Cookie, CookieJar, Status};
-use rocket::response::Redirect;
-use rocket::serde::json::Json;
-use rocket::time::Duration;
-use rocket::tokio::time::sleep;
-use rocket::State;
-use std::collections::HashSet;
-use std::env;
-use std::time::Duration as StdDuration;
-
-static SPOTIFY_TOKEN_URL: &str = "https://accounts.spotify.com/api/token";
-
-pub async fn authenticate(cookies: &CookieJar<'_>) {
-    let client_id = env::var("SPOTIFY_CLIENT");
-    if client_id.is_err() {
-        Redirect::to("/fail");
-    }
+use base64::Engine;
+use reqwest::header::{CONTENT_TYPE, RETRY_AFTER};
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use rocket::serde::{DeserializeOwned, Deserialize};
+use std::sync::Arc;
+
+// Spotify rejects add-tracks calls with more URIs than this.
+const MAX_TRACKS_PER_REQUEST: usize = 100;
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct SpotifySearchResponse {
+    tracks: SpotifyPage<SpotifyTrack>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct SpotifyPage<T> {
+    items: Vec<T>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct SpotifyTrack {
+    id: String,
+    uri: String,
+    name: String,
+    artists: Vec<SpotifyArtist>,
+    album: SpotifyAlbum,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct SpotifyArtist {
+    name: String,
+}
 
-    let client_secret = env::var("SPOTIFY_SECRET");
-    if client_secret.is_err() {
-        Redirect::to("/fail");
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct SpotifyAlbum {
+    images: Vec<SpotifyImage>,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct SpotifyImage {
+    url: String,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct SpotifyPlaylist {
+    id: String,
+}
+
+impl SpotifyTrack {
+    fn into_track(self) -> Option<Track> {
+        let artist = self.artists.into_iter().next()?.name;
+        // Spotify lists images largest first; the middle one (300px) suits the song cards.
+        let mut images = self.album.images;
+        let album_cover_url = if images.len() > 1 {
+            images.swap_remove(1).url
+        } else {
+            images.pop().map(|image| image.url).unwrap_or_default()
+        };
+
+        Some(Track {
+            id: self.id,
+            uri: self.uri,
+            name: self.name,
+            artist,
+            album_cover_url,
+        })
     }
+}
 
-    let encoded = general_purpose::STANDARD.encode(format!("{}:{}", client_id.unwrap(), client_secret.unwrap()));
+/// The Spotify Web API behind the `MusicProvider` trait.
+/// Search uses the app token, playlist writes use the owner's linked account.
+pub struct SpotifyProvider {
+    client: Client,
+    tokens: Arc<SpotifyTokenManager>,
+}
 
-    let basic_appended = format!("Basic {}", encoded);
+impl SpotifyProvider {
+    pub fn new(client: Client, tokens: Arc<SpotifyTokenManager>) -> Self {
+        SpotifyProvider { client, tokens }
+    }
 
-    //rocket::info!("JSON: {:?}", encoded);
+    async fn owner_token(&self, owner: &User) -> Result<SpotifyUserToken, ApiError> {
+        let db_pool = DB_POOL.get().unwrap();
+        self.tokens
+            .user_access_token(&self.client, db_pool, &owner.id)
+            .await
+    }
+}
 
-    let client = Client::new();
-    let response = client
-        .post(SPOTIFY_TOKEN_URL)
-        .header("Content-Type", "application/x-www-form-urlencoded")
-        .header("Authorization", basic_appended)
-        .form(&[
-            ("grant_type", "client_credentials"),
-        ])
+async fn send(request: RequestBuilder) -> Result<Response, ApiError> {
+    request
         .send()
         .await
-        .expect("Failed to get access token");
+        .map_err(|err| ApiError::Unavailable(format!("Failed to call Spotify API: {}", err)))
+}
 
-   let data: AccessTokenResponse = response
-        .json()
+async fn check_status(response: Response) -> Result<Response, ApiError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+
+    if status == StatusCode::TOO_MANY_REQUESTS {
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse().ok());
+        return Err(ApiError::RateLimited { retry_after });
+    }
+
+    let error_text = response
+        .text()
         .await
-        .expect("Failed to parse token response");
+        .unwrap_or_else(|e| format!("Failed to read response: {}", e));
 
-    cookies.add_private(
-        Cookie::build(("api_token", data.access_token))
-            .http_only(true)
-            .max_age(Duration::minutes(60)),
-    );
+    // Callers care whether the resource is gone; everything else is just an upstream failure.
+    if status == StatusCode::NOT_FOUND {
+        rocket::warn!("Spotify API returned 404: {}", error_text);
+        return Err(ApiError::NotFound("Not found on Spotify".to_string()));
+    }
+    if status.is_server_error() {
+        return Err(ApiError::Unavailable(format!("Spotify API error {}: {}", status, error_text)));
+    }
+    Err(ApiError::Upstream(format!("Spotify API error {}: {}", status, error_text)))
+}
 
-    sleep(StdDuration::from_secs(3)).await;
+async fn parse_json<T: DeserializeOwned>(response: Response) -> Result<T, ApiError> {
+    check_status(response)
+        .await?
+        .json::<T>()
+        .await
+        .map_err(|err| ApiError::Upstream(format!("Failed to parse Spotify API response: {}", err)))
 }
 
-pub async fn create_playlist(
-    cookies: &CookieJar<'_>,
-    client: &State<Client>,
-) -> Result<String, (Status, Json<ErrorResponse>)> {
-    let user_name_opt = cookies
-        .get_private("user")
-        .map(|cookie| cookie.value().to_string());
-
-    if user_name_opt.is_none() {
-        return Err((
-            Status::InternalServerError,
-            Json(ErrorResponse {
-                error: "No Token".to_string(),
-            }),
+#[rocket::async_trait]
+impl MusicProvider for SpotifyProvider {
+    async fn search_tracks(&self, query: &str, limit: u32) -> Result<Vec<Track>, ApiError> {
+        let access_token = self.tokens.access_token(&self.client).await?;
+
+        let spotify_url = self.tokens.config().api(&format!(
+            "/search?q={}&type=track&limit={}",
+            urlencoding::encode(query),
+            limit
         ));
-    }
 
-    let user_name = user_name_opt.unwrap();
+        let response = send(self.client.get(&spotify_url).bearer_auth(access_token)).await?;
+        let data: SpotifySearchResponse = parse_json(response).await?;
 
-    let split_name = user_name.split(':').nth(2).ok_or_else(|| {
-        (
-            Status::InternalServerError,
-            Json(ErrorResponse {
-                error: "Failed to parse Username to parse into Spotify API".to_string(),
-            }),
-        )
-    })?;
-
-    let split_name_trimmed = split_name.trim().replace('\\', "").replace('\"', "");
-
-    let access_token_opt = cookies
-        .get_private("api_token")
-        .map(|cookie| cookie.value().to_string());
-    if access_token_opt.is_none() {
-        return Err((
-            Status::InternalServerError,
-            Json(ErrorResponse {
-                error: "No Token".to_string(),
-            }),
-        ));
+        Ok(data
+            .tracks
+            .items
+            .into_iter()
+            .filter_map(SpotifyTrack::into_track)
+            .collect())
     }
 
-    let access_token = access_token_opt.unwrap();
-
-    let json_body = serde_json::to_string(&CreatePlaylistBody {
-        name: "Hottest100".to_string(),
-        description: "Hottest100".to_string(),
-        public: true,
-    })
-    .map_err(|err| {
-        (
-            Status::InternalServerError,
-            Json(ErrorResponse {
-                error: format!(
-                    "Failed to parse Username to parse into Spotify API: {}",
-                    err
-                ),
-            }),
-        )
-    })?;
-
-    let create_spotify_playlist = format!(
-        "https://api.spotify.com/v1/users/{}/playlists",
-        split_name_trimmed
-    );
+    async fn get_track(&self, id: &str) -> Result<Option<Track>, ApiError> {
+        let access_token = self.tokens.access_token(&self.client).await?;
 
-    rocket::info!("JSON {:#?}", json_body);
+        let spotify_url = self
+            .tokens
+            .config()
+            .api(&format!("/tracks/{}", urlencoding::encode(id)));
 
-    rocket::info!("URL {:#?}", create_spotify_playlist);
+        let response = send(self.client.get(&spotify_url).bearer_auth(access_token)).await?;
 
-    let response = client
-        .post(&create_spotify_playlist)
-        .body(json_body)
-        .header("Authorization", format!("Bearer {}", access_token))
-        .send()
-        .await
-        .map_err(|err| {
-            (
-                Status::InternalServerError,
-                Json(ErrorResponse {
-                    error: format!("Failed to create Playlist via Spotify API: {}", err),
-                }),
-            )
-        })?;
-
-    if response.status().is_success() {
-        if let Ok(data) = response.json::<serde_json::Value>().await {
-            let id = &data["id"];
-            // Process the `id` here
-            Ok(id.to_string())
-        } else {
-            Err((
-                Status::InternalServerError,
-                Json(ErrorResponse {
-                    error: "Failed to parse Spotify API response".to_string(),
-                }),
-            ))
+        // Spotify answers unknown and malformed ids alike with 400/404.
+        if matches!(
+            response.status(),
+            StatusCode::NOT_FOUND | StatusCode::BAD_REQUEST
+        ) {
+            return Ok(None);
         }
-    } else {
-        if let Ok(data) = response.json::<serde_json::Value>().await {
-            rocket::error!("Failed: {}", data);
-            Err((
-                Status::InternalServerError,
-                Json(ErrorResponse {
-                    error: format!("Failed to post to Spotify API - {}", data),
-                }),
-            ))
-        } else {
-            Err((
-                Status::InternalServerError,
-                Json(ErrorResponse {
-                    error: "Failed to parse Spotify API response".to_string(),
-                }),
-            ))
-        }
-    }
-}
-pub async fn add_songs_to_playlist(
-    create_playlist_id: String,
-    ranked_song_uris: Vec<String>,
-    cookies: &CookieJar<'_>,
-    client: &State<Client>,
-) -> Result<(), (Status, Json<ErrorResponse>)> {
-    let access_token_opt = cookies
-        .get_private("api_token")
-        .map(|cookie| cookie.value().to_string());
-    if access_token_opt.is_none() {
-        return Err((
-            Status::InternalServerError,
-            Json(ErrorResponse {
-                error: "No Access Token".to_string(),
-            }),
-        ));
+
+        let track: SpotifyTrack = parse_json(response).await?;
+        Ok(track.into_track())
     }
 
-    let access_token = access_token_opt.unwrap();
+    async fn create_playlist(
+        &self,
+        owner: &User,
+        playlist: &CreatePlaylistBody,
+    ) -> Result<String, ApiError> {
+        let user_token = self.owner_token(owner).await?;
 
-    let add_songs_to_playlist = AddSongsToPlaylistBody {
-        uris: ranked_song_uris,
-        position: 0,
-    };
+        let create_spotify_playlist = self.tokens.config().api(&format!(
+            "/users/{}/playlists",
+            urlencoding::encode(&user_token.spotify_user_id)
+        ));
 
-    let json_body = serde_json::to_string(&add_songs_to_playlist).map_err(|err| {
-        (
-            Status::InternalServerError,
-            Json(ErrorResponse {
-                error: format!("Failed to parse ranked songs into JSON: {}", err),
-            }),
+        let response = send(
+            self.client
+                .post(&create_spotify_playlist)
+                .bearer_auth(user_token.access_token)
+                .json(playlist),
         )
-    })?;
-
-    rocket::info!("Songs {:#?}", json_body);
+        .await?;
+        let created: SpotifyPlaylist = parse_json(response).await?;
 
-    let playlist_id = create_playlist_id.trim_matches('"');
+        Ok(created.id)
+    }
 
-    let create_spotify_playlist = format!(
-        "https://api.spotify.com/v1/playlists/{}/tracks",
-        playlist_id
-    );
+    async fn update_playlist_details(
+        &self,
+        owner: &User,
+        playlist_id: &str,
+        playlist: &CreatePlaylistBody,
+    ) -> Result<(), ApiError> {
+        let user_token = self.owner_token(owner).await?;
+
+        let playlist_url = self
+            .tokens
+            .config()
+            .api(&format!("/playlists/{}", urlencoding::encode(playlist_id)));
+
+        let response = send(
+            self.client
+                .put(&playlist_url)
+                .bearer_auth(user_token.access_token)
+                .json(playlist),
+        )
+        .await?;
+        check_status(response).await?;
 
-    rocket::info!("URL {:#?}", create_spotify_playlist);
+        Ok(())
+    }
 
-    let response = client
-        .post(&create_spotify_playlist)
-        .body(json_body)
-        .header("Authorization", format!("Bearer {}", access_token))
-        .send()
-        .await
-        .map_err(|err| {
-            (
-                Status::InternalServerError,
-                Json(ErrorResponse {
-                    error: format!(
-                        "Failed to add ranked songs to playlist via Spotify API: {}",
-                        err
-                    ),
-                }),
-            )
-        })?;
+    async fn upload_cover_image(
+        &self,
+        owner: &User,
+        playlist_id: &str,
+        jpeg: &[u8],
+    ) -> Result<(), ApiError> {
+        let user_token = self.owner_token(owner).await?;
+
+        let playlist_images = self
+            .tokens
+            .config()
+            .api(&format!("/playlists/{}/images", urlencoding::encode(playlist_id)));
+
+        // Spotify wants the JPEG base64-encoded in the body, yet labelled as image/jpeg.
+        let response = send(
+            self.client
+                .put(&playlist_images)
+                .bearer_auth(user_token.access_token)
+                .header(CONTENT_TYPE, "image/jpeg")
+                .body(general_purpose::STANDARD.encode(jpeg)),
+        )
+        .await?;
+        check_status(response).await?;
 
-    if response.status().is_success() {
         Ok(())
-    } else {
-        let error_text = response
-            .text()
-            .await
-            .unwrap_or_else(|e| format!("Failed to read response: {}", e));
-        Err((
-            Status::InternalServerError,
-            Json(ErrorResponse {
-                error: format!("Failed to post to Spotify API: {}", error_text),
-            }),
-        ))
     }
-}
 
-#[get("/search-songs?<query..>")]
-pub async fn search_spotify_songs(
-    cookies: &CookieJar<'_>,
-    query: Option<SearchSongsQuery>,
-    client: &State<Client>,
-) -> Result<Json<Vec<Song>>, (Status, Json<ErrorResponse>)> {
-    let query = query.unwrap();
-
-    let token = cookies
-        .get_private("api_token")
-        .map(|cookie| cookie.value().to_string());
-
-    if token.is_none() {
-        authenticate(cookies).await;
-        let token2 = cookies
-            .get_private("api_token")
-            .map(|cookie| cookie.value().to_string());
-        if token2.is_none() {
-            return Err((
-                Status::InternalServerError,
-                Json(ErrorResponse {
-                    error: "No Token".to_string(),
-                }),
-            ));
-        }
+    fn max_tracks_per_request(&self) -> usize {
+        MAX_TRACKS_PER_REQUEST
     }
 
-    let access_token = token.unwrap();
+    async fn add_tracks(
+        &self,
+        owner: &User,
+        playlist_id: &str,
+        uris: &[String],
+    ) -> Result<(), ApiError> {
+        let user_token = self.owner_token(owner).await?;
+
+        let add_songs_to_playlist = self
+            .tokens
+            .config()
+            .api(&format!("/playlists/{}/tracks", urlencoding::encode(playlist_id)));
+
+        let response = send(
+            self.client
+                .post(&add_songs_to_playlist)
+                .bearer_auth(user_token.access_token)
+                .json(&AddSongsToPlaylistBody {
+                    uris: uris.to_vec(),
+                }),
+        )
+        .await?;
+        check_status(response).await?;
 
-    // Extract the track name from the query
-    let track_name = query.track.unwrap();
-    let rank = query.rank.unwrap();
+        Ok(())
+    }
 
-    let spotify_url = format!(
-        "https://api.spotify.com/v1/search?q={}&type=track&limit=10",
-        track_name
-    );
-    let response = client
-        .get(&spotify_url)
-        .header("Authorization", format!("Bearer {}", access_token))
-        .send()
-        .await;
-
-    match response {
-        Ok(res) if res.status().is_success() => {
-            let data = res.json::<serde_json::Value>().await.map_err(|err| {
-                (
-                    Status::InternalServerError,
-                    Json(ErrorResponse {
-                        error: format!("Failed to parse Spotify API response: {}", err),
-                    }),
-                )
-            })?;
-
-            let items = data["tracks"]["items"].as_array();
-
-            let mut seen_keys = HashSet::new();
-            let songs: Vec<Song> = items
-                .unwrap()
-                .to_vec()
-                .into_iter()
-                .filter_map(|item| {
-                    let name = item["name"].as_str()?.to_string();
-                    let artist = item["artists"][0]["name"].as_str()?.to_string();
-                    let key = format!("{}{}", name, artist);
-
-                    // Skip if the key is a duplicate
-                    if !seen_keys.insert(key.clone()) {
-                        return None;
-                    }
-
-                    Some(Song {
-                        key: Some(key),
-                        name,
-                        artist,
-                        uri: item["uri"].as_str().unwrap_or_default().to_string(),
-                        album_cover_url: item["album"]["images"][1]["url"]
-                            .as_str()
-                            .unwrap_or_default()
-                            .to_string(),
-                        rank: Some(rank),
-                    })
-                })
-                .collect();
-
-            rocket::info!("Tracks {:#?}", songs);
-
-            Ok(Json(songs))
-        }
-        Ok(res) => {
-            let error_text = res.text().await.unwrap_or_default();
-            Err((
-                Status::InternalServerError,
-                Json(ErrorResponse {
-                    error: format!("Spotify API error: {}", error_text),
+    async fn replace_tracks(
+        &self,
+        owner: &User,
+        playlist_id: &str,
+        uris: &[String],
+    ) -> Result<(), ApiError> {
+        let user_token = self.owner_token(owner).await?;
+
+        let playlist_tracks = self
+            .tokens
+            .config()
+            .api(&format!("/playlists/{}/tracks", urlencoding::encode(playlist_id)));
+
+        let response = send(
+            self.client
+                .put(&playlist_tracks)
+                .bearer_auth(user_token.access_token)
+                .json(&AddSongsToPlaylistBody {
+                    uris: uris.to_vec(),
                 }),
-            ))
-        }
-        Err(err) => Err((
-            Status::InternalServerError,
-            Json(ErrorResponse {
-                error: format!("Failed to call Spotify API: {}", err),
-            }),
-        )),
+        )
+        .await?;
+        check_status(response).await?;
+
+        Ok(())
     }
 }
@@ -1,20 +1,30 @@
+use crate::api::circles;
+use crate::api::compatibility::{self, PairListing, SeasonBallots};
 use crate::api::db;
-use crate::api::external_api::{
-    add_songs_to_playlist, authenticate, create_playlist, search_spotify_songs,
+use crate::api::drift;
+use crate::api::error::ApiError;
+use crate::api::music_provider::MusicProvider;
+use crate::api::playlist_upload::{decode_cover_image, playlist_details, publish_preview};
+use crate::api::previews;
+use crate::api::seasons;
+use crate::api::sessions::AuthenticatedUser;
+use crate::api::similarity::SimilarityMetric;
+use crate::api::types::{
+    MusicTasteIndividual, MusicTasteOverview, MusicTastePairs, MusicTastePairsQuery,
+    PairComparison, PlaylistPreview, PreviewPlaylistRequest, PublishPlaylistRequest,
+    PublishPlaylistResponse, SearchSongsQuery, SeasonRef, Song, TasteDriftReport,
 };
-use crate::api::types::{ErrorResponse, MusicTasteOverview, SearchSongsQuery, Song};
 use crate::DB_POOL;
-use reqwest::Client;
 use rocket::fs::NamedFile;
-use rocket::http::{CookieJar, Status};
 use rocket::response::Redirect;
 use rocket::serde::json::Json;
 use rocket::State;
+use std::collections::HashSet;
 use std::path::{Path, PathBuf};
 
 #[get("/")]
-pub async fn index(cookies: &CookieJar<'_>) -> Redirect {
-    if cookies.get_private("user").is_some() {
+pub async fn index(user: Option<AuthenticatedUser>) -> Redirect {
+    if user.is_some() {
         Redirect::to("/main")
     } else {
         Redirect::to("/login")
@@ -33,9 +43,7 @@ pub async fn signup_page() -> Option<NamedFile> {
 }
 
 #[get("/main")]
-pub async fn main_page(cookies: &CookieJar<'_>) -> Result<NamedFile, Redirect> {
-    authenticate(cookies).await;
-
+pub async fn main_page() -> Result<NamedFile, Redirect> {
     let mut file_path = PathBuf::from("static");
     file_path.push("index.html");
     NamedFile::open(file_path)
@@ -44,8 +52,8 @@ pub async fn main_page(cookies: &CookieJar<'_>) -> Result<NamedFile, Redirect> {
 }
 
 #[get("/connections")]
-pub async fn connections_page(cookies: &CookieJar<'_>) -> Result<NamedFile, Redirect> {
-    if cookies.get_private("user").is_none() {
+pub async fn connections_page(user: Option<AuthenticatedUser>) -> Result<NamedFile, Redirect> {
+    if user.is_none() {
         return Err(Redirect::to("/login"));
     }
     NamedFile::open(Path::new("static").join("connector.html"))
@@ -58,86 +66,37 @@ pub async fn files(file: PathBuf) -> Option<NamedFile> {
     NamedFile::open(Path::new("static").join(file)).await.ok()
 }
 
-#[post("/songs", format = "json", data = "<songs>")]
+#[post("/songs?<circle>&<season>", format = "json", data = "<songs>")]
 pub async fn save_songs(
-    cookies: &CookieJar<'_>,
+    user: AuthenticatedUser,
+    circle: Option<i32>,
+    season: Option<i32>,
     songs: Json<Vec<Song>>,
-) -> Result<(), (Status, Json<ErrorResponse>)> {
+) -> Result<(), ApiError> {
     let db_pool = DB_POOL.get().unwrap();
-    let user_name_opt = cookies
-        .get_private("user")
-        .map(|cookie| cookie.value().to_string());
-
-
-    if user_name_opt.is_none() {
-        return Err((
-            Status::NotFound,
-            Json(ErrorResponse {
-                error: "No username".to_string(),
-            }),
-        ));
-    }
 
+    let membership = circles::membership(&user, circle).await?;
+    let season = seasons::resolve(&membership, season).await?;
+    seasons::require_open(&season)?;
+    seasons::validate_ballot(&season, &songs)?;
 
-    let user_name = user_name_opt.unwrap();
-
-    rocket::info!("CookieVal: {}", user_name);
-
-    let user = db::get_or_insert_user(db_pool, &user_name)
-        .await
-        .map_err(|err| {
-            (
-                Status::NotAcceptable,
-                Json(ErrorResponse {
-                    error: format!("Database error: {}", err),
-                }),
-            )
-        })?;
-
-    db::insert_or_update_songs(db_pool, &user.id, &songs)
-        .await
-        .map_err(|err| {
-            (
-                Status::InternalServerError,
-                Json(ErrorResponse {
-                    error: format!("Failed to insert or update the list of songs: {}", err),
-                }),
-            )
-        })?;
+    db::insert_or_update_songs(db_pool, &season, &user.id, &songs).await?;
 
     // add songs
     Ok(())
 }
 
-#[get("/songs")]
+#[get("/songs?<circle>&<season>")]
 pub async fn get_songs(
-    cookies: &CookieJar<'_>,
-) -> Result<Json<Vec<Song>>, (Status, Json<ErrorResponse>)> {
+    user: AuthenticatedUser,
+    circle: Option<i32>,
+    season: Option<i32>,
+) -> Result<Json<Vec<Song>>, ApiError> {
     let db_pool = DB_POOL.get().unwrap();
-    let user_name_opt = cookies
-        .get_private("user")
-        .map(|cookie| cookie.value().to_string());
-
-    if user_name_opt.is_none() {
-        return Err((
-            Status::InternalServerError,
-            Json(ErrorResponse {
-                error: "No Username Token".to_string(),
-            }),
-        ));
-    }
 
-    let user_name = user_name_opt.unwrap();
-    let songs = db::get_songs_for_user_name(db_pool, &user_name)
-        .await
-        .map_err(|err| {
-            (
-                Default::default(),
-                Json(ErrorResponse {
-                    error: format!("Database error: {}", err),
-                }),
-            )
-        })?;
+    let membership = circles::membership(&user, circle).await?;
+    let season = seasons::resolve(&membership, season).await?;
+    let songs = db::get_songs_for_user_name(db_pool, &season.id, &user.name).await?;
 
     rocket::info!("Tracks {:#?}", songs);
 
@@ -146,110 +105,278 @@ pub async fn get_songs(
 
 #[get("/search-songs?<query..>")]
 pub async fn search_songs(
-    cookies: &CookieJar<'_>,
-    query: Option<SearchSongsQuery>,
-    client: &State<Client>,
-) -> Result<Json<Vec<Song>>, (Status, Json<ErrorResponse>)> {
-    search_spotify_songs(cookies, query, client).await
-}
-
-#[get("/generate_playlist")]
-pub async fn generate_playlist(
-    cookies: &CookieJar<'_>,
-    client: &State<Client>,
-) -> Result<(), (Status, Json<ErrorResponse>)> {
-    let db_pool = DB_POOL.get().unwrap();
+    query: SearchSongsQuery,
+    provider: &State<Box<dyn MusicProvider>>,
+) -> Result<Json<Vec<Song>>, ApiError> {
+    let (track_name, rank) = match (query.track, query.rank) {
+        (Some(track_name), Some(rank)) => (track_name, rank),
+        (track, _) => {
+            let missing = if track.is_none() { "track" } else { "rank" };
+            return Err(ApiError::invalid_field(
+                missing,
+                "Both track and rank are required",
+            ));
+        }
+    };
+
+    let tracks = provider.search_tracks(&track_name, 10).await?;
+
+    let mut seen_keys = HashSet::new();
+    let songs: Vec<Song> = tracks
+        .into_iter()
+        .filter_map(|track| {
+            let key = format!("{}{}", track.name, track.artist);
+
+            // Skip if the key is a duplicate
+            if !seen_keys.insert(key.clone()) {
+                return None;
+            }
+
+            Some(Song {
+                key: Some(key),
+                name: track.name,
+                artist: track.artist,
+                uri: track.uri,
+                album_cover_url: track.album_cover_url,
+                rank: Some(rank),
+            })
+        })
+        .collect();
+
+    rocket::info!("Tracks {:#?}", songs);
+
+    Ok(Json(songs))
+}
+
+#[get("/tracks/<id>")]
+pub async fn get_track(
+    id: &str,
+    provider: &State<Box<dyn MusicProvider>>,
+) -> Result<Json<Song>, ApiError> {
+    let track = provider
+        .get_track(id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("Track not found".to_string()))?;
+
+    Ok(Json(Song {
+        key: Some(format!("{}{}", track.name, track.artist)),
+        name: track.name,
+        artist: track.artist,
+        uri: track.uri,
+        album_cover_url: track.album_cover_url,
+        rank: None,
+    }))
+}
+
+#[post("/playlist/preview?<circle>&<season>", format = "json", data = "<request>")]
+pub async fn preview_playlist(
+    user: AuthenticatedUser,
+    circle: Option<i32>,
+    season: Option<i32>,
+    request: Json<PreviewPlaylistRequest>,
+) -> Result<Json<PlaylistPreview>, ApiError> {
+    let membership = circles::membership(&user, circle).await?;
+    let season = seasons::resolve(&membership, season).await?;
+    // Members only see the outcome once it is revealed; owners and admins can check on it any time.
+    if !membership.role.can_manage() {
+        seasons::require_revealed(&season)?;
+    }
+
+    let method = request.method.unwrap_or_default();
+    Ok(Json(previews::create_preview(&season, &user.id, method).await?))
+}
+
+#[get("/playlist/preview/<id>")]
+pub async fn get_playlist_preview(
+    user: AuthenticatedUser,
+    id: i32,
+) -> Result<Json<PlaylistPreview>, ApiError> {
+    Ok(Json(load_preview(&user, id).await?))
+}
 
-    let ranked_songs = db::get_song_rankings(db_pool).await.map_err(|err| {
-        (
-            Status::InternalServerError,
-            Json(ErrorResponse {
-                error: format!("Failed to get the top songs: {}", err),
-            }),
-        )
-    })?;
-
-    let playlist_id = create_playlist(cookies, client).await.map_err(|err| {
-        (
-            Status::InternalServerError,
-            Json(ErrorResponse {
-                error: format!("Failed to create Playlist via Spotify API: {:?}", err.1),
-            }),
-        )
-    })?;
-
-    // pass the playlist id into the external function with the songs to make the playlist
-    add_songs_to_playlist(playlist_id, ranked_songs, &cookies, &client).await
-}
-
-#[get("/music-taste")]
-pub async fn get_music_taste() -> Result<Json<Vec<MusicTasteOverview>>, (Status, Json<ErrorResponse>)> {
+#[post("/playlist/publish", format = "json", data = "<request>")]
+pub async fn publish_playlist(
+    user: AuthenticatedUser,
+    request: Json<PublishPlaylistRequest>,
+    provider: &State<Box<dyn MusicProvider>>,
+) -> Result<Json<PublishPlaylistResponse>, ApiError> {
+    let preview = load_preview(&user, request.preview_id).await?;
+
+    let details = playlist_details(&request, &preview)?;
+    let cover_image = request
+        .cover_image
+        .as_deref()
+        .map(decode_cover_image)
+        .transpose()?;
+
+    let summary = publish_preview(provider.as_ref(), &user, &preview, &details, cover_image.as_deref()).await?;
+
+    Ok(Json(PublishPlaylistResponse {
+        playlist_id: summary.playlist_id,
+        total_batches: summary.total_batches,
+        skipped_batches: summary.skipped_batches,
+    }))
+}
+
+/// Loads a preview the user created in a circle they are still in; other previews are reported as missing.
+async fn load_preview(user: &db::User, id: i32) -> Result<PlaylistPreview, ApiError> {
     let db_pool = DB_POOL.get().unwrap();
 
-    let overview = db::get_music_taste_overview(db_pool)
+    let not_found = || ApiError::NotFound("Playlist preview not found".to_string());
+
+    let row = db::get_playlist_preview(db_pool, &id)
+        .await?
+        .filter(|row| row.created_by == user.id)
+        .ok_or_else(not_found)?;
+    circles::membership(user, Some(row.circle_id))
         .await
-        .map_err(|err| {
-            (
-                Status::InternalServerError,
-                Json(ErrorResponse {
-                    error: format!("Failed to get music taste overview: {}", err),
-                }),
-            )
-        })?;
+        .map_err(|_| not_found())?;
+
+    previews::from_row(row)
+}
+
+/// How many of the season's most compatible pairs `/music-taste` shows.
+const OVERVIEW_PAIRS: usize = 5;
+/// How many connections `/music-taste-user` shows.
+const USER_CONNECTIONS: usize = 10;
+
+#[get("/music-taste?<circle>&<season>")]
+pub async fn get_music_taste(
+    user: AuthenticatedUser,
+    circle: Option<i32>,
+    season: Option<i32>,
+) -> Result<Json<Vec<MusicTasteOverview>>, ApiError> {
+    let membership = circles::membership(&user, circle).await?;
+    let season = seasons::resolve(&membership, season).await?;
+    let mut overview = SeasonBallots::load(&season).await?.overview();
+    overview.truncate(OVERVIEW_PAIRS);
 
     Ok(Json(overview))
 }
 
-#[get("/music-taste-user")]
+#[get("/music-taste-user?<circle>&<season>&<metric>")]
 pub async fn get_music_taste_user(
-    cookies: &CookieJar<'_>,
-) -> Result<Json<Vec<db::MusicTasteIndividual>>, (Status, Json<ErrorResponse>)> {
-    let db_pool = DB_POOL.get().unwrap();
+    user: AuthenticatedUser,
+    circle: Option<i32>,
+    season: Option<i32>,
+    metric: Option<SimilarityMetric>,
+) -> Result<Json<Vec<MusicTasteIndividual>>, ApiError> {
+    let membership = circles::membership(&user, circle).await?;
+    let season = seasons::resolve(&membership, season).await?;
+    let mut connections = SeasonBallots::load(&season)
+        .await?
+        .connections(user.id, metric.unwrap_or_default());
+    connections.truncate(USER_CONNECTIONS);
 
-    let user_name_opt = cookies
-        .get_private("user")
-        .map(|cookie| cookie.value().to_string());
+    Ok(Json(connections))
+}
 
-    if user_name_opt.is_none() {
-        return Err((
-            Status::Unauthorized,
-            Json(ErrorResponse {
-                error: "Not logged in".to_string(),
-            }),
+/// Every pair in the season, a page at a time, filtered and ordered as asked. Unlike `/music-taste` and
+/// `/music-taste-user` nothing is cut off.
+#[get("/music-taste-pairs?<query..>")]
+pub async fn get_music_taste_pairs(
+    user: AuthenticatedUser,
+    query: MusicTastePairsQuery,
+) -> Result<Json<MusicTastePairs>, ApiError> {
+    let page = query.page.unwrap_or(1);
+    if page == 0 {
+        return Err(ApiError::invalid_field("page", "Pages count from 1"));
+    }
+    let per_page = query.per_page.unwrap_or(compatibility::DEFAULT_PAGE_SIZE);
+    if !(1..=compatibility::MAX_PAGE_SIZE).contains(&per_page) {
+        return Err(ApiError::invalid_field(
+            "per_page",
+            format!("A page holds 1 to {} pairs", compatibility::MAX_PAGE_SIZE),
         ));
     }
 
-    let user_name = user_name_opt.unwrap();
-    let user = db::get_user(&db_pool, &user_name)
-        .await
-        .map_err(|err| {
-            (
-                Status::InternalServerError,
-                Json(ErrorResponse {
-                    error: format!("Database error: {}", err),
-                }),
-            )
-        })?;
+    let membership = circles::membership(&user, query.circle).await?;
+    let season = seasons::resolve(&membership, query.season).await?;
+    let username = query.user.as_deref().map(|name| name.trim().to_lowercase());
+    let listing = PairListing {
+        metric: query.metric.unwrap_or_default(),
+        sort: query.sort.unwrap_or_default(),
+        order: query.order.unwrap_or_default(),
+        min_score: query.min_score,
+        min_overlap: query.min_overlap,
+        user: username.as_deref(),
+    };
+
+    Ok(Json(SeasonBallots::load(&season).await?.pair_page(&listing, page, per_page)))
+}
 
-    if user.is_none() {
-        return Err((
-            Status::NotFound,
-            Json(ErrorResponse {
-                error: "User not found".to_string(),
-            }),
-        ));
+/// Head to head: any two members of the circle, compared on the season's ballots with every metric.
+#[get("/compare/<user_a>/<user_b>?<circle>&<season>")]
+pub async fn compare_users(
+    user: AuthenticatedUser,
+    user_a: &str,
+    user_b: &str,
+    circle: Option<i32>,
+    season: Option<i32>,
+) -> Result<Json<PairComparison>, ApiError> {
+    let membership = circles::membership(&user, circle).await?;
+    let season = seasons::resolve(&membership, season).await?;
+    let a = circles::find_member(&membership.circle_id, user_a).await?;
+    let b = circles::find_member(&membership.circle_id, user_b).await?;
+    if a.user_id == b.user_id {
+        return Err(ApiError::validation("Pick two different members to compare"));
     }
 
-    let connections = db::get_music_taste_user(&db_pool, &user.unwrap().id)
-        .await
-        .map_err(|err| {
-            (
-                Status::InternalServerError,
-                Json(ErrorResponse {
-                    error: format!("Failed to get connections: {}", err),
-                }),
-            )
-        })?;
+    Ok(Json(SeasonBallots::load(&season).await?.compare(&season, &a, &b)))
+}
 
-    Ok(Json(connections))
+/// How the user's taste moved between two of the circle's seasons: `to` defaults to the current season and
+/// `from` to the one before it.
+#[get("/music-taste-drift?<circle>&<from>&<to>")]
+pub async fn get_taste_drift(
+    user: AuthenticatedUser,
+    circle: Option<i32>,
+    from: Option<i32>,
+    to: Option<i32>,
+) -> Result<Json<TasteDriftReport>, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    let membership = circles::membership(&user, circle).await?;
+    let to = seasons::resolve(&membership, to).await?;
+    let from = match from {
+        Some(_) => seasons::resolve(&membership, from).await?,
+        None => db::get_circle_seasons(db_pool, &membership.circle_id)
+            .await?
+            .into_iter()
+            .find(|season| season.opens_at < to.opens_at)
+            .ok_or_else(|| {
+                ApiError::NotFound("There is no earlier season to compare with".to_string())
+            })?,
+    };
+    if from.id == to.id {
+        return Err(ApiError::invalid_field("from", "Pick two different seasons"));
+    }
+
+    let from_ballot = db::get_songs_for_user_name(db_pool, &from.id, &user.name).await?;
+    let to_ballot = db::get_songs_for_user_name(db_pool, &to.id, &user.name).await?;
+    let ballot_size = from.ballot_size.max(to.ballot_size);
+    let ballots = drift::compare_ballots(&from_ballot, &to_ballot, ballot_size);
+
+    let from_ballots = SeasonBallots::load(&from).await?;
+    let to_ballots = SeasonBallots::load(&to).await?;
+    let [from_scores, to_scores] = [&from_ballots, &to_ballots].map(|ballots| {
+        let scores = ballots.scores(user.id);
+        scores.into_iter().map(|(voter, score)| (voter, score.combined)).collect::<Vec<_>>()
+    });
+    let compatibility = drift::compatibility_changes(&from_scores, &to_scores);
+
+    Ok(Json(TasteDriftReport {
+        from_season: SeasonRef {
+            id: from.id,
+            name: from.name,
+        },
+        to_season: SeasonRef {
+            id: to.id,
+            name: to.name,
+        },
+        drift: ballots.drift,
+        persisted_artists: ballots.persisted_artists,
+        carried_over_songs: ballots.carried_over_songs,
+        compatibility,
+    }))
 }
@@ -0,0 +1,252 @@
+//! A stand-in for the Spotify accounts and Web APIs, mounted at `/mock-spotify` when the
+//! `mock-spotify` feature is on. Run with `ROCKET_PROFILE=mock` to point the app at it.
+
+use rocket::fairing::AdHoc;
+use rocket::data::{Data, ToByteUnit};
+use rocket::form::Form;
+use rocket::http::Status;
+use rocket::response::Redirect;
+use rocket::serde::json::{json, Json, Value};
+use rocket::serde::Deserialize;
+use rocket::tokio::sync::Mutex;
+use rocket::State;
+use std::collections::HashMap;
+
+// Spotify rejects bigger add-tracks calls, so the mock does too.
+const MAX_URIS_PER_REQUEST: usize = 100;
+
+// Largest base64 cover image Spotify accepts, in KiB.
+const MAX_IMAGE_SIZE: u64 = 256;
+
+const CATALOG: &[(&str, &str, &str)] = &[
+    ("4uLU6hMCjMI75M1A2tKUQC", "Never Gonna Give You Up", "Rick Astley"),
+    ("3n3Ppam7vgaVa1iaRUc9Lp", "Mr. Brightside", "The Killers"),
+    ("0VjIjW4GlUZAMYd2vXMi3b", "Blinding Lights", "The Weeknd"),
+    ("7qiZfU4dY1lWllzX7mPBI3", "Shape of You", "Ed Sheeran"),
+    ("2takcwOaAZWiXQijPHIx7B", "Time to Pretend", "MGMT"),
+    ("1mea3bSkSGXuIRvnydlB5b", "Viva La Vida", "Coldplay"),
+    ("0eGsygTp906u18L0Oimnem", "Mr. Blue Sky", "Electric Light Orchestra"),
+    ("5ghIJDpPoe3CfHMGu71E6T", "Smells Like Teen Spirit", "Nirvana"),
+    ("40riOy7x9W7GXjyGp4pjAv", "Hotel California", "Eagles"),
+    ("2Fxmhks0bxGSBdJ92vM42m", "bad guy", "Billie Eilish"),
+    ("6habFhsOp2NvshLv26DqMb", "Despacito", "Luis Fonsi"),
+    ("3AJwUDP919kvQ9QcozQPxg", "Yellow", "Coldplay"),
+];
+
+pub fn stage() -> AdHoc {
+    AdHoc::on_ignite("Mock Spotify", |rocket| async {
+        rocket.manage(MockSpotify::default()).mount(
+            "/mock-spotify",
+            routes![
+                token,
+                authorize,
+                me,
+                search,
+                track,
+                create_playlist,
+                add_tracks,
+                replace_tracks,
+                update_playlist,
+                playlist,
+                upload_playlist_image,
+                playlist_tracks
+            ],
+        )
+    })
+}
+
+#[derive(Default)]
+pub struct MockSpotify {
+    playlists: Mutex<HashMap<String, Vec<String>>>,
+    details: Mutex<HashMap<String, Value>>,
+}
+
+#[derive(FromForm)]
+pub struct TokenForm {
+    grant_type: String,
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct AddTracksBody {
+    uris: Vec<String>,
+}
+
+fn track_json(id: &str, name: &str, artist: &str) -> Value {
+    json!({
+        "id": id,
+        "uri": format!("spotify:track:{}", id),
+        "name": name,
+        "artists": [{ "name": artist }],
+        "album": {
+            "images": [
+                { "url": format!("https://picsum.photos/seed/{}/640", id) },
+                { "url": format!("https://picsum.photos/seed/{}/300", id) },
+                { "url": format!("https://picsum.photos/seed/{}/64", id) }
+            ]
+        }
+    })
+}
+
+#[post("/api/token", data = "<form>")]
+pub fn token(form: Form<TokenForm>) -> Json<Value> {
+    let mut body = json!({
+        "access_token": format!("mock-{}-token", form.grant_type),
+        "token_type": "Bearer",
+        "expires_in": 3600
+    });
+    if form.grant_type == "authorization_code" {
+        body["refresh_token"] = json!("mock-refresh-token");
+    }
+    Json(body)
+}
+
+#[get("/authorize?<redirect_uri>&<state>")]
+pub fn authorize(redirect_uri: &str, state: &str) -> Redirect {
+    // Skip the consent screen and approve straight away.
+    Redirect::to(format!(
+        "{}?code=mock-code&state={}",
+        redirect_uri,
+        urlencoding::encode(state)
+    ))
+}
+
+#[get("/v1/me")]
+pub fn me() -> Json<Value> {
+    Json(json!({ "id": "mock-user", "display_name": "Mock User" }))
+}
+
+#[get("/v1/search?<q>&<limit>")]
+pub fn search(q: &str, limit: Option<usize>) -> Json<Value> {
+    let needle = q.to_lowercase();
+    let items: Vec<Value> = CATALOG
+        .iter()
+        .filter(|(_, name, artist)| {
+            name.to_lowercase().contains(&needle) || artist.to_lowercase().contains(&needle)
+        })
+        .take(limit.unwrap_or(10))
+        .map(|(id, name, artist)| track_json(id, name, artist))
+        .collect();
+
+    Json(json!({ "tracks": { "items": items } }))
+}
+
+#[get("/v1/tracks/<id>")]
+pub fn track(id: &str) -> Result<Json<Value>, Status> {
+    CATALOG
+        .iter()
+        .find(|(track_id, _, _)| *track_id == id)
+        .map(|(id, name, artist)| Json(track_json(id, name, artist)))
+        .ok_or(Status::NotFound)
+}
+
+#[post("/v1/users/<_>/playlists", data = "<body>")]
+pub async fn create_playlist(body: Json<Value>, mock: &State<MockSpotify>) -> (Status, Json<Value>) {
+    let mut playlists = mock.playlists.lock().await;
+    let id = format!("mockplaylist{}", playlists.len() + 1);
+    playlists.insert(id.clone(), Vec::new());
+    mock.details.lock().await.insert(id.clone(), body.into_inner());
+    (Status::Created, Json(json!({ "id": id })))
+}
+
+#[post("/v1/playlists/<id>/tracks", data = "<body>")]
+pub async fn add_tracks(
+    id: &str,
+    body: Json<AddTracksBody>,
+    mock: &State<MockSpotify>,
+) -> (Status, Json<Value>) {
+    if body.uris.len() > MAX_URIS_PER_REQUEST {
+        return (
+            Status::BadRequest,
+            Json(json!({ "error": { "status": 400, "message": "Too many ids requested" } })),
+        );
+    }
+
+    let mut playlists = mock.playlists.lock().await;
+    match playlists.get_mut(id) {
+        Some(tracks) => {
+            tracks.extend(body.into_inner().uris);
+            (
+                Status::Created,
+                Json(json!({ "snapshot_id": format!("{}-{}", id, tracks.len()) })),
+            )
+        }
+        None => (
+            Status::NotFound,
+            Json(json!({ "error": { "status": 404, "message": "Resource not found" } })),
+        ),
+    }
+}
+
+#[put("/v1/playlists/<id>/tracks", data = "<body>")]
+pub async fn replace_tracks(
+    id: &str,
+    body: Json<AddTracksBody>,
+    mock: &State<MockSpotify>,
+) -> (Status, Json<Value>) {
+    if body.uris.len() > MAX_URIS_PER_REQUEST {
+        return (
+            Status::BadRequest,
+            Json(json!({ "error": { "status": 400, "message": "Too many ids requested" } })),
+        );
+    }
+
+    let mut playlists = mock.playlists.lock().await;
+    match playlists.get_mut(id) {
+        Some(tracks) => {
+            *tracks = body.into_inner().uris;
+            (
+                Status::Ok,
+                Json(json!({ "snapshot_id": format!("{}-{}", id, tracks.len()) })),
+            )
+        }
+        None => (
+            Status::NotFound,
+            Json(json!({ "error": { "status": 404, "message": "Resource not found" } })),
+        ),
+    }
+}
+
+#[put("/v1/playlists/<id>", data = "<body>")]
+pub async fn update_playlist(id: &str, body: Json<Value>, mock: &State<MockSpotify>) -> Status {
+    if mock.playlists.lock().await.contains_key(id) {
+        mock.details.lock().await.insert(id.to_string(), body.into_inner());
+        Status::Ok
+    } else {
+        Status::NotFound
+    }
+}
+
+#[get("/v1/playlists/<id>")]
+pub async fn playlist(id: &str, mock: &State<MockSpotify>) -> Option<Json<Value>> {
+    let mut details = mock.details.lock().await.get(id)?.clone();
+    details["id"] = json!(id);
+    Some(Json(details))
+}
+
+#[put("/v1/playlists/<id>/images", data = "<body>")]
+pub async fn upload_playlist_image(id: &str, body: Data<'_>, mock: &State<MockSpotify>) -> Status {
+    if !mock.playlists.lock().await.contains_key(id) {
+        return Status::NotFound;
+    }
+    let body = match body.open(MAX_IMAGE_SIZE.kibibytes()).into_string().await {
+        Ok(body) if body.is_complete() => body.into_inner(),
+        _ => return Status::PayloadTooLarge,
+    };
+    // Accept anything that is base64 like the real API expects; the image itself is discarded.
+    if body.is_empty() || !body.bytes().all(|b| b.is_ascii_alphanumeric() || b"+/=".contains(&b)) {
+        return Status::BadRequest;
+    }
+    Status::Accepted
+}
+
+#[get("/v1/playlists/<id>/tracks")]
+pub async fn playlist_tracks(id: &str, mock: &State<MockSpotify>) -> Option<Json<Value>> {
+    let playlists = mock.playlists.lock().await;
+    let items: Vec<Value> = playlists
+        .get(id)?
+        .iter()
+        .map(|uri| json!({ "track": { "uri": uri } }))
+        .collect();
+    Some(Json(json!({ "items": items, "total": items.len() })))
+}
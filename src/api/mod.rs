@@ -1,5 +1,22 @@
 pub mod internal_api;
+pub mod error;
+#[cfg(feature = "mock-spotify")]
+pub mod mock_spotify;
 pub mod external_api;
+pub mod music_provider;
+pub mod playlist_upload;
+pub mod spotify_token;
 mod types;
 pub mod db;
-pub mod auth_api;
\ No newline at end of file
+pub mod ranking;
+pub mod auth_api;
+pub mod credentials;
+pub mod sessions;
+pub mod circles;
+pub mod circle_api;
+pub mod seasons;
+pub mod previews;
+pub mod drift;
+pub mod compatibility;
+pub mod similarity;
+pub mod scoring;
\ No newline at end of file
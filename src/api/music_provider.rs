@@ -0,0 +1,55 @@
+use crate::api::db::User;
+use crate::api::error::ApiError;
+use crate::api::types::{CreatePlaylistBody, Track};
+
+/// A music catalog the app can search and publish playlists to.
+/// Routes only talk to this trait, so the Spotify backend can be swapped for another catalog or a fake.
+#[rocket::async_trait]
+pub trait MusicProvider: Send + Sync {
+    async fn search_tracks(&self, query: &str, limit: u32) -> Result<Vec<Track>, ApiError>;
+
+    async fn get_track(&self, id: &str) -> Result<Option<Track>, ApiError>;
+
+    /// Creates a playlist in `owner`'s account and returns its id.
+    async fn create_playlist(
+        &self,
+        owner: &User,
+        playlist: &CreatePlaylistBody,
+    ) -> Result<String, ApiError>;
+
+    /// Renames the playlist and rewrites its description and visibility.
+    /// Fails with `ApiError::NotFound` when the playlist no longer exists.
+    async fn update_playlist_details(
+        &self,
+        owner: &User,
+        playlist_id: &str,
+        playlist: &CreatePlaylistBody,
+    ) -> Result<(), ApiError>;
+
+    /// Sets the playlist's cover art to `jpeg`.
+    async fn upload_cover_image(
+        &self,
+        owner: &User,
+        playlist_id: &str,
+        jpeg: &[u8],
+    ) -> Result<(), ApiError>;
+
+    /// Most tracks `add_tracks` and `replace_tracks` accept in one call.
+    fn max_tracks_per_request(&self) -> usize;
+
+    /// Appends `uris` to the end of the playlist, in order.
+    async fn add_tracks(
+        &self,
+        owner: &User,
+        playlist_id: &str,
+        uris: &[String],
+    ) -> Result<(), ApiError>;
+
+    /// Replaces everything in the playlist with `uris`, in order.
+    async fn replace_tracks(
+        &self,
+        owner: &User,
+        playlist_id: &str,
+        uris: &[String],
+    ) -> Result<(), ApiError>;
+}
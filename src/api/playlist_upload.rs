@@ -0,0 +1,521 @@
+use crate::api::db::{self, User};
+use crate::api::error::ApiError;
+use crate::api::music_provider::MusicProvider;
+use crate::api::types::{
+    CreatePlaylistBody, PlaylistPreview, PlaylistVisibility, PublishPlaylistRequest,
+};
+use crate::DB_POOL;
+use base64::engine::general_purpose;
+use base64::Engine;
+use rocket::tokio::time::sleep;
+use sqlx::types::time::OffsetDateTime;
+use std::time::Duration;
+
+const MAX_ATTEMPTS: u32 = 3;
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(500);
+/// Longer `Retry-After` waits are left to the caller, rather than holding the request open.
+const MAX_RETRY_AFTER: u64 = 30;
+
+const DEFAULT_NAME: &str = "Hottest100";
+const DEFAULT_DESCRIPTION: &str = "Hottest100 - generated {date}";
+// Spotify's own limits on playlist details and cover images.
+const MAX_NAME_LENGTH: usize = 100;
+const MAX_DESCRIPTION_LENGTH: usize = 300;
+const MAX_COVER_IMAGE_SIZE: usize = 256 * 1024;
+
+pub struct UploadSummary {
+    pub playlist_id: String,
+    pub total_batches: usize,
+    pub skipped_batches: usize,
+}
+
+/// Builds the playlist's name, description and visibility from the publish request,
+/// filling the description template in from the preview.
+pub fn playlist_details(
+    request: &PublishPlaylistRequest,
+    preview: &PlaylistPreview,
+) -> Result<CreatePlaylistBody, ApiError> {
+    let name = request.name.as_deref().unwrap_or(DEFAULT_NAME).trim().to_string();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(ApiError::invalid_field(
+            "name",
+            format!("Playlist name must be 1 to {} characters", MAX_NAME_LENGTH),
+        ));
+    }
+
+    let now = OffsetDateTime::now_utc();
+    let date = format!(
+        "{}-{:02}-{:02} {:02}:{:02} UTC",
+        now.year(),
+        now.month() as u8,
+        now.day(),
+        now.hour(),
+        now.minute()
+    );
+    let method = serde_json::to_value(preview.method)
+        .ok()
+        .and_then(|value| value.as_str().map(str::to_string))
+        .unwrap_or_default();
+
+    // Spotify drops line breaks from descriptions, so flatten them here where the length is checked.
+    let description = request
+        .description
+        .as_deref()
+        .unwrap_or(DEFAULT_DESCRIPTION)
+        .replace("{date}", &date)
+        .replace("{method}", &method)
+        .replace("{voters}", &preview.voters.to_string())
+        .replace("{songs}", &preview.songs.len().to_string())
+        .replace(['\r', '\n'], " ");
+    if description.chars().count() > MAX_DESCRIPTION_LENGTH {
+        return Err(ApiError::invalid_field(
+            "description",
+            format!(
+                "Playlist description must be at most {} characters once filled in",
+                MAX_DESCRIPTION_LENGTH
+            ),
+        ));
+    }
+
+    let visibility = request.visibility.unwrap_or_default();
+    Ok(CreatePlaylistBody {
+        name,
+        description,
+        public: visibility == PlaylistVisibility::Public,
+        collaborative: visibility == PlaylistVisibility::Collaborative,
+    })
+}
+
+/// Decodes and sanity-checks a base64 JPEG cover image.
+pub fn decode_cover_image(encoded: &str) -> Result<Vec<u8>, ApiError> {
+    if encoded.len() > MAX_COVER_IMAGE_SIZE {
+        return Err(ApiError::invalid_field(
+            "cover_image",
+            format!(
+                "Cover image must be at most {} KB once base64-encoded",
+                MAX_COVER_IMAGE_SIZE / 1024
+            ),
+        ));
+    }
+
+    let jpeg = general_purpose::STANDARD
+        .decode(encoded)
+        .map_err(|_| ApiError::invalid_field("cover_image", "Cover image is not valid base64"))?;
+    if !jpeg.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        return Err(ApiError::invalid_field("cover_image", "Cover image must be a JPEG"));
+    }
+
+    Ok(jpeg)
+}
+
+/// Publishes a preview's tracks into the owner's playlist.
+///
+/// A preview that was already (partly) published resumes into the same playlist. Otherwise the owner's
+/// playlist from the season's last generation is reused and its details refreshed, or a new one is created if
+/// there is none or it has since been deleted. Details and cover are only applied when a preview is
+/// first published; resuming just finishes the tracks. Publishes of the same preview run one at a time, so
+/// a second one waits for the first and then resumes rather than creating another playlist.
+pub async fn publish_preview(
+    provider: &dyn MusicProvider,
+    owner: &User,
+    preview: &PlaylistPreview,
+    details: &CreatePlaylistBody,
+    cover_image: Option<&[u8]>,
+) -> Result<UploadSummary, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    let lock = db::lock_preview(db_pool, &preview.id).await?;
+    let existing = db::get_playlist_publication(db_pool, &preview.id)
+        .await?;
+
+    let publication = match existing {
+        Some(publication) => publication,
+        None => {
+            let playlist_id = target_playlist(provider, owner, preview, details).await?;
+
+            db::upsert_generated_playlist(
+                db_pool,
+                &owner.id,
+                &preview.circle_id,
+                &preview.season_id,
+                &playlist_id,
+            )
+            .await?;
+
+            // Before recording the publication, so a failed upload is retried by publishing again.
+            if let Some(jpeg) = cover_image {
+                provider.upload_cover_image(owner, &playlist_id, jpeg).await?;
+            }
+
+            db::insert_playlist_publication(db_pool, &preview.id, &playlist_id)
+                .await?
+        }
+    };
+
+    let uris: Vec<String> = preview.songs.iter().map(|song| song.uri.clone()).collect();
+    let summary = upload_tracks(provider, owner, &publication.playlist_id, &publication, &uris).await?;
+    lock.commit().await?;
+    Ok(summary)
+}
+
+async fn target_playlist(
+    provider: &dyn MusicProvider,
+    owner: &User,
+    preview: &PlaylistPreview,
+    playlist: &CreatePlaylistBody,
+) -> Result<String, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    let previous = db::get_generated_playlist(db_pool, &owner.id, &preview.circle_id, &preview.season_id)
+        .await?;
+
+    if let Some(playlist_id) = previous {
+        match provider
+            .update_playlist_details(owner, &playlist_id, playlist)
+            .await
+        {
+            Ok(()) => return Ok(playlist_id),
+            // Deleted on the provider's side since the last generation; start a fresh one.
+            Err(ApiError::NotFound(_)) => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    provider.create_playlist(owner, playlist).await
+}
+
+/// Which batches of a publication have landed, so publishing it again resumes instead of starting over.
+#[rocket::async_trait]
+trait UploadLog: Send + Sync {
+    async fn uploaded_batches(&self) -> Result<Vec<i32>, ApiError>;
+
+    async fn record_batch(&self, batch_index: i32, track_count: i32) -> Result<(), ApiError>;
+}
+
+#[rocket::async_trait]
+impl UploadLog for db::PlaylistPublication {
+    async fn uploaded_batches(&self) -> Result<Vec<i32>, ApiError> {
+        let db_pool = DB_POOL.get().unwrap();
+
+        Ok(db::get_uploaded_batches(db_pool, &self.id).await?)
+    }
+
+    async fn record_batch(&self, batch_index: i32, track_count: i32) -> Result<(), ApiError> {
+        let db_pool = DB_POOL.get().unwrap();
+
+        Ok(db::record_uploaded_batch(db_pool, &self.id, batch_index, track_count).await?)
+    }
+}
+
+/// How long to wait before trying a failed batch again, or `None` when trying again cannot help.
+fn retry_delay(err: &ApiError, attempt: u32) -> Option<Duration> {
+    match err {
+        ApiError::RateLimited { retry_after: Some(seconds) } => {
+            (*seconds <= MAX_RETRY_AFTER).then(|| Duration::from_secs(*seconds))
+        }
+        err if err.is_transient() => Some(RETRY_BASE_DELAY * 2u32.pow(attempt - 1)),
+        _ => None,
+    }
+}
+
+/// Writes `uris` into the playlist in provider-sized batches, in order.
+///
+/// The first batch replaces whatever the playlist held, the rest are appended. A batch that fails with a
+/// transient error is retried, after the provider's `Retry-After` if it gave one and with backoff
+/// otherwise, and each batch is recorded in `log` once it lands. Uploading stops at the first batch that
+/// keeps failing, so the recorded batches are always a prefix and a rerun picks up from there.
+async fn upload_tracks(
+    provider: &dyn MusicProvider,
+    owner: &User,
+    playlist_id: &str,
+    log: &dyn UploadLog,
+    uris: &[String],
+) -> Result<UploadSummary, ApiError> {
+    let uploaded = log.uploaded_batches().await?;
+
+    let mut batches: Vec<&[String]> = uris.chunks(provider.max_tracks_per_request()).collect();
+    if batches.is_empty() {
+        // Still replace once so an empty ranking empties the playlist.
+        batches.push(&[]);
+    }
+    let mut skipped_batches = 0;
+
+    for (index, batch) in batches.iter().enumerate() {
+        let batch_index = index as i32;
+        if uploaded.contains(&batch_index) {
+            skipped_batches += 1;
+            continue;
+        }
+
+        let mut attempt = 1;
+        loop {
+            let result = if index == 0 {
+                provider.replace_tracks(owner, playlist_id, batch).await
+            } else {
+                provider.add_tracks(owner, playlist_id, batch).await
+            };
+
+            let err = match result {
+                Ok(()) => break,
+                Err(err) => err,
+            };
+            match retry_delay(&err, attempt).filter(|_| attempt < MAX_ATTEMPTS) {
+                Some(delay) => {
+                    rocket::warn!(
+                        "Batch {} of playlist {} failed (attempt {}): {:?}",
+                        batch_index,
+                        playlist_id,
+                        attempt,
+                        err
+                    );
+                    sleep(delay).await;
+                    attempt += 1;
+                }
+                None => {
+                    // Publishing the same preview again resumes from this batch.
+                    rocket::warn!(
+                        "Uploaded {} of {} batches of playlist {} before giving up",
+                        index,
+                        batches.len(),
+                        playlist_id
+                    );
+                    return Err(err);
+                }
+            }
+        }
+
+        log.record_batch(batch_index, batch.len() as i32).await?;
+    }
+
+    Ok(UploadSummary {
+        playlist_id: playlist_id.to_string(),
+        total_batches: batches.len(),
+        skipped_batches,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::types::Track;
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::time::Instant;
+
+    /// Takes tracks in batches of 100 and fails the track calls listed in `failures`, counted from 1.
+    #[derive(Default)]
+    struct FakeProvider {
+        failures: Mutex<HashMap<usize, ApiError>>,
+        /// Every track call, failed or not: whether it replaced the playlist, and its uris.
+        calls: Mutex<Vec<(bool, Vec<String>)>>,
+    }
+
+    impl FakeProvider {
+        fn failing(failures: Vec<(usize, ApiError)>) -> Self {
+            FakeProvider {
+                failures: Mutex::new(failures.into_iter().collect()),
+                ..FakeProvider::default()
+            }
+        }
+
+        fn track_call(&self, replace: bool, uris: &[String]) -> Result<(), ApiError> {
+            let mut calls = self.calls.lock().unwrap();
+            calls.push((replace, uris.to_vec()));
+            match self.failures.lock().unwrap().remove(&calls.len()) {
+                Some(err) => Err(err),
+                None => Ok(()),
+            }
+        }
+
+        /// The size of each call, negative for replacing ones.
+        fn call_sizes(&self) -> Vec<i64> {
+            let calls = self.calls.lock().unwrap();
+            calls
+                .iter()
+                .map(|(replace, uris)| if *replace { -(uris.len() as i64) } else { uris.len() as i64 })
+                .collect()
+        }
+    }
+
+    /// What the fake answers to calls the track upload must not make.
+    fn not_for_uploads(call: &str) -> ApiError {
+        ApiError::Internal(format!("Uploading tracks should not call {}", call))
+    }
+
+    #[rocket::async_trait]
+    impl MusicProvider for FakeProvider {
+        async fn search_tracks(&self, _query: &str, _limit: u32) -> Result<Vec<Track>, ApiError> {
+            Err(not_for_uploads("search_tracks"))
+        }
+
+        async fn get_track(&self, _id: &str) -> Result<Option<Track>, ApiError> {
+            Err(not_for_uploads("get_track"))
+        }
+
+        async fn create_playlist(
+            &self,
+            _owner: &User,
+            _playlist: &CreatePlaylistBody,
+        ) -> Result<String, ApiError> {
+            Err(not_for_uploads("create_playlist"))
+        }
+
+        async fn update_playlist_details(
+            &self,
+            _owner: &User,
+            _playlist_id: &str,
+            _playlist: &CreatePlaylistBody,
+        ) -> Result<(), ApiError> {
+            Err(not_for_uploads("update_playlist_details"))
+        }
+
+        async fn upload_cover_image(
+            &self,
+            _owner: &User,
+            _playlist_id: &str,
+            _jpeg: &[u8],
+        ) -> Result<(), ApiError> {
+            Err(not_for_uploads("upload_cover_image"))
+        }
+
+        fn max_tracks_per_request(&self) -> usize {
+            100
+        }
+
+        async fn add_tracks(
+            &self,
+            _owner: &User,
+            _playlist_id: &str,
+            uris: &[String],
+        ) -> Result<(), ApiError> {
+            self.track_call(false, uris)
+        }
+
+        async fn replace_tracks(
+            &self,
+            _owner: &User,
+            _playlist_id: &str,
+            uris: &[String],
+        ) -> Result<(), ApiError> {
+            self.track_call(true, uris)
+        }
+    }
+
+    /// Recorded batches as `(batch_index, track_count)`.
+    #[derive(Default)]
+    struct FakeLog(Mutex<Vec<(i32, i32)>>);
+
+    impl FakeLog {
+        fn batches(&self) -> Vec<(i32, i32)> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    #[rocket::async_trait]
+    impl UploadLog for FakeLog {
+        async fn uploaded_batches(&self) -> Result<Vec<i32>, ApiError> {
+            Ok(self.batches().into_iter().map(|(index, _)| index).collect())
+        }
+
+        async fn record_batch(&self, batch_index: i32, track_count: i32) -> Result<(), ApiError> {
+            self.0.lock().unwrap().push((batch_index, track_count));
+            Ok(())
+        }
+    }
+
+    fn owner() -> User {
+        User {
+            id: 1,
+            name: "alice".to_string(),
+        }
+    }
+
+    fn uris(count: usize) -> Vec<String> {
+        (0..count).map(|i| format!("spotify:track:{}", i)).collect()
+    }
+
+    #[rocket::async_test]
+    async fn uploads_in_batches_replacing_then_appending() {
+        let (provider, log) = (FakeProvider::default(), FakeLog::default());
+        let summary = upload_tracks(&provider, &owner(), "playlist", &log, &uris(250)).await.unwrap();
+
+        assert_eq!(provider.call_sizes(), vec![-100, 100, 50]);
+        let calls = provider.calls.lock().unwrap();
+        let sent: Vec<String> = calls.iter().flat_map(|(_, uris)| uris.clone()).collect();
+        assert_eq!(sent, uris(250));
+        assert_eq!(log.batches(), vec![(0, 100), (1, 100), (2, 50)]);
+        assert_eq!((summary.total_batches, summary.skipped_batches), (3, 0));
+    }
+
+    #[rocket::async_test]
+    async fn an_empty_ranking_still_empties_the_playlist() {
+        let (provider, log) = (FakeProvider::default(), FakeLog::default());
+        let summary = upload_tracks(&provider, &owner(), "playlist", &log, &[]).await.unwrap();
+
+        assert_eq!(provider.call_sizes(), vec![0]);
+        assert_eq!(log.batches(), vec![(0, 0)]);
+        assert_eq!(summary.total_batches, 1);
+    }
+
+    #[rocket::async_test]
+    async fn a_failed_batch_stops_the_upload_and_a_rerun_resumes_from_it() {
+        let log = FakeLog::default();
+        let failing = FakeProvider::failing(vec![(2, ApiError::Upstream("bad request".to_string()))]);
+        let result = upload_tracks(&failing, &owner(), "playlist", &log, &uris(250)).await;
+
+        // Not transient, so the second batch is not retried and the third never starts.
+        assert!(matches!(result, Err(ApiError::Upstream(_))));
+        assert_eq!(failing.call_sizes(), vec![-100, 100]);
+        assert_eq!(log.batches(), vec![(0, 100)]);
+
+        let provider = FakeProvider::default();
+        let summary = upload_tracks(&provider, &owner(), "playlist", &log, &uris(250)).await.unwrap();
+
+        assert_eq!(provider.call_sizes(), vec![100, 50]);
+        assert_eq!(provider.calls.lock().unwrap()[0].1, uris(250)[100..200].to_vec());
+        assert_eq!(log.batches(), vec![(0, 100), (1, 100), (2, 50)]);
+        assert_eq!((summary.total_batches, summary.skipped_batches), (3, 1));
+    }
+
+    #[rocket::async_test]
+    async fn transient_failures_are_retried_after_retry_after() {
+        let (provider, log) = (
+            FakeProvider::failing(vec![(2, ApiError::RateLimited { retry_after: Some(1) })]),
+            FakeLog::default(),
+        );
+        let started = Instant::now();
+        upload_tracks(&provider, &owner(), "playlist", &log, &uris(150)).await.unwrap();
+
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert_eq!(provider.call_sizes(), vec![-100, 50, 50]);
+        assert_eq!(log.batches(), vec![(0, 100), (1, 50)]);
+    }
+
+    #[rocket::async_test]
+    async fn a_batch_that_keeps_failing_gives_up_after_the_last_attempt() {
+        let rate_limited = || ApiError::RateLimited { retry_after: Some(0) };
+        let provider =
+            FakeProvider::failing(vec![(1, rate_limited()), (2, rate_limited()), (3, rate_limited())]);
+        let log = FakeLog::default();
+        let result = upload_tracks(&provider, &owner(), "playlist", &log, &uris(10)).await;
+
+        assert!(matches!(result, Err(ApiError::RateLimited { .. })));
+        assert_eq!(provider.call_sizes(), vec![-10; MAX_ATTEMPTS as usize]);
+        assert!(log.batches().is_empty());
+    }
+
+    #[test]
+    fn only_transient_errors_are_retried() {
+        assert_eq!(retry_delay(&ApiError::Unavailable(String::new()), 1), Some(RETRY_BASE_DELAY));
+        assert_eq!(retry_delay(&ApiError::Unavailable(String::new()), 2), Some(RETRY_BASE_DELAY * 2));
+        assert_eq!(retry_delay(&ApiError::RateLimited { retry_after: None }, 1), Some(RETRY_BASE_DELAY));
+        assert_eq!(
+            retry_delay(&ApiError::RateLimited { retry_after: Some(3) }, 1),
+            Some(Duration::from_secs(3))
+        );
+        assert_eq!(retry_delay(&ApiError::RateLimited { retry_after: Some(MAX_RETRY_AFTER + 1) }, 1), None);
+        assert_eq!(retry_delay(&ApiError::Upstream(String::new()), 1), None);
+        assert_eq!(retry_delay(&ApiError::NotFound(String::new()), 1), None);
+        assert_eq!(retry_delay(&ApiError::Forbidden(String::new()), 1), None);
+    }
+}
@@ -0,0 +1,167 @@
+//! Playlist previews: a season's ballots ranked into a countdown and frozen, so what gets published is
+//! exactly what was reviewed.
+
+use crate::api::db::{self, PlaylistPreviewRow, Season};
+use crate::api::error::ApiError;
+use crate::api::ranking::{self, BallotEntry, RankingMethod};
+use crate::api::scoring::{self, ScoringWeights};
+use crate::api::types::{PlaylistPreview, RankedSong};
+use crate::DB_POOL;
+use std::collections::{HashMap, HashSet};
+
+/// Ranks the season's ballots with `method` and saves the result as a preview owned by `created_by`.
+pub async fn create_preview(
+    season: &Season,
+    created_by: &i32,
+    method: RankingMethod,
+) -> Result<PlaylistPreview, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    let (songs, voters) = countdown(season, method).await?;
+    let (method_name, songs_json) = serialise(method, &songs)?;
+    let id = db::insert_playlist_preview(
+        db_pool,
+        season,
+        created_by,
+        &method_name,
+        &songs_json,
+        &voters,
+    )
+    .await?;
+
+    Ok(PlaylistPreview {
+        id,
+        circle_id: season.circle_id,
+        season_id: season.id,
+        method,
+        voters,
+        songs,
+    })
+}
+
+/// Ranks the season's ballots the default way and saves the result as its generated playlist, owned by
+/// `created_by`. Returns `None` if the season's playlist was generated in the meantime.
+pub async fn create_generated_preview(
+    season: &Season,
+    created_by: &i32,
+) -> Result<Option<PlaylistPreview>, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    let method = RankingMethod::default();
+    let (songs, voters) = countdown(season, method).await?;
+    let (method_name, songs_json) = serialise(method, &songs)?;
+    let id = db::insert_generated_preview(
+        db_pool,
+        season,
+        created_by,
+        &method_name,
+        &songs_json,
+        &voters,
+    )
+    .await?;
+
+    Ok(id.map(|id| PlaylistPreview {
+        id,
+        circle_id: season.circle_id,
+        season_id: season.id,
+        method,
+        voters,
+        songs,
+    }))
+}
+
+/// The season's ranked songs in playlist order, along with how many ballots there were. The playlist plays
+/// as a countdown, so the top song goes last.
+async fn countdown(season: &Season, method: RankingMethod) -> Result<(Vec<RankedSong>, i32), ApiError> {
+    let weights = scoring::for_season(season).await?;
+    let (mut songs, voters) = rank_ballots(season, method, &weights).await?;
+    songs.reverse();
+    Ok((songs, voters))
+}
+
+/// The method's stored name and the songs as saved JSON.
+fn serialise(method: RankingMethod, songs: &[RankedSong]) -> Result<(String, serde_json::Value), ApiError> {
+    let songs_json = serde_json::to_value(songs)
+        .map_err(|err| ApiError::Internal(format!("Failed to serialise the preview: {}", err)))?;
+    let method_name = serde_json::to_value(method)
+        .ok()
+        .and_then(|value| value.as_str().map(str::to_string))
+        .unwrap_or_default();
+    Ok((method_name, songs_json))
+}
+
+pub fn from_row(row: PlaylistPreviewRow) -> Result<PlaylistPreview, ApiError> {
+    let method = serde_json::from_value(serde_json::Value::String(row.method));
+    let songs = serde_json::from_value(row.songs);
+    match (method, songs) {
+        (Ok(method), Ok(songs)) => Ok(PlaylistPreview {
+            id: row.id,
+            circle_id: row.circle_id,
+            season_id: row.season_id,
+            method,
+            voters: row.voter_count,
+            songs,
+        }),
+        _ => Err(ApiError::Internal(format!("Saved preview {} could not be read", row.id))),
+    }
+}
+
+/// Runs the ranking engine over every ballot of the season, top song first, along with how many ballots
+/// there were.
+pub async fn rank_ballots(
+    season: &Season,
+    method: RankingMethod,
+    weights: &ScoringWeights,
+) -> Result<(Vec<RankedSong>, i32), ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    let rows = db::get_ballots(db_pool, &season.id).await?;
+    rank_rows(&rows, season.ballot_size, method, weights).await
+}
+
+/// Like `rank_ballots`, over ballots that were already loaded.
+pub async fn rank_rows(
+    rows: &[db::BallotRow],
+    ballot_size: i32,
+    method: RankingMethod,
+    weights: &ScoringWeights,
+) -> Result<(Vec<RankedSong>, i32), ApiError> {
+    let entries: Vec<BallotEntry> = rows
+        .iter()
+        .map(|row| BallotEntry {
+            user_id: row.user_id,
+            song_id: row.song_id,
+            rank: row.rank,
+        })
+        .collect();
+    let songs: HashMap<i32, &db::BallotRow> = rows.iter().map(|row| (row.song_id, row)).collect();
+    let voters = rows.iter().map(|row| row.user_id).collect::<HashSet<_>>().len() as i32;
+
+    // Schulze is cubic in the number of songs, so keep the ranking off the async workers.
+    let weights = *weights;
+    let tallies = rocket::tokio::task::spawn_blocking(move || {
+        ranking::rank_songs(method, ballot_size, &weights, &entries)
+    })
+    .await
+    .map_err(|err| ApiError::Internal(format!("Failed to rank the songs: {}", err)))?;
+
+    let ranked = tallies
+        .into_iter()
+        .enumerate()
+        .map(|(index, tally)| {
+            let song = songs[&tally.song_id];
+            RankedSong {
+                position: index + 1,
+                name: song.name.clone(),
+                artist: song.artist.clone(),
+                uri: song.uri.clone(),
+                album_cover_url: song.album_cover_url.clone(),
+                score: tally.score,
+                voters: tally.voters,
+                average_rank: tally.average_rank,
+            }
+        })
+        .collect();
+
+    Ok((ranked, voters))
+}
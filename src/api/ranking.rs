@@ -0,0 +1,393 @@
+//! Turns everyone's ballots into one ordered list of songs.
+//!
+//! Every method produces a primary score per song; ties on that score are always broken the same way:
+//! more voters, then better (lower) average rank, then better best rank, then lower song id
+//! (the song saved first). That makes the order fully deterministic for a given set of ballots.
+
+use crate::api::scoring::ScoringWeights;
+use rocket::serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+#[derive(FromFormField, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum RankingMethod {
+    /// Voter count plus a small bonus for high average rank (the season's `rank_bonus`), the original
+    /// Hottest 100 formula.
+    #[default]
+    Classic,
+    /// Rank 1 earns the ballot size in points, the last rank earns 1.
+    Borda,
+    /// One point per ballot the song appears on.
+    #[field(value = "vote_count")]
+    VoteCount,
+    /// Condorcet method: ordered by how many other songs each song beats on its strongest path.
+    Schulze,
+    /// Repeatedly drops the song with the fewest first preferences; the order songs survive in is the ranking.
+    #[field(value = "instant_runoff")]
+    InstantRunoff,
+}
+
+/// One user's pick for one song.
+pub struct BallotEntry {
+    pub user_id: i32,
+    pub song_id: i32,
+    pub rank: i32,
+}
+
+/// How a song fared under the chosen method.
+#[derive(Serialize, Clone, Debug)]
+#[serde(crate = "rocket::serde")]
+pub struct SongTally {
+    pub song_id: i32,
+    pub score: f64,
+    pub voters: i64,
+    pub average_rank: f64,
+    pub best_rank: i32,
+}
+
+/// Orders every song that appears on at least one ballot, best first. Ranks run from 1 (favourite) to
+/// `ballot_size`.
+pub fn rank_songs(
+    method: RankingMethod,
+    ballot_size: i32,
+    weights: &ScoringWeights,
+    entries: &[BallotEntry],
+) -> Vec<SongTally> {
+    let mut tallies = base_tallies(entries);
+
+    match method {
+        RankingMethod::Classic => {
+            // The largest rank bonus is the same whatever the ballot size: 1.5 on the default weights.
+            let bonus_per_place = weights.rank_bonus_per_place(ballot_size);
+            for tally in tallies.iter_mut() {
+                tally.score = tally.voters as f64
+                    + bonus_per_place * ((ballot_size + 1) as f64 - tally.average_rank);
+            }
+        }
+        RankingMethod::Borda => {
+            let mut points: HashMap<i32, f64> = HashMap::new();
+            for entry in entries {
+                *points.entry(entry.song_id).or_default() += (ballot_size + 1 - entry.rank) as f64;
+            }
+            for tally in tallies.iter_mut() {
+                tally.score = points[&tally.song_id];
+            }
+        }
+        RankingMethod::VoteCount => {
+            for tally in tallies.iter_mut() {
+                tally.score = tally.voters as f64;
+            }
+        }
+        RankingMethod::Schulze => {
+            let wins = schulze_wins(tallies.len(), &ballots(&tallies, entries));
+            for (tally, wins) in tallies.iter_mut().zip(wins) {
+                tally.score = wins as f64;
+            }
+        }
+        RankingMethod::InstantRunoff => {
+            // The IRV order is the ranking itself, so the score is just the survival position.
+            tallies.sort_by(tie_break);
+            let order = instant_runoff_order(tallies.len(), &ballots(&tallies, entries));
+            let song_count = tallies.len();
+            let mut slots: Vec<Option<SongTally>> = tallies.into_iter().map(Some).collect();
+            return order
+                .into_iter()
+                .enumerate()
+                .map(|(position, index)| {
+                    let mut tally = slots[index].take().unwrap();
+                    tally.score = (song_count - position) as f64;
+                    tally
+                })
+                .collect();
+        }
+    }
+
+    tallies.sort_by(|a, b| {
+        b.score
+            .partial_cmp(&a.score)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| tie_break(a, b))
+    });
+    tallies
+}
+
+fn base_tallies(entries: &[BallotEntry]) -> Vec<SongTally> {
+    let mut by_song: HashMap<i32, (i64, i64, i32)> = HashMap::new();
+    for entry in entries {
+        let (voters, rank_sum, best_rank) =
+            by_song.entry(entry.song_id).or_insert((0, 0, i32::MAX));
+        *voters += 1;
+        *rank_sum += entry.rank as i64;
+        *best_rank = (*best_rank).min(entry.rank);
+    }
+
+    by_song
+        .into_iter()
+        .map(|(song_id, (voters, rank_sum, best_rank))| SongTally {
+            song_id,
+            score: 0.0,
+            voters,
+            average_rank: rank_sum as f64 / voters as f64,
+            best_rank,
+        })
+        .collect()
+}
+
+/// The shared tie-break: `Less` means `a` places ahead of `b`.
+fn tie_break(a: &SongTally, b: &SongTally) -> Ordering {
+    b.voters
+        .cmp(&a.voters)
+        .then_with(|| {
+            a.average_rank
+                .partial_cmp(&b.average_rank)
+                .unwrap_or(Ordering::Equal)
+        })
+        .then_with(|| a.best_rank.cmp(&b.best_rank))
+        .then_with(|| a.song_id.cmp(&b.song_id))
+}
+
+/// A fixed-size set of indices, one bit each.
+struct BitSet {
+    words: Vec<u64>,
+}
+
+impl BitSet {
+    fn new(len: usize) -> Self {
+        BitSet {
+            words: vec![0; len.div_ceil(64)],
+        }
+    }
+
+    fn full(len: usize) -> Self {
+        let mut set = BitSet::new(len);
+        for index in 0..len {
+            set.insert(index);
+        }
+        set
+    }
+
+    fn insert(&mut self, index: usize) {
+        self.words[index / 64] |= 1 << (index % 64);
+    }
+
+    fn remove(&mut self, index: usize) {
+        self.words[index / 64] &= !(1 << (index % 64));
+    }
+
+    fn contains(&self, index: usize) -> bool {
+        self.words[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn clear(&mut self) {
+        self.words.fill(0);
+    }
+}
+
+/// Each user's picks as indices into `tallies`, favourite first.
+fn ballots(tallies: &[SongTally], entries: &[BallotEntry]) -> Vec<Vec<usize>> {
+    let index: HashMap<i32, usize> = tallies
+        .iter()
+        .enumerate()
+        .map(|(i, tally)| (tally.song_id, i))
+        .collect();
+    let mut by_user: HashMap<i32, Vec<(i32, usize)>> = HashMap::new();
+    for entry in entries {
+        by_user
+            .entry(entry.user_id)
+            .or_default()
+            .push((entry.rank, index[&entry.song_id]));
+    }
+
+    let mut users: Vec<_> = by_user.into_iter().collect();
+    users.sort_by_key(|(user_id, _)| *user_id);
+    users
+        .into_iter()
+        .map(|(_, mut picks)| {
+            picks.sort();
+            picks.into_iter().map(|(_, song)| song).collect()
+        })
+        .collect()
+}
+
+/// For each of the `n` songs, how many other songs it beats once strongest paths are computed.
+/// A song on a ballot is preferred over every song left off it.
+fn schulze_wins(n: usize, ballots: &[Vec<usize>]) -> Vec<usize> {
+    // Row-major n x n matrices: `preferred[i * n + j]` ballots prefer i over j.
+    let mut preferred = vec![0u32; n * n];
+    let mut on_ballot = BitSet::new(n);
+    for ballot in ballots {
+        on_ballot.clear();
+        for &song in ballot {
+            on_ballot.insert(song);
+        }
+        for (i, &winner) in ballot.iter().enumerate() {
+            let row = &mut preferred[winner * n..(winner + 1) * n];
+            for &loser in &ballot[i + 1..] {
+                row[loser] += 1;
+            }
+            for (loser, count) in row.iter_mut().enumerate() {
+                if !on_ballot.contains(loser) {
+                    *count += 1;
+                }
+            }
+        }
+    }
+
+    let mut strength = vec![0u32; n * n];
+    for i in 0..n {
+        for j in 0..n {
+            if i != j && preferred[i * n + j] > preferred[j * n + i] {
+                strength[i * n + j] = preferred[i * n + j];
+            }
+        }
+    }
+    // Floyd-Warshall over the widest paths. Most songs only beat a few others directly, so rows with no
+    // path through k are skipped.
+    for k in 0..n {
+        for i in 0..n {
+            let through_k = strength[i * n + k];
+            if i == k || through_k == 0 {
+                continue;
+            }
+            for j in 0..n {
+                if j != i && j != k {
+                    let path = through_k.min(strength[k * n + j]);
+                    if path > strength[i * n + j] {
+                        strength[i * n + j] = path;
+                    }
+                }
+            }
+        }
+    }
+
+    (0..n)
+        .map(|i| {
+            (0..n)
+                .filter(|&j| j != i && strength[i * n + j] > strength[j * n + i])
+                .count()
+        })
+        .collect()
+}
+
+/// Indices of the `n` songs from winner to first eliminated. The indices must follow the tie-break order,
+/// which decides who goes when several songs share the lowest first-preference count.
+fn instant_runoff_order(n: usize, ballots: &[Vec<usize>]) -> Vec<usize> {
+    let mut remaining = BitSet::full(n);
+    // Where each ballot's highest remaining pick is; songs only ever drop out, so it only moves down.
+    let mut cursors = vec![0usize; ballots.len()];
+    let mut first_preferences = vec![0i64; n];
+    let mut eliminated = Vec::with_capacity(n);
+
+    for _ in 0..n {
+        first_preferences.fill(0);
+        for (ballot, cursor) in ballots.iter().zip(cursors.iter_mut()) {
+            while *cursor < ballot.len() && !remaining.contains(ballot[*cursor]) {
+                *cursor += 1;
+            }
+            if let Some(&top) = ballot.get(*cursor) {
+                first_preferences[top] += 1;
+            }
+        }
+
+        // Scan from the back so the song that loses the tie-break is eliminated first.
+        let loser = (0..n)
+            .rev()
+            .filter(|&song| remaining.contains(song))
+            .min_by_key(|&song| first_preferences[song])
+            .unwrap();
+        remaining.remove(loser);
+        eliminated.push(loser);
+    }
+
+    eliminated.reverse();
+    eliminated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Ballots for users 1.., each listing song ids favourite first.
+    fn entries(ballots: &[&[i32]]) -> Vec<BallotEntry> {
+        ballots
+            .iter()
+            .enumerate()
+            .flat_map(|(user, songs)| {
+                songs.iter().enumerate().map(move |(rank, &song_id)| BallotEntry {
+                    user_id: user as i32 + 1,
+                    song_id,
+                    rank: rank as i32 + 1,
+                })
+            })
+            .collect()
+    }
+
+    fn rank(method: RankingMethod, ballot_size: i32, ballots: &[&[i32]]) -> Vec<(i32, f64)> {
+        rank_songs(method, ballot_size, &ScoringWeights::default(), &entries(ballots))
+            .into_iter()
+            .map(|tally| (tally.song_id, tally.score))
+            .collect()
+    }
+
+    #[test]
+    fn borda_gives_the_ballot_size_for_first_down_to_one_for_last() {
+        let ranked = rank(RankingMethod::Borda, 3, &[&[1, 2, 3], &[2, 1], &[3]]);
+        assert_eq!(ranked, vec![(1, 5.0), (2, 5.0), (3, 4.0)]);
+    }
+
+    #[test]
+    fn vote_count_counts_ballots() {
+        let ranked = rank(RankingMethod::VoteCount, 3, &[&[1, 2, 3], &[3, 2], &[3]]);
+        assert_eq!(ranked, vec![(3, 3.0), (2, 2.0), (1, 1.0)]);
+    }
+
+    #[test]
+    fn schulze_resolves_a_condorcet_cycle_by_path_strength() {
+        // A beats B 6-3, B beats C 7-2 and C beats A 5-4: a cycle that every song wins 9 votes in.
+        let mut ballots: Vec<&[i32]> = vec![&[1, 2, 3]; 4];
+        ballots.extend(vec![&[2, 3, 1][..]; 3]);
+        ballots.extend(vec![&[3, 1, 2][..]; 2]);
+        let ranked = rank(RankingMethod::Schulze, 3, &ballots);
+        assert_eq!(ranked, vec![(1, 2.0), (2, 1.0), (3, 0.0)]);
+        let votes = rank(RankingMethod::VoteCount, 3, &ballots);
+        assert!(votes.iter().all(|&(_, score)| score == 9.0));
+    }
+
+    #[test]
+    fn schulze_prefers_listed_songs_over_unlisted_ones() {
+        let ranked = rank(RankingMethod::Schulze, 3, &[&[2], &[2, 1], &[1, 3]]);
+        assert_eq!(ranked, vec![(2, 2.0), (1, 1.0), (3, 0.0)]);
+    }
+
+    #[test]
+    fn instant_runoff_redistributes_eliminated_votes() {
+        // A leads on first preferences, but once C goes its voters carry B past A.
+        let mut ballots: Vec<&[i32]> = vec![&[1]; 4];
+        ballots.extend(vec![&[2][..]; 3]);
+        ballots.extend(vec![&[3, 2, 1][..]; 2]);
+        let ranked = rank(RankingMethod::InstantRunoff, 3, &ballots);
+        assert_eq!(ranked, vec![(2, 3.0), (1, 2.0), (3, 1.0)]);
+    }
+
+    #[test]
+    fn instant_runoff_eliminates_the_tie_break_loser_first() {
+        // Songs 1 and 2 tie on one first preference each; 1 is on fewer ballots, so it goes first.
+        let ranked = rank(RankingMethod::InstantRunoff, 2, &[&[1, 3], &[2, 3], &[3, 2], &[3]]);
+        assert_eq!(ranked, vec![(3, 3.0), (2, 2.0), (1, 1.0)]);
+    }
+
+    #[test]
+    fn ties_break_on_average_rank_then_best_rank_then_song_id() {
+        // 4, 3 and 1 are on two ballots each. 4 has the best average rank; 3 and 1 both average 3, and 3
+        // has the better best rank. The rest are on one ballot each: 6 and 8 both sit at rank 2 and 5 and 2
+        // at rank 4, so only the song id splits them.
+        let ballots: &[&[i32]] = &[&[4, 3, 1, 5], &[4, 8, 1, 3], &[7, 6, 9, 2]];
+        let ranked = rank(RankingMethod::VoteCount, 4, ballots);
+        let order: Vec<i32> = ranked.iter().map(|&(song_id, _)| song_id).collect();
+        assert_eq!(order, vec![4, 3, 1, 7, 6, 8, 9, 2, 5]);
+        for _ in 0..10 {
+            assert_eq!(rank(RankingMethod::VoteCount, 4, ballots), ranked);
+        }
+    }
+}
@@ -0,0 +1,218 @@
+//! Scoring weights: the points behind compatibility scores and the classic playlist ranking.
+//!
+//! Every season scores with its own weights. A new season copies its circle's, so changing a circle's
+//! weights only affects seasons created afterwards. Song points and the rank bonus are stated for a top 10
+//! and scale with the season's ballot size, like the defaults always have.
+
+use crate::api::compatibility::{round2, SeasonBallots};
+use crate::api::db::{self, Season};
+use crate::api::error::ApiError;
+use crate::api::previews;
+use crate::api::ranking::RankingMethod;
+use crate::api::types::{PairMovement, ScoringWeightsRequest, SongMovement, WeightsPreview};
+use crate::DB_POOL;
+use rocket::serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// The ballot size song points and the rank bonus are stated for.
+pub const REFERENCE_BALLOT_SIZE: i32 = 10;
+pub const MAX_WEIGHT: f64 = 1000.0;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(crate = "rocket::serde")]
+pub struct ScoringWeights {
+    /// Points for each song on both ballots, less the average rank difference over those songs.
+    pub shared_song_points: f64,
+    /// Points for each artist on both ballots.
+    pub shared_artist_points: f64,
+    /// Taken off per place of average rank difference between songs by a shared artist.
+    pub artist_rank_diff_weight: f64,
+    /// Classic ranking: the bonus per place a song's average rank is above the bottom of the ballot.
+    pub rank_bonus: f64,
+}
+
+impl Default for ScoringWeights {
+    /// The original formulas: `overlap*10 - avg_rank_diff + shared_artists*3 - artist_rank_diff*0.5`, and
+    /// voters plus 0.15 per place of average rank.
+    fn default() -> Self {
+        ScoringWeights {
+            shared_song_points: 10.0,
+            shared_artist_points: 3.0,
+            artist_rank_diff_weight: 0.5,
+            rank_bonus: 0.15,
+        }
+    }
+}
+
+impl ScoringWeights {
+    /// What a shared song is worth on a ballot of `ballot_size`.
+    pub fn song_points(&self, ballot_size: i32) -> f64 {
+        self.shared_song_points * ballot_size as f64 / REFERENCE_BALLOT_SIZE as f64
+    }
+
+    /// The classic rank bonus per place on a ballot of `ballot_size`, so the largest bonus is the same
+    /// whatever the size.
+    pub fn rank_bonus_per_place(&self, ballot_size: i32) -> f64 {
+        self.rank_bonus * REFERENCE_BALLOT_SIZE as f64 / ballot_size as f64
+    }
+
+    /// These weights with the fields given in `request` replaced.
+    pub fn apply(self, request: &ScoringWeightsRequest) -> ScoringWeights {
+        ScoringWeights {
+            shared_song_points: request.shared_song_points.unwrap_or(self.shared_song_points),
+            shared_artist_points: request.shared_artist_points.unwrap_or(self.shared_artist_points),
+            artist_rank_diff_weight: request
+                .artist_rank_diff_weight
+                .unwrap_or(self.artist_rank_diff_weight),
+            rank_bonus: request.rank_bonus.unwrap_or(self.rank_bonus),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), ApiError> {
+        let fields = [
+            ("shared_song_points", self.shared_song_points),
+            ("shared_artist_points", self.shared_artist_points),
+            ("artist_rank_diff_weight", self.artist_rank_diff_weight),
+            ("rank_bonus", self.rank_bonus),
+        ];
+        for (field, value) in fields {
+            if !(0.0..=MAX_WEIGHT).contains(&value) {
+                return Err(ApiError::invalid_field(
+                    field,
+                    format!("Weights run from 0 to {}", MAX_WEIGHT),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The weights the circle's new seasons start with.
+pub async fn for_circle(circle_id: &i32) -> Result<ScoringWeights, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    Ok(db::get_circle_weights(db_pool, circle_id).await?.unwrap_or_default())
+}
+
+pub async fn for_season(season: &Season) -> Result<ScoringWeights, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    Ok(db::get_season_weights(db_pool, &season.id).await?.unwrap_or_default())
+}
+
+/// How the season's pair leaderboard and classic playlist order would change from `current` to `proposed`
+/// weights. Both lists are in proposed order; nothing is saved.
+pub async fn preview(
+    season: &Season,
+    current: ScoringWeights,
+    proposed: ScoringWeights,
+) -> Result<WeightsPreview, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    let mut ballots = SeasonBallots::load(season).await?;
+    let current_pairs: HashMap<(i32, i32), (usize, f64)> = ballots
+        .pairs()
+        .into_iter()
+        .enumerate()
+        .map(|(index, (user_1, user_2, score))| ((user_1, user_2), (index + 1, score.combined)))
+        .collect();
+    ballots.set_weights(proposed);
+    let pairs = ballots
+        .pairs()
+        .into_iter()
+        .enumerate()
+        .map(|(index, (user_1, user_2, score))| {
+            let (current_position, current_score) = current_pairs[&(user_1, user_2)];
+            PairMovement {
+                user_1: ballots.voter_name(user_1),
+                user_2: ballots.voter_name(user_2),
+                current_position,
+                proposed_position: index + 1,
+                movement: current_position as i64 - (index + 1) as i64,
+                current_score: round2(current_score),
+                proposed_score: round2(score.combined),
+            }
+        })
+        .collect();
+
+    // Both rankings come from the same ballots, so a vote saved in between cannot leave a song unmatched.
+    let method = RankingMethod::default();
+    let rows = db::get_ballots(db_pool, &season.id).await?;
+    let (current_songs, _) = previews::rank_rows(&rows, season.ballot_size, method, &current).await?;
+    let (proposed_songs, _) = previews::rank_rows(&rows, season.ballot_size, method, &proposed).await?;
+    let current_songs: HashMap<&str, (usize, f64)> = current_songs
+        .iter()
+        .map(|song| (song.uri.as_str(), (song.position, song.score)))
+        .collect();
+    let songs = proposed_songs
+        .iter()
+        .map(|song| {
+            let (current_position, current_score) =
+                current_songs.get(song.uri.as_str()).copied().ok_or_else(|| {
+                    ApiError::Internal(format!("{} is missing from the current ranking", song.uri))
+                })?;
+            Ok(SongMovement {
+                name: song.name.clone(),
+                artist: song.artist.clone(),
+                current_position,
+                proposed_position: song.position,
+                movement: current_position as i64 - song.position as i64,
+                current_score: round2(current_score),
+                proposed_score: round2(song.score),
+            })
+        })
+        .collect::<Result<_, ApiError>>()?;
+
+    Ok(WeightsPreview {
+        current,
+        proposed,
+        pairs,
+        songs,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_scale_like_the_original_formulas() {
+        let weights = ScoringWeights::default();
+        assert_eq!(weights.song_points(10), 10.0);
+        assert_eq!(weights.song_points(3), 3.0);
+        assert_eq!(weights.song_points(20), 20.0);
+        assert_eq!(weights.rank_bonus_per_place(10), 1.5 / 10.0);
+        assert_eq!(weights.rank_bonus_per_place(3), 1.5 / 3.0);
+    }
+
+    #[test]
+    fn apply_replaces_only_the_given_fields() {
+        let request = ScoringWeightsRequest {
+            shared_song_points: None,
+            shared_artist_points: Some(5.0),
+            artist_rank_diff_weight: None,
+            rank_bonus: Some(0.0),
+        };
+        let weights = ScoringWeights::default().apply(&request);
+        assert_eq!(
+            weights,
+            ScoringWeights {
+                shared_artist_points: 5.0,
+                rank_bonus: 0.0,
+                ..ScoringWeights::default()
+            }
+        );
+    }
+
+    #[test]
+    fn validate_rejects_negative_huge_and_nan_weights() {
+        assert!(ScoringWeights::default().validate().is_ok());
+        for weights in [
+            ScoringWeights { shared_song_points: -1.0, ..ScoringWeights::default() },
+            ScoringWeights { rank_bonus: MAX_WEIGHT + 1.0, ..ScoringWeights::default() },
+            ScoringWeights { shared_artist_points: f64::NAN, ..ScoringWeights::default() },
+        ] {
+            assert!(weights.validate().is_err());
+        }
+    }
+}
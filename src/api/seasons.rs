@@ -0,0 +1,215 @@
+//! Seasons: the editions a circle ranks in, e.g. one per year. A season takes ballots while it is open,
+//! locks voting and generates its playlist at `locks_at`, shows that playlist to members from
+//! `reveals_at`, and keeps everything unchanged once it has closed.
+
+use crate::api::db::{self, Membership, Season, SeasonWindow};
+use crate::api::error::ApiError;
+use crate::api::previews;
+use crate::api::types::Song;
+use crate::DB_POOL;
+use rocket::fairing::AdHoc;
+use rocket::serde::Serialize;
+use rocket::time::format_description::well_known::Rfc3339;
+use rocket::tokio::time::interval;
+use sqlx::types::time::OffsetDateTime;
+use std::collections::HashSet;
+
+pub const MAX_SEASON_NAME_LENGTH: usize = 100;
+/// A top 10, unless the season says otherwise.
+pub const DEFAULT_BALLOT_SIZE: i32 = 10;
+pub const MAX_BALLOT_SIZE: i32 = 100;
+
+/// How often the scheduler looks for seasons that locked without a playlist yet.
+const SCHEDULE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum SeasonStatus {
+    Upcoming,
+    /// Taking ballots.
+    Open,
+    /// Ballots are final; the playlist is only visible to the circle's owner and admins.
+    Locked,
+    /// Members can see the playlist.
+    Revealed,
+    Closed,
+}
+
+pub fn status(season: &Season) -> SeasonStatus {
+    let now = OffsetDateTime::now_utc();
+    if now < season.opens_at {
+        SeasonStatus::Upcoming
+    } else if now < season.locks_at {
+        SeasonStatus::Open
+    } else if now < season.reveals_at {
+        SeasonStatus::Locked
+    } else if now < season.closes_at {
+        SeasonStatus::Revealed
+    } else {
+        SeasonStatus::Closed
+    }
+}
+
+fn timestamp(at: OffsetDateTime) -> String {
+    at.format(&Rfc3339).unwrap_or_default()
+}
+
+/// Checks the name, the ballot size and that the phases come in order: opens, locks, reveals, closes.
+pub fn validate_window(window: &SeasonWindow) -> Result<(), ApiError> {
+    if window.name.is_empty() || window.name.chars().count() > MAX_SEASON_NAME_LENGTH {
+        return Err(ApiError::invalid_field(
+            "name",
+            format!("Season name must be 1 to {} characters", MAX_SEASON_NAME_LENGTH),
+        ));
+    }
+    if !(1..=MAX_BALLOT_SIZE).contains(&window.ballot_size) {
+        return Err(ApiError::invalid_field(
+            "ballot_size",
+            format!("A ballot holds 1 to {} songs", MAX_BALLOT_SIZE),
+        ));
+    }
+    if window.closes_at <= window.opens_at {
+        return Err(ApiError::invalid_field(
+            "closes_at",
+            "A season has to close after it opens",
+        ));
+    }
+    if window.locks_at < window.opens_at || window.locks_at > window.closes_at {
+        return Err(ApiError::invalid_field(
+            "locks_at",
+            "Voting has to lock between the season opening and closing",
+        ));
+    }
+    if window.reveals_at < window.locks_at || window.reveals_at > window.closes_at {
+        return Err(ApiError::invalid_field(
+            "reveals_at",
+            "The playlist has to be revealed between voting locking and the season closing",
+        ));
+    }
+    Ok(())
+}
+
+/// The season a request is about: `season` when given, otherwise the circle's open season, or its latest
+/// one if none is open. Seasons of other circles are reported as missing.
+pub async fn resolve(membership: &Membership, season: Option<i32>) -> Result<Season, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    match season {
+        Some(season_id) => db::get_season(db_pool, &membership.circle_id, &season_id)
+            .await?
+            .ok_or_else(|| ApiError::NotFound("Season not found".to_string())),
+        None => db::get_current_season(db_pool, &membership.circle_id)
+            .await?
+            .ok_or_else(|| ApiError::NotFound("This circle has no season yet".to_string())),
+    }
+}
+
+/// Fails unless the season is taking ballots.
+pub fn require_open(season: &Season) -> Result<(), ApiError> {
+    match status(season) {
+        SeasonStatus::Open => Ok(()),
+        SeasonStatus::Upcoming => Err(ApiError::Forbidden(format!(
+            "Season {} opens at {}",
+            season.name,
+            timestamp(season.opens_at)
+        ))),
+        SeasonStatus::Locked | SeasonStatus::Revealed | SeasonStatus::Closed => {
+            Err(ApiError::Locked(format!(
+                "Voting in season {} locked at {}; its rankings can no longer change",
+                season.name,
+                timestamp(season.locks_at)
+            )))
+        }
+    }
+}
+
+/// Checks that every song has its own rank within the season's ballot size.
+pub fn validate_ballot(season: &Season, songs: &[Song]) -> Result<(), ApiError> {
+    let mut ranks = HashSet::new();
+    let mut uris = HashSet::new();
+    for song in songs {
+        let rank = song
+            .rank
+            .ok_or_else(|| ApiError::invalid_field("rank", "Every song needs a rank"))?;
+        if !(1..=season.ballot_size).contains(&rank) {
+            return Err(ApiError::invalid_field(
+                "rank",
+                format!("Ranks in season {} run from 1 to {}", season.name, season.ballot_size),
+            ));
+        }
+        if !ranks.insert(rank) {
+            return Err(ApiError::invalid_field(
+                "rank",
+                format!("Rank {} is used more than once", rank),
+            ));
+        }
+        if !uris.insert(song.uri.as_str()) {
+            return Err(ApiError::invalid_field(
+                "uri",
+                format!("{} is on the ballot more than once", song.name),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Fails until the season's playlist is revealed to members.
+pub fn require_revealed(season: &Season) -> Result<(), ApiError> {
+    match status(season) {
+        SeasonStatus::Revealed | SeasonStatus::Closed => Ok(()),
+        _ => Err(ApiError::Forbidden(format!(
+            "The playlist for season {} is revealed at {}",
+            season.name,
+            timestamp(season.reveals_at)
+        ))),
+    }
+}
+
+/// Generates the playlist of every season whose voting has locked, on behalf of the circle's owner. A season
+/// that fails is logged and left for the next tick without holding up the others.
+async fn generate_locked_playlists() -> Result<usize, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    let seasons = db::get_seasons_awaiting_playlist(db_pool).await?;
+    let mut generated = 0;
+    for season in &seasons {
+        match generate_playlist(season).await {
+            Ok(true) => generated += 1,
+            Ok(false) => {}
+            Err(err) => {
+                rocket::error!("Failed to generate the playlist of season {}: {:?}", season.id, err)
+            }
+        }
+    }
+    Ok(generated)
+}
+
+/// Whether this call generated the season's playlist, rather than finding it already done.
+async fn generate_playlist(season: &Season) -> Result<bool, ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    let owner = db::get_circle_owner(db_pool, &season.circle_id)
+        .await?
+        .ok_or_else(|| ApiError::Internal(format!("Circle {} has no owner", season.circle_id)))?;
+    Ok(previews::create_generated_preview(season, &owner.id).await?.is_some())
+}
+
+/// Moves seasons from open to locked: once `locks_at` passes, the season's playlist is generated and
+/// kept, ready for its reveal. Failures are retried on the next tick.
+pub fn scheduler() -> AdHoc {
+    AdHoc::on_liftoff("Season Scheduler", |_| {
+        Box::pin(async {
+            rocket::tokio::spawn(async {
+                let mut ticker = interval(SCHEDULE_INTERVAL);
+                loop {
+                    ticker.tick().await;
+                    match generate_locked_playlists().await {
+                        Ok(0) => {}
+                        Ok(generated) => rocket::info!("Generated {} season playlists", generated),
+                        Err(err) => rocket::error!("Failed to generate season playlists: {:?}", err),
+                    }
+                }
+            });
+        })
+    })
+}
@@ -0,0 +1,185 @@
+//! Server-side login sessions. The private `session` cookie only holds a random id; the `sessions` row it
+//! points at decides who the request belongs to, so deleting rows revokes sessions immediately.
+
+use crate::api::credentials::AuthConfig;
+use crate::api::db::{self, User};
+use crate::api::error::ApiError;
+use crate::DB_POOL;
+use base64::engine::general_purpose;
+use base64::Engine;
+use rand::RngCore;
+use rocket::fairing::AdHoc;
+use rocket::http::{Cookie, CookieJar, Status};
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::time::Duration;
+use rocket::tokio::time::interval;
+use sqlx::types::time::OffsetDateTime;
+use std::ops::Deref;
+
+pub const SESSION_COOKIE: &str = "session";
+
+const SESSION_LIFETIME: Duration = Duration::days(30);
+const SWEEP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+// User agents are only stored so people can tell their sessions apart; no need to keep a novel.
+const MAX_USER_AGENT_LENGTH: usize = 512;
+
+/// The signed-in user behind a request, resolved through the `sessions` table.
+/// Fails with 401 when there is no cookie or the session has expired or been revoked.
+pub struct Session {
+    pub id: String,
+    pub user: User,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Session {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        let cookies = request.cookies();
+        let id = match cookies.get_private(SESSION_COOKIE) {
+            Some(cookie) => cookie.value().to_string(),
+            None => return Outcome::Error((Status::Unauthorized, ())),
+        };
+
+        let db_pool = DB_POOL.get().unwrap();
+        match db::touch_session(db_pool, &id).await {
+            Ok(Some(user)) => Outcome::Success(Session { id, user }),
+            Ok(None) => {
+                cookies.remove_private(SESSION_COOKIE);
+                Outcome::Error((Status::Unauthorized, ()))
+            }
+            Err(err) => {
+                rocket::error!("Failed to look up session: {}", err);
+                Outcome::Error((Status::InternalServerError, ()))
+            }
+        }
+    }
+}
+
+/// The logged-in user, for routes that need one. Without a live session the request fails with 401
+/// and the `unauthorized` catcher answers with `ApiError::NotAuthenticated`.
+pub struct AuthenticatedUser(pub User);
+
+impl Deref for AuthenticatedUser {
+    type Target = User;
+
+    fn deref(&self) -> &User {
+        &self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AuthenticatedUser {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        request
+            .guard::<Session>()
+            .await
+            .map(|session| AuthenticatedUser(session.user))
+    }
+}
+
+/// A logged-in user listed under `auth.admins`. Anyone else logged in gets 403.
+pub struct AdminUser(pub User);
+
+impl Deref for AdminUser {
+    type Target = User;
+
+    fn deref(&self) -> &User {
+        &self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for AdminUser {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        let user = match request.guard::<AuthenticatedUser>().await {
+            Outcome::Success(user) => user.0,
+            Outcome::Error(failure) => return Outcome::Error(failure),
+            Outcome::Forward(status) => return Outcome::Forward(status),
+        };
+
+        let is_admin = request
+            .rocket()
+            .state::<AuthConfig>()
+            .is_some_and(|config| config.is_admin(&user.name));
+        if is_admin {
+            Outcome::Success(AdminUser(user))
+        } else {
+            Outcome::Error((Status::Forbidden, ()))
+        }
+    }
+}
+
+#[catch(401)]
+pub fn unauthorized() -> ApiError {
+    ApiError::NotAuthenticated("Not logged in".to_string())
+}
+
+#[catch(403)]
+pub fn forbidden() -> ApiError {
+    ApiError::Forbidden("Only admins can do that".to_string())
+}
+
+/// The `User-Agent` header, if the client sent one.
+pub struct UserAgent(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for UserAgent {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, ()> {
+        let user_agent = request
+            .headers()
+            .get_one("User-Agent")
+            .map(|value| value.chars().take(MAX_USER_AGENT_LENGTH).collect());
+        Outcome::Success(UserAgent(user_agent))
+    }
+}
+
+/// Opens a new session for `user` and hands its id to the browser.
+pub async fn start(
+    cookies: &CookieJar<'_>,
+    user: &User,
+    user_agent: &UserAgent,
+) -> Result<(), ApiError> {
+    let db_pool = DB_POOL.get().unwrap();
+
+    let mut id_bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut id_bytes);
+    let id = general_purpose::URL_SAFE_NO_PAD.encode(id_bytes);
+
+    let expires_at = OffsetDateTime::now_utc() + SESSION_LIFETIME;
+    db::insert_session(db_pool, &id, &user.id, user_agent.0.as_deref(), expires_at).await?;
+
+    cookies.add_private(
+        Cookie::build((SESSION_COOKIE, id))
+            .http_only(true)
+            .max_age(SESSION_LIFETIME),
+    );
+
+    Ok(())
+}
+
+/// Deletes expired sessions once an hour for as long as the server runs.
+pub fn sweeper() -> AdHoc {
+    AdHoc::on_liftoff("Session Sweeper", |_| {
+        Box::pin(async {
+            rocket::tokio::spawn(async {
+                let db_pool = DB_POOL.get().unwrap();
+                let mut ticker = interval(SWEEP_INTERVAL);
+                loop {
+                    ticker.tick().await;
+                    match db::delete_expired_sessions(db_pool).await {
+                        Ok(0) => {}
+                        Ok(removed) => rocket::info!("Removed {} expired sessions", removed),
+                        Err(err) => rocket::error!("Failed to remove expired sessions: {}", err),
+                    }
+                }
+            });
+        })
+    })
+}
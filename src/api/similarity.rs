@@ -0,0 +1,418 @@
+//! Other ways to measure how alike two ballots are, next to the combined compatibility score. Each metric
+//! comes back with the components it was computed from.
+//!
+//! Ballot `a` is the active user's, `b` the other user's. Picks are expected in rank order.
+
+use crate::api::compatibility::{round2, PairScore, Pick};
+use rocket::serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// How quickly rank-biased overlap loses interest in lower ranks: the top `1 / (1 - p)` places, here 10,
+/// carry most of the weight.
+pub const RBO_PERSISTENCE: f64 = 0.9;
+
+#[derive(FromFormField, Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(crate = "rocket::serde", rename_all = "snake_case")]
+pub enum SimilarityMetric {
+    /// Shared songs and artists, weighted by how close their ranks are. Unbounded.
+    #[default]
+    Combined,
+    /// Mean of the Jaccard index over songs and over artists, 0 to 1.
+    Jaccard,
+    /// Spearman's rank correlation over the shared songs, -1 to 1.
+    Spearman,
+    /// Kendall's tau over the shared songs, -1 to 1.
+    Kendall,
+    /// Rank-biased overlap of the two ballots, top-weighted, 0 to 1.
+    Rbo,
+    /// Cosine between the ballots as vectors of song weights, rank 1 weighing most, 0 to 1.
+    Cosine,
+}
+
+impl SimilarityMetric {
+    pub const ALL: [SimilarityMetric; 6] = [
+        SimilarityMetric::Combined,
+        SimilarityMetric::Jaccard,
+        SimilarityMetric::Spearman,
+        SimilarityMetric::Kendall,
+        SimilarityMetric::Rbo,
+        SimilarityMetric::Cosine,
+    ];
+}
+
+/// A metric's score and its components. Correlations need two shared songs and are 0 otherwise.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(crate = "rocket::serde", tag = "metric", rename_all = "snake_case")]
+pub enum Similarity {
+    Combined {
+        score: f64,
+        overlapping_songs: i64,
+        song_rank_diff: f64,
+        shared_artists: i64,
+        artist_rank_diff: f64,
+    },
+    Jaccard {
+        score: f64,
+        song_jaccard: f64,
+        artist_jaccard: f64,
+        shared_songs: usize,
+        total_songs: usize,
+        shared_artists: usize,
+        total_artists: usize,
+    },
+    Spearman {
+        score: f64,
+        shared_songs: usize,
+        /// Sum of squared differences between the shared songs' places, each ballot re-ranked 1..n.
+        squared_rank_differences: i64,
+    },
+    Kendall {
+        score: f64,
+        shared_songs: usize,
+        concordant_pairs: usize,
+        discordant_pairs: usize,
+    },
+    Rbo {
+        score: f64,
+        persistence: f64,
+        depth: usize,
+    },
+    Cosine {
+        score: f64,
+        dot_product: f64,
+        active_norm: f64,
+        other_norm: f64,
+    },
+}
+
+impl Similarity {
+    pub fn score(&self) -> f64 {
+        match self {
+            Similarity::Combined { score, .. }
+            | Similarity::Jaccard { score, .. }
+            | Similarity::Spearman { score, .. }
+            | Similarity::Kendall { score, .. }
+            | Similarity::Rbo { score, .. }
+            | Similarity::Cosine { score, .. } => *score,
+        }
+    }
+}
+
+pub fn measure(
+    metric: SimilarityMetric,
+    a: &[Pick],
+    b: &[Pick],
+    ballot_size: i32,
+    pair: &PairScore,
+) -> Similarity {
+    match metric {
+        SimilarityMetric::Combined => Similarity::Combined {
+            score: round2(pair.combined),
+            overlapping_songs: pair.overlapping_songs,
+            song_rank_diff: round2(pair.song_rank_diff),
+            shared_artists: pair.shared_artists,
+            artist_rank_diff: round2(pair.artist_rank_diff),
+        },
+        SimilarityMetric::Jaccard => jaccard(a, b),
+        SimilarityMetric::Spearman => spearman(a, b),
+        SimilarityMetric::Kendall => kendall(a, b),
+        SimilarityMetric::Rbo => rbo(a, b, RBO_PERSISTENCE),
+        SimilarityMetric::Cosine => cosine(a, b, ballot_size),
+    }
+}
+
+/// Size of the intersection and of the union.
+fn overlap<T: Eq + std::hash::Hash>(a: &HashSet<T>, b: &HashSet<T>) -> (usize, usize) {
+    (a.intersection(b).count(), a.union(b).count())
+}
+
+fn ratio(part: usize, whole: usize) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        part as f64 / whole as f64
+    }
+}
+
+fn song_set(ballot: &[Pick]) -> HashSet<i32> {
+    ballot.iter().map(|pick| pick.song_id).collect()
+}
+
+fn artist_set(ballot: &[Pick]) -> HashSet<&str> {
+    ballot.iter().map(|pick| pick.artist.as_str()).collect()
+}
+
+fn jaccard(a: &[Pick], b: &[Pick]) -> Similarity {
+    let (shared_songs, total_songs) = overlap(&song_set(a), &song_set(b));
+    let (shared_artists, total_artists) = overlap(&artist_set(a), &artist_set(b));
+    let song_jaccard = ratio(shared_songs, total_songs);
+    let artist_jaccard = ratio(shared_artists, total_artists);
+
+    Similarity::Jaccard {
+        score: (song_jaccard + artist_jaccard) / 2.0,
+        song_jaccard,
+        artist_jaccard,
+        shared_songs,
+        total_songs,
+        shared_artists,
+        total_artists,
+    }
+}
+
+/// Each shared song's place among the shared songs on `a` and on `b`, in `a`'s order.
+fn shared_places(a: &[Pick], b: &[Pick]) -> Vec<(usize, usize)> {
+    let in_a = song_set(a);
+    let places_in_b: HashMap<i32, usize> = b
+        .iter()
+        .filter(|pick| in_a.contains(&pick.song_id))
+        .enumerate()
+        .map(|(place, pick)| (pick.song_id, place + 1))
+        .collect();
+
+    a.iter()
+        .filter_map(|pick| places_in_b.get(&pick.song_id).copied())
+        .enumerate()
+        .map(|(place, place_in_b)| (place + 1, place_in_b))
+        .collect()
+}
+
+fn spearman(a: &[Pick], b: &[Pick]) -> Similarity {
+    let places = shared_places(a, b);
+    let n = places.len();
+    let squared_rank_differences: i64 = places
+        .iter()
+        .map(|&(place_a, place_b)| (place_a as i64 - place_b as i64).pow(2))
+        .sum();
+
+    let score = if n < 2 {
+        0.0
+    } else {
+        let n = n as f64;
+        1.0 - 6.0 * squared_rank_differences as f64 / (n * (n * n - 1.0))
+    };
+
+    Similarity::Spearman {
+        score,
+        shared_songs: n,
+        squared_rank_differences,
+    }
+}
+
+fn kendall(a: &[Pick], b: &[Pick]) -> Similarity {
+    let places = shared_places(a, b);
+    let n = places.len();
+
+    // `places` is in `a`'s order, so a pair agrees when `b` orders it the same way.
+    let (mut concordant_pairs, mut discordant_pairs) = (0, 0);
+    for (i, &(_, earlier)) in places.iter().enumerate() {
+        for &(_, later) in &places[i + 1..] {
+            if earlier < later {
+                concordant_pairs += 1;
+            } else {
+                discordant_pairs += 1;
+            }
+        }
+    }
+
+    let score = if n < 2 {
+        0.0
+    } else {
+        (concordant_pairs as f64 - discordant_pairs as f64) / (n * (n - 1) / 2) as f64
+    };
+
+    Similarity::Kendall {
+        score,
+        shared_songs: n,
+        concordant_pairs,
+        discordant_pairs,
+    }
+}
+
+/// Extrapolated rank-biased overlap (Webber, Moffat and Zobel, 2010) down to the longer ballot's length.
+fn rbo(a: &[Pick], b: &[Pick], persistence: f64) -> Similarity {
+    let depth = a.len().max(b.len());
+    let mut seen_a: HashSet<i32> = HashSet::new();
+    let mut seen_b: HashSet<i32> = HashSet::new();
+    let mut shared = 0usize;
+    let mut sum = 0.0;
+
+    for d in 1..=depth {
+        let song_a = a.get(d - 1).map(|pick| pick.song_id);
+        let song_b = b.get(d - 1).map(|pick| pick.song_id);
+        if let Some(song) = song_a {
+            seen_a.insert(song);
+            if seen_b.contains(&song) {
+                shared += 1;
+            }
+        }
+        if let Some(song) = song_b {
+            seen_b.insert(song);
+            if seen_a.contains(&song) {
+                shared += 1;
+            }
+        }
+        sum += shared as f64 / d as f64 * persistence.powi(d as i32);
+    }
+
+    let score = if depth == 0 {
+        0.0
+    } else {
+        shared as f64 / depth as f64 * persistence.powi(depth as i32)
+            + (1.0 - persistence) / persistence * sum
+    };
+
+    Similarity::Rbo {
+        score,
+        persistence,
+        depth,
+    }
+}
+
+fn cosine(a: &[Pick], b: &[Pick], ballot_size: i32) -> Similarity {
+    let weight = |pick: &Pick| (ballot_size + 1 - pick.rank).max(0) as f64;
+    let weights_b: HashMap<i32, f64> = b.iter().map(|pick| (pick.song_id, weight(pick))).collect();
+
+    // Folded from 0.0 rather than summed, which gives -0.0 for ballots with no song in common.
+    let dot_product = a
+        .iter()
+        .filter_map(|pick| Some(weight(pick) * weights_b.get(&pick.song_id)?))
+        .fold(0.0, |total, product| total + product);
+    let norm = |ballot: &[Pick]| ballot.iter().map(|pick| weight(pick).powi(2)).sum::<f64>().sqrt();
+    let active_norm = norm(a);
+    let other_norm = norm(b);
+
+    let score = if active_norm == 0.0 || other_norm == 0.0 {
+        0.0
+    } else {
+        dot_product / (active_norm * other_norm)
+    };
+
+    Similarity::Cosine {
+        score,
+        dot_product,
+        active_norm,
+        other_norm,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ballot(songs: &[(i32, &str)]) -> Vec<Pick> {
+        songs
+            .iter()
+            .enumerate()
+            .map(|(index, &(song_id, artist))| Pick {
+                song_id,
+                name: format!("Song {}", song_id),
+                artist: artist.to_string(),
+                rank: index as i32 + 1,
+            })
+            .collect()
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn identical_ballots_score_one_everywhere() {
+        let a = ballot(&[(1, "X"), (2, "Y"), (3, "Z")]);
+
+        assert_close(jaccard(&a, &a).score(), 1.0);
+        assert_close(spearman(&a, &a).score(), 1.0);
+        assert_close(kendall(&a, &a).score(), 1.0);
+        assert_close(rbo(&a, &a, RBO_PERSISTENCE).score(), 1.0);
+        assert_close(cosine(&a, &a, 10).score(), 1.0);
+    }
+
+    #[test]
+    fn reversed_shared_songs_correlate_negatively() {
+        let a = ballot(&[(1, "X"), (2, "Y"), (3, "Z")]);
+        let b = ballot(&[(3, "Z"), (2, "Y"), (1, "X")]);
+
+        assert_eq!(
+            spearman(&a, &b),
+            Similarity::Spearman {
+                score: -1.0,
+                shared_songs: 3,
+                squared_rank_differences: 8,
+            }
+        );
+        assert_eq!(
+            kendall(&a, &b),
+            Similarity::Kendall {
+                score: -1.0,
+                shared_songs: 3,
+                concordant_pairs: 0,
+                discordant_pairs: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn correlations_only_look_at_shared_songs() {
+        // Shared songs 1, 2 and 3 come in the same order on both, with other songs in between.
+        let a = ballot(&[(1, "X"), (9, "Q"), (2, "Y"), (3, "Z")]);
+        let b = ballot(&[(8, "P"), (1, "X"), (2, "Y"), (3, "Z")]);
+
+        assert_close(spearman(&a, &b).score(), 1.0);
+        assert_close(kendall(&a, &b).score(), 1.0);
+        assert_close(spearman(&a, &ballot(&[(1, "X")])).score(), 0.0);
+    }
+
+    #[test]
+    fn jaccard_averages_songs_and_artists() {
+        let a = ballot(&[(1, "X"), (2, "Y")]);
+        let b = ballot(&[(1, "X"), (3, "Y"), (4, "Z")]);
+
+        assert_eq!(
+            jaccard(&a, &b),
+            Similarity::Jaccard {
+                // Songs 1 of 4, artists 2 of 3.
+                score: (0.25 + 2.0 / 3.0) / 2.0,
+                song_jaccard: 0.25,
+                artist_jaccard: 2.0 / 3.0,
+                shared_songs: 1,
+                total_songs: 4,
+                shared_artists: 2,
+                total_artists: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn rbo_weighs_the_top_of_the_ballot_most() {
+        let a = ballot(&[(1, "X"), (2, "Y"), (3, "Z"), (4, "W")]);
+        let agree_on_top = ballot(&[(1, "X"), (2, "Y"), (5, "V"), (6, "U")]);
+        let agree_on_bottom = ballot(&[(5, "V"), (6, "U"), (3, "Z"), (4, "W")]);
+
+        let top = rbo(&a, &agree_on_top, RBO_PERSISTENCE).score();
+        let bottom = rbo(&a, &agree_on_bottom, RBO_PERSISTENCE).score();
+
+        assert!(top > bottom, "{} <= {}", top, bottom);
+        assert_close(rbo(&a, &ballot(&[(7, "T")]), RBO_PERSISTENCE).score(), 0.0);
+    }
+
+    #[test]
+    fn cosine_weights_follow_the_ballot_size() {
+        // On a top 3 the shared song weighs 3 on `a` and 2 on `b`; the other songs 2 and 3.
+        let a = ballot(&[(1, "X"), (2, "Y")]);
+        let b = ballot(&[(3, "Z"), (1, "X")]);
+
+        let Similarity::Cosine {
+            score,
+            dot_product,
+            active_norm,
+            other_norm,
+        } = cosine(&a, &b, 3)
+        else {
+            panic!("not a cosine similarity");
+        };
+        assert_close(dot_product, 6.0);
+        assert_close(active_norm, 13f64.sqrt());
+        assert_close(other_norm, 13f64.sqrt());
+        assert_close(score, 6.0 / 13.0);
+    }
+}
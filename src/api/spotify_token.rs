@@ -0,0 +1,266 @@
+use crate::api::db;
+use crate::api::error::ApiError;
+use crate::api::types::AccessTokenResponse;
+use base64::engine::general_purpose;
+use base64::Engine;
+use reqwest::Client;
+use rocket::serde::Deserialize;
+use rocket::tokio::sync::Mutex;
+use sqlx::types::time::OffsetDateTime;
+use sqlx_postgres::PgPool;
+use std::env;
+use std::time::{Duration, Instant};
+
+// Refresh a little before Spotify's expiry so a request never goes out with a token that dies mid-flight.
+const REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+// Everything the playlist generation needs to write into the user's own account.
+const USER_SCOPES: &str = "playlist-modify-public playlist-modify-private ugc-image-upload";
+
+/// Spotify endpoints, read from the `spotify` table of the Rocket config so they can point at a local stand-in.
+#[derive(Deserialize, Clone)]
+#[serde(crate = "rocket::serde", default)]
+pub struct SpotifyConfig {
+    pub accounts_url: String,
+    pub api_url: String,
+    pub redirect_uri: String,
+}
+
+impl Default for SpotifyConfig {
+    fn default() -> Self {
+        SpotifyConfig {
+            accounts_url: "https://accounts.spotify.com".to_string(),
+            api_url: "https://api.spotify.com/v1".to_string(),
+            redirect_uri: "http://localhost:8080/api/spotify/callback".to_string(),
+        }
+    }
+}
+
+impl SpotifyConfig {
+    fn token_url(&self) -> String {
+        format!("{}/api/token", self.accounts_url.trim_end_matches('/'))
+    }
+
+    fn authorize_url(&self) -> String {
+        format!("{}/authorize", self.accounts_url.trim_end_matches('/'))
+    }
+
+    /// Web API url for `path`, which starts with a `/`.
+    pub fn api(&self, path: &str) -> String {
+        format!("{}{}", self.api_url.trim_end_matches('/'), path)
+    }
+}
+
+struct CachedToken {
+    access_token: String,
+    expires_at: Instant,
+}
+
+/// A user's access token together with the Spotify account it belongs to.
+pub struct SpotifyUserToken {
+    pub access_token: String,
+    pub spotify_user_id: String,
+}
+
+/// Holds the app's client-credentials token for the whole server and refreshes it shortly before it expires.
+/// Also drives the authorization-code flow used to act on a user's own Spotify account.
+pub struct SpotifyTokenManager {
+    config: SpotifyConfig,
+    client_id: String,
+    client_secret: String,
+    cached: Mutex<Option<CachedToken>>,
+}
+
+impl SpotifyTokenManager {
+    pub fn from_env(config: SpotifyConfig) -> Self {
+        SpotifyTokenManager {
+            config,
+            client_id: env::var("SPOTIFY_CLIENT").expect("SPOTIFY_CLIENT must be set"),
+            client_secret: env::var("SPOTIFY_SECRET").expect("SPOTIFY_SECRET must be set"),
+            cached: Mutex::new(None),
+        }
+    }
+
+    pub async fn access_token(&self, client: &Client) -> Result<String, ApiError> {
+        // The lock is held across the refresh so concurrent requests wait for one token instead of all fetching their own.
+        let mut cached = self.cached.lock().await;
+
+        if let Some(token) = cached.as_ref() {
+            if Instant::now() + REFRESH_MARGIN < token.expires_at {
+                return Ok(token.access_token.clone());
+            }
+        }
+
+        let data = self
+            .request_token(client, &[("grant_type", "client_credentials")])
+            .await?;
+        let access_token = data.access_token.clone();
+        *cached = Some(CachedToken {
+            access_token: data.access_token,
+            expires_at: Instant::now() + Duration::from_secs(data.expires_in),
+        });
+
+        Ok(access_token)
+    }
+
+    pub fn config(&self) -> &SpotifyConfig {
+        &self.config
+    }
+
+    /// URL of Spotify's consent screen; `state` comes back untouched on the callback.
+    pub fn authorize_url(&self, state: &str) -> String {
+        format!(
+            "{}?response_type=code&client_id={}&scope={}&redirect_uri={}&state={}",
+            self.config.authorize_url(),
+            urlencoding::encode(&self.client_id),
+            urlencoding::encode(USER_SCOPES),
+            urlencoding::encode(&self.config.redirect_uri),
+            urlencoding::encode(state)
+        )
+    }
+
+    /// Swaps the code from the OAuth callback for tokens and links the Spotify account to `user_id`.
+    pub async fn link_account(
+        &self,
+        client: &Client,
+        pool: &PgPool,
+        user_id: &i32,
+        code: &str,
+    ) -> Result<(), ApiError> {
+        let data = self
+            .request_token(
+                client,
+                &[
+                    ("grant_type", "authorization_code"),
+                    ("code", code),
+                    ("redirect_uri", &self.config.redirect_uri),
+                ],
+            )
+            .await?;
+
+        let refresh_token = data.refresh_token.ok_or_else(|| {
+            ApiError::Upstream("Spotify did not return a refresh token".to_string())
+        })?;
+
+        let spotify_user_id =
+            fetch_spotify_user_id(client, &self.config.api("/me"), &data.access_token).await?;
+
+        db::upsert_spotify_account(
+            pool,
+            user_id,
+            &spotify_user_id,
+            &refresh_token,
+            &data.access_token,
+            expiry_from_now(data.expires_in),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Returns a live access token for the user's linked Spotify account, refreshing it when it is about to expire.
+    pub async fn user_access_token(
+        &self,
+        client: &Client,
+        pool: &PgPool,
+        user_id: &i32,
+    ) -> Result<SpotifyUserToken, ApiError> {
+        let account = db::get_spotify_account(pool, user_id)
+            .await?
+            .ok_or_else(|| ApiError::Forbidden("Connect your Spotify account first".to_string()))?;
+
+        let margin = rocket::time::Duration::seconds(REFRESH_MARGIN.as_secs() as i64);
+        if let (Some(access_token), Some(expires_at)) =
+            (account.access_token, account.access_token_expires_at)
+        {
+            if OffsetDateTime::now_utc() + margin < expires_at {
+                return Ok(SpotifyUserToken {
+                    access_token,
+                    spotify_user_id: account.spotify_user_id,
+                });
+            }
+        }
+
+        let data = self
+            .request_token(
+                client,
+                &[
+                    ("grant_type", "refresh_token"),
+                    ("refresh_token", &account.refresh_token),
+                ],
+            )
+            .await?;
+
+        // Spotify only sometimes rotates the refresh token; keep the old one otherwise.
+        let refresh_token = data.refresh_token.unwrap_or(account.refresh_token);
+
+        db::upsert_spotify_account(
+            pool,
+            user_id,
+            &account.spotify_user_id,
+            &refresh_token,
+            &data.access_token,
+            expiry_from_now(data.expires_in),
+        )
+        .await?;
+
+        Ok(SpotifyUserToken {
+            access_token: data.access_token,
+            spotify_user_id: account.spotify_user_id,
+        })
+    }
+
+    async fn request_token(
+        &self,
+        client: &Client,
+        form: &[(&str, &str)],
+    ) -> Result<AccessTokenResponse, ApiError> {
+        let encoded = general_purpose::STANDARD
+            .encode(format!("{}:{}", self.client_id, self.client_secret));
+
+        let response = client
+            .post(self.config.token_url())
+            .header("Authorization", format!("Basic {}", encoded))
+            .form(form)
+            .send()
+            .await
+            .map_err(|err| ApiError::Upstream(format!("Failed to get access token: {}", err)))?;
+
+        if !response.status().is_success() {
+            let error_text = response.text().await.unwrap_or_default();
+            return Err(ApiError::Upstream(format!("Spotify token error: {}", error_text)));
+        }
+
+        response
+            .json()
+            .await
+            .map_err(|err| ApiError::Upstream(format!("Failed to parse token response: {}", err)))
+    }
+}
+
+fn expiry_from_now(expires_in: u64) -> OffsetDateTime {
+    OffsetDateTime::now_utc() + rocket::time::Duration::seconds(expires_in as i64)
+}
+
+async fn fetch_spotify_user_id(
+    client: &Client,
+    me_url: &str,
+    access_token: &str,
+) -> Result<String, ApiError> {
+    let response = client
+        .get(me_url)
+        .header("Authorization", format!("Bearer {}", access_token))
+        .send()
+        .await
+        .map_err(|err| ApiError::Upstream(format!("Failed to call Spotify API: {}", err)))?;
+
+    let data = response
+        .json::<serde_json::Value>()
+        .await
+        .map_err(|err| ApiError::Upstream(format!("Failed to parse Spotify API response: {}", err)))?;
+
+    data["id"]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| ApiError::Upstream(format!("Spotify profile has no id: {}", data)))
+}
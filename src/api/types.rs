@@ -1,8 +1,23 @@
+use crate::api::circles::CircleRole;
+use crate::api::compatibility::{PairSort, SortOrder};
+use crate::api::ranking::RankingMethod;
+use crate::api::scoring::ScoringWeights;
+use crate::api::seasons::SeasonStatus;
+use crate::api::similarity::{Similarity, SimilarityMetric};
 use rocket::serde::{Deserialize, Serialize};
 
 #[derive(Deserialize)]
 pub struct AccessTokenResponse {
     pub(crate) access_token: String,
+    pub(crate) expires_in: u64,
+    pub(crate) refresh_token: Option<String>,
+}
+
+#[derive(FromForm)]
+pub struct SpotifyCallbackQuery {
+    pub(crate) code: Option<String>,
+    pub(crate) state: Option<String>,
+    pub(crate) error: Option<String>,
 }
 
 // Struct to parse the query parameters
@@ -12,6 +27,20 @@ pub struct SearchSongsQuery {
     pub(crate) rank: Option<i32>,
 }
 
+#[derive(FromForm)]
+pub struct MusicTastePairsQuery {
+    pub(crate) circle: Option<i32>,
+    pub(crate) season: Option<i32>,
+    pub(crate) metric: Option<SimilarityMetric>,
+    pub(crate) sort: Option<PairSort>,
+    pub(crate) order: Option<SortOrder>,
+    pub(crate) min_score: Option<f64>,
+    pub(crate) min_overlap: Option<i64>,
+    pub(crate) user: Option<String>,
+    pub(crate) page: Option<usize>,
+    pub(crate) per_page: Option<usize>,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Song {
     pub key: Option<String>,
@@ -22,31 +51,79 @@ pub struct Song {
     pub rank: Option<i32>
 }
 
+/// A catalog track as returned by a `MusicProvider`.
+#[derive(Serialize, Debug, Clone)]
+pub struct Track {
+    pub id: String,
+    pub uri: String,
+    pub name: String,
+    pub artist: String,
+    pub album_cover_url: String,
+}
+
+/// Body of every error response; see `ApiError`.
 #[derive(Serialize, Debug)]
 pub struct ErrorResponse {
+    /// Human-readable message.
     pub(crate) error: String,
+    /// Stable machine-readable error code, e.g. `validation_failed`.
+    pub(crate) code: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) fields: Vec<FieldError>,
+    /// Quote this when reporting a server-side failure; it matches the server log entry.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) correlation_id: Option<String>,
 }
 
-#[derive(Serialize, Deserialize)]
-pub struct CreatePlaylistId {
-    pub(crate) id: String
+/// A problem with one field of the request body.
+#[derive(Serialize, Debug)]
+pub struct FieldError {
+    pub(crate) field: String,
+    pub(crate) message: String,
 }
 
-
 #[derive(Serialize, Debug)]
 pub struct CreatePlaylistBody {
     pub(crate) name: String,
     pub(crate) description: String,
-    pub(crate) public: bool
+    pub(crate) public: bool,
+    pub(crate) collaborative: bool,
+}
+
+/// Who can see and edit a published playlist.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PlaylistVisibility {
+    #[default]
+    Public,
+    Private,
+    /// Private, but anyone the owner shares it with can add tracks.
+    Collaborative,
 }
 
 #[derive(Serialize, Debug)]
 pub struct AddSongsToPlaylistBody {
     pub(crate) uris: Vec<String>,
-    pub(crate) position: i32
 }
 
-#[derive(Serialize, Deserialize, Debug, sqlx::FromRow)]
+/// One of the user's connections.
+#[derive(Serialize, Debug)]
+pub struct MusicTasteIndividual {
+    pub other_user_name: String,
+    pub overlapping_songs: Option<i64>,
+    pub song_rank_diff: Option<f64>,
+    pub song_relationship_strength: Option<f64>,
+    pub overlapping_artists: Option<i64>,
+    pub total_songs_shared_artists: Option<i64>,
+    pub artist_rank_diff: Option<f64>,
+    pub combined_score: Option<f64>,
+    pub overlapping_song_details: Option<serde_json::Value>,
+    pub overlapping_artist_details: Option<serde_json::Value>,
+    /// The score connections are ordered by, under the requested metric.
+    pub similarity: Similarity,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 pub struct MusicTasteOverview {
     pub user_1: Option<String>,
     pub user_2: Option<String>,
@@ -61,9 +138,121 @@ pub struct MusicTasteOverview {
     pub overlapping_artist_details: Option<serde_json::Value>,
 }
 
+/// A pair of voters in a listing of the whole season, `position` counting from 1 across every page.
+#[derive(Serialize, Debug)]
+pub struct MusicTastePair {
+    pub position: usize,
+    pub user_1: String,
+    pub user_2: String,
+    pub overlapping_songs: i64,
+    pub song_rank_diff: f64,
+    pub song_relationship_strength: f64,
+    pub overlapping_artists: i64,
+    pub total_songs_shared_artists: i64,
+    pub artist_rank_diff: f64,
+    pub combined_score: f64,
+    pub overlapping_song_details: serde_json::Value,
+    pub overlapping_artist_details: serde_json::Value,
+    pub similarity: Similarity,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MusicTastePairs {
+    /// Every pair with a song or artist in common.
+    pub total_pairs: usize,
+    /// The pairs left after the filters, over all pages.
+    pub matching_pairs: usize,
+    pub page: usize,
+    pub per_page: usize,
+    pub pages: usize,
+    pub pairs: Vec<MusicTastePair>,
+}
+
+/// How many picks are only on `user_a`'s ballot, on both, and only on `user_b`'s.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct VennCounts {
+    pub only_a: usize,
+    pub both: usize,
+    pub only_b: usize,
+}
+
+/// Everything about one pair of members in one season. In the details `user1` is `user_a`.
+#[derive(Serialize, Debug)]
+pub struct PairComparison {
+    pub season: SeasonRef,
+    pub user_a: String,
+    pub user_a_name: String,
+    pub user_b: String,
+    pub user_b_name: String,
+    pub overlapping_songs: i64,
+    pub song_rank_diff: f64,
+    pub song_relationship_strength: f64,
+    pub overlapping_artists: i64,
+    pub total_songs_shared_artists: i64,
+    pub artist_rank_diff: f64,
+    pub combined_score: f64,
+    pub songs: VennCounts,
+    pub artists: VennCounts,
+    pub overlapping_song_details: serde_json::Value,
+    pub overlapping_artist_details: serde_json::Value,
+    /// One entry per similarity metric.
+    pub metrics: Vec<Similarity>,
+}
+
+/// A song's place in the generated list, 1 being the top song.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RankedSong {
+    pub position: usize,
+    pub name: String,
+    pub artist: String,
+    pub uri: String,
+    pub album_cover_url: String,
+    pub score: f64,
+    pub voters: i64,
+    pub average_rank: f64,
+}
+
+/// A saved ranking, songs in countdown (playback) order.
+#[derive(Serialize, Debug)]
+pub struct PlaylistPreview {
+    pub id: i32,
+    pub circle_id: i32,
+    pub season_id: i32,
+    pub method: RankingMethod,
+    /// How many ballots the ranking was built from.
+    pub voters: i32,
+    pub songs: Vec<RankedSong>,
+}
+
+#[derive(Deserialize)]
+pub struct PreviewPlaylistRequest {
+    pub method: Option<RankingMethod>,
+}
+
+#[derive(Deserialize)]
+pub struct PublishPlaylistRequest {
+    pub preview_id: i32,
+    pub name: Option<String>,
+    /// May contain `{date}`, `{method}`, `{voters}` and `{songs}`, filled in at publish time.
+    pub description: Option<String>,
+    pub visibility: Option<PlaylistVisibility>,
+    /// Base64-encoded JPEG, at most 256 KB once encoded.
+    pub cover_image: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct PublishPlaylistResponse {
+    pub playlist_id: String,
+    pub total_batches: usize,
+    /// Batches already uploaded by an earlier attempt at publishing the same preview.
+    pub skipped_batches: usize,
+}
+
 #[derive(Deserialize)]
 pub struct LoginRequest {
     pub username: String,
+    /// Required once the user has a password. Users without one set it by logging in with it.
+    pub password: Option<String>,
 }
 
 #[derive(Deserialize)]
@@ -71,6 +260,216 @@ pub struct SignupRequest {
     pub username: String,
     pub first_name: String,
     pub last_name: String,
+    pub password: Option<String>,
+    /// Needed when registration is invite-only.
+    pub invite_code: Option<String>,
+}
+
+/// One of the user's signed-in browsers. Timestamps are RFC 3339.
+#[derive(Serialize)]
+pub struct SessionInfo {
+    pub user_agent: Option<String>,
+    pub created_at: String,
+    pub last_seen_at: String,
+    pub expires_at: String,
+    /// Whether this is the session making the request.
+    pub current: bool,
+}
+
+#[derive(Serialize)]
+pub struct LogoutEverywhereResponse {
+    pub success: bool,
+    pub sessions_ended: u64,
+}
+
+#[derive(Deserialize)]
+pub struct CreateInviteRequest {
+    /// How many signups the code is good for; 1 when left out.
+    pub max_uses: Option<i32>,
+    /// Never expires when left out.
+    pub expires_in_days: Option<i64>,
+    /// Circle the new user joins on signup.
+    pub circle_id: Option<i32>,
+}
+
+/// An invite code as admins see it. Timestamps are RFC 3339.
+#[derive(Serialize)]
+pub struct InviteInfo {
+    pub code: String,
+    pub circle_id: Option<i32>,
+    pub max_uses: i32,
+    pub uses: i32,
+    pub expires_at: Option<String>,
+    pub created_at: String,
+}
+
+#[derive(Deserialize)]
+pub struct CircleNameRequest {
+    pub name: String,
+}
+
+#[derive(Deserialize)]
+pub struct JoinCircleRequest {
+    pub join_code: String,
+}
+
+#[derive(Deserialize)]
+pub struct SetCircleRoleRequest {
+    /// Making someone `owner` hands the circle over; the current owner becomes an admin.
+    pub role: CircleRole,
+}
+
+/// One of the user's circles, as listed.
+#[derive(Serialize)]
+pub struct CircleSummary {
+    pub id: i32,
+    pub name: String,
+    pub role: CircleRole,
+    pub member_count: i64,
+}
+
+#[derive(Serialize)]
+pub struct CircleMember {
+    pub username: String,
+    pub display_name: String,
+    pub role: CircleRole,
+}
+
+#[derive(Serialize)]
+pub struct CircleDetails {
+    pub id: i32,
+    pub name: String,
+    pub role: CircleRole,
+    /// Only shown to owners and admins.
+    pub join_code: Option<String>,
+    pub members: Vec<CircleMember>,
+}
+
+/// Timestamps are RFC 3339. Voting locks and the playlist is revealed when the season closes unless
+/// `locks_at` and `reveals_at` say otherwise. Ballots hold 10 songs unless `ballot_size` says otherwise.
+#[derive(Deserialize)]
+pub struct CreateSeasonRequest {
+    pub name: String,
+    pub opens_at: String,
+    pub locks_at: Option<String>,
+    pub reveals_at: Option<String>,
+    pub closes_at: String,
+    pub ballot_size: Option<i32>,
+}
+
+/// Only the fields given change. Timestamps are RFC 3339.
+#[derive(Deserialize)]
+pub struct UpdateSeasonRequest {
+    pub name: Option<String>,
+    pub opens_at: Option<String>,
+    pub locks_at: Option<String>,
+    pub reveals_at: Option<String>,
+    pub closes_at: Option<String>,
+    pub ballot_size: Option<i32>,
+}
+
+/// Timestamps are RFC 3339.
+#[derive(Serialize)]
+pub struct SeasonInfo {
+    pub id: i32,
+    pub name: String,
+    pub opens_at: String,
+    pub locks_at: String,
+    pub reveals_at: String,
+    pub closes_at: String,
+    pub ballot_size: i32,
+    pub status: SeasonStatus,
+    /// Whether the playlist was generated when voting locked.
+    pub playlist_generated: bool,
+}
+
+/// Only the weights given change.
+#[derive(Deserialize)]
+pub struct ScoringWeightsRequest {
+    pub shared_song_points: Option<f64>,
+    pub shared_artist_points: Option<f64>,
+    pub artist_rank_diff_weight: Option<f64>,
+    pub rank_bonus: Option<f64>,
+}
+
+/// Where a pair of voters would move on the season's compatibility leaderboard. `movement` is how many
+/// places they would climb.
+#[derive(Serialize)]
+pub struct PairMovement {
+    pub user_1: String,
+    pub user_2: String,
+    pub current_position: usize,
+    pub proposed_position: usize,
+    pub movement: i64,
+    pub current_score: f64,
+    pub proposed_score: f64,
+}
+
+/// Where a song would move in the season's playlist, top song first. `movement` is how many places it
+/// would climb.
+#[derive(Serialize)]
+pub struct SongMovement {
+    pub name: String,
+    pub artist: String,
+    pub current_position: usize,
+    pub proposed_position: usize,
+    pub movement: i64,
+    pub current_score: f64,
+    pub proposed_score: f64,
+}
+
+#[derive(Serialize)]
+pub struct WeightsPreview {
+    pub current: ScoringWeights,
+    pub proposed: ScoringWeights,
+    /// In proposed order.
+    pub pairs: Vec<PairMovement>,
+    /// In proposed order, ranked the classic way.
+    pub songs: Vec<SongMovement>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SeasonRef {
+    pub id: i32,
+    pub name: String,
+}
+
+/// An artist on both ballots, with their best rank on each.
+#[derive(Serialize)]
+pub struct PersistedArtist {
+    pub artist: String,
+    pub from_rank: i32,
+    pub to_rank: i32,
+}
+
+#[derive(Serialize)]
+pub struct CarriedOverSong {
+    pub name: String,
+    pub artist: String,
+    pub from_rank: i32,
+    pub to_rank: i32,
+}
+
+/// Scores are missing for a season where either of the two has no ballot.
+#[derive(Serialize)]
+pub struct CompatibilityChange {
+    pub username: String,
+    pub display_name: Option<String>,
+    pub from_score: Option<f64>,
+    pub to_score: Option<f64>,
+    pub change: Option<f64>,
+}
+
+#[derive(Serialize)]
+pub struct TasteDriftReport {
+    pub from_season: SeasonRef,
+    pub to_season: SeasonRef,
+    /// 0 when the ballot stayed the same, 1 when nothing on it stayed.
+    pub drift: f64,
+    pub persisted_artists: Vec<PersistedArtist>,
+    pub carried_over_songs: Vec<CarriedOverSong>,
+    /// Biggest gains first.
+    pub compatibility: Vec<CompatibilityChange>,
 }
 
 #[derive(Serialize)]
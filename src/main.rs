@@ -4,15 +4,23 @@ mod api;
 
 use dotenv::dotenv;
 use reqwest::Client;
-use rocket::fairing::{AdHoc, Fairing, Info, Kind};
+use rocket::fairing::AdHoc;
 use rocket::fs::FileServer;
 use rocket::tokio::sync::OnceCell;
-use sqlx::{ConnectOptions, Connection};
+use rocket::{Build, Rocket};
+use sqlx::migrate::Migrator;
 use sqlx_postgres::{PgPool, PgPoolOptions};
-use crate::api::{auth_api, internal_api};
+use crate::api::credentials::AuthConfig;
+use crate::api::external_api::SpotifyProvider;
+use crate::api::music_provider::MusicProvider;
+use crate::api::spotify_token::{SpotifyConfig, SpotifyTokenManager};
+use std::sync::Arc;
+use crate::api::{auth_api, circle_api, internal_api};
 
 static DB_POOL: OnceCell<PgPool> = OnceCell::const_new();
 
+static MIGRATOR: Migrator = sqlx::migrate!();
+
 async fn init_pool() -> PgPool {
     let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set");
     PgPoolOptions::new()
@@ -23,20 +31,60 @@ async fn init_pool() -> PgPool {
 
 }
 
-#[launch]
-fn rocket() -> _ {
+/// Applies pending migrations. sqlx refuses to touch a database that has migrations this binary does not
+/// know about, so an older build never runs against a newer schema.
+async fn run_migrations(pool: &PgPool) {
+    MIGRATOR.run(pool).await.expect("Failed to run migrations");
+}
+
+#[rocket::main]
+async fn main() {
     dotenv().ok();
+
+    if std::env::args().any(|arg| arg == "--migrate-only") {
+        let pool = init_pool().await;
+        run_migrations(&pool).await;
+        println!("Migrations applied");
+        return;
+    }
+
+    if let Err(err) = rocket().launch().await {
+        panic!("Rocket failed to launch: {:?}", err);
+    }
+}
+
+fn rocket() -> Rocket<Build> {
     let static_dir = std::env::var("STATIC_DIR").expect("STATIC_DIR must be set");
 
     let figment = rocket::Config::figment()
         .merge(("port", 8080))
         .merge(("address", "0.0.0.0"));
-    rocket::custom(figment)
+    let rocket = rocket::custom(figment)
         .attach(AdHoc::on_ignite("Database Pool", |rocket| async {
             let pool = init_pool().await;
+            run_migrations(&pool).await;
             DB_POOL.set(pool).unwrap();
             rocket }))
-        .manage(Client::new())
+        .attach(AdHoc::on_ignite("Auth Config", |rocket| async {
+            let config: AuthConfig = rocket
+                .figment()
+                .focus("auth")
+                .extract()
+                .expect("Invalid auth config");
+            rocket.manage(config)
+        }))
+        .attach(AdHoc::on_ignite("Spotify Tokens", |rocket| async {
+            let config: SpotifyConfig = rocket
+                .figment()
+                .focus("spotify")
+                .extract()
+                .expect("Invalid spotify config");
+            let client = Client::new();
+            let tokens = Arc::new(SpotifyTokenManager::from_env(config));
+            let provider: Box<dyn MusicProvider> =
+                Box::new(SpotifyProvider::new(client.clone(), tokens.clone()));
+            rocket.manage(client).manage(tokens).manage(provider)
+        }))
         .mount("/", routes![
             internal_api::index,
             internal_api::login_page_static,
@@ -44,16 +92,66 @@ fn rocket() -> _ {
             internal_api::main_page,
             internal_api::files,
             internal_api::search_songs,
+            internal_api::get_track,
             internal_api::save_songs,
             internal_api::get_songs,
-            internal_api::generate_playlist,
+            internal_api::preview_playlist,
+            internal_api::get_playlist_preview,
+            internal_api::publish_playlist,
             internal_api::get_music_taste,
             internal_api::get_music_taste_user,
+            internal_api::get_music_taste_pairs,
+            internal_api::compare_users,
+            internal_api::get_taste_drift,
             internal_api::connections_page,
             auth_api::login,
             auth_api::signup,
-            auth_api::logout
+            auth_api::logout,
+            auth_api::logout_everywhere,
+            auth_api::list_sessions,
+            auth_api::create_invite,
+            auth_api::list_invites,
+            auth_api::delete_invite,
+            auth_api::spotify_login,
+            auth_api::spotify_callback,
+            circle_api::list_circles,
+            circle_api::create_circle,
+            circle_api::get_circle,
+            circle_api::rename_circle,
+            circle_api::join_circle,
+            circle_api::rotate_join_code,
+            circle_api::leave_circle,
+            circle_api::set_member_role,
+            circle_api::remove_member,
+            circle_api::list_seasons,
+            circle_api::create_season,
+            circle_api::update_season,
+            circle_api::get_season_playlist,
+            circle_api::get_circle_weights,
+            circle_api::update_circle_weights,
+            circle_api::get_season_weights,
+            circle_api::update_season_weights,
+            circle_api::preview_season_weights
         ])
-        .mount("/main", FileServer::from(static_dir))
+        .register(
+            "/",
+            catchers![
+                api::sessions::unauthorized,
+                api::sessions::forbidden,
+                api::error::bad_request,
+                api::error::not_found,
+                api::error::unprocessable_entity,
+                api::error::internal_error,
+                api::error::any_error
+            ],
+        )
+        .attach(api::sessions::sweeper())
+        .attach(api::seasons::scheduler())
+        .mount("/main", FileServer::from(static_dir));
+
+    #[cfg(feature = "mock-spotify")]
+    let rocket = rocket.attach(api::mock_spotify::stage());
+
+    rocket
 
 }
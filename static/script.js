@@ -13,9 +13,12 @@ const rankInput = document.getElementById('rank-input');
 const searchResults = document.getElementById('search-results');
 const rankingsList = document.getElementById('rankings-list');
 const saveBtn = document.getElementById('save-btn');
+const circleSelect = document.getElementById('circle-select');
 
 // Initialize
-window.onload = () => {
+window.onload = async () => {
+  await loadCircles();
+  loadBallotSize();
   loadSavedSongs();
   setupEventListeners();
 };
@@ -23,12 +26,115 @@ window.onload = () => {
 function setupEventListeners() {
   searchForm.addEventListener('submit', handleSearch);
   saveBtn.addEventListener('click', handleSave);
+  circleSelect.addEventListener('change', handleCircleChange);
+}
+
+// The circle being ranked in is remembered across pages and sent as ?circle=
+function circleQuery() {
+  const circle = localStorage.getItem('circle');
+  return circle ? `?circle=${circle}` : '';
+}
+
+async function loadCircles() {
+  try {
+    const response = await fetch('/api/circles');
+    if (!response.ok) return;
+
+    const circles = await response.json();
+    const current = localStorage.getItem('circle');
+    if (!circles.some(circle => String(circle.id) === current)) {
+      localStorage.removeItem('circle');
+    }
+
+    circleSelect.innerHTML = '';
+    circles.forEach(circle => {
+      const option = document.createElement('option');
+      option.value = circle.id;
+      option.textContent = circle.name;
+      option.selected = String(circle.id) === localStorage.getItem('circle');
+      circleSelect.appendChild(option);
+    });
+    circleSelect.insertAdjacentHTML('beforeend', `
+      <option value="join">Join a circle…</option>
+      <option value="create">Create a circle…</option>
+    `);
+    if (circles.length === 0) {
+      circleSelect.value = '';
+    }
+  } catch (error) {
+    console.error('Error loading circles:', error);
+  }
+}
+
+// Ballots hold as many songs as the current season allows
+async function loadBallotSize() {
+  const circle = circleSelect.value;
+  if (!/^\d+$/.test(circle)) return;
+
+  try {
+    const response = await fetch(`/api/circles/${circle}/seasons`);
+    if (!response.ok) return;
+
+    // Same pick as the server: the season running now, else the latest one that has opened
+    const seasons = (await response.json()).filter(season => season.status !== 'upcoming');
+    const season = seasons.find(season => season.status !== 'closed') || seasons[0];
+    if (!season) return;
+
+    rankInput.max = season.ballot_size;
+    document.getElementById('rankings-title').textContent = `Your Top ${season.ballot_size}`;
+  } catch (error) {
+    console.error('Error loading season:', error);
+  }
+}
+
+async function handleCircleChange() {
+  const choice = circleSelect.value;
+  let request = null;
+
+  if (choice === 'join') {
+    const joinCode = prompt('Join code:');
+    if (joinCode) request = { url: '/api/circles/join', body: { join_code: joinCode } };
+  } else if (choice === 'create') {
+    const name = prompt('Circle name:');
+    if (name) request = { url: '/api/circles', body: { name } };
+  } else {
+    localStorage.setItem('circle', choice);
+    window.location.reload();
+    return;
+  }
+
+  if (request) {
+    const response = await fetch(request.url, {
+      method: 'POST',
+      headers: { 'Content-Type': 'application/json' },
+      body: JSON.stringify(request.body)
+    });
+    const data = await response.json();
+    if (response.ok) {
+      localStorage.setItem('circle', data.id);
+    } else {
+      alert(data.error);
+    }
+  }
+  window.location.reload();
 }
 
 // Load saved songs from server
 async function loadSavedSongs() {
   try {
-    const response = await fetch('/songs');
+    const response = await fetch(`/songs${circleQuery()}`);
+
+    if (response.status === 401) {
+      window.location.href = '/login';
+      return;
+    }
+
+    if (!response.ok) {
+      const data = await response.json();
+      alert(data.error);
+      return;
+    }
+
     const songs = await response.json();
 
     songs.forEach(song => {
@@ -206,7 +312,7 @@ async function handleSave() {
   saveBtn.textContent = 'Saving...';
 
   try {
-    const response = await fetch('/songs', {
+    const response = await fetch(`/songs${circleQuery()}`, {
       method: 'POST',
       headers: { 'Content-Type': 'application/json' },
       body: JSON.stringify(Array.from(rankedSongs.values()))
@@ -214,8 +320,11 @@ async function handleSave() {
 
     if (response.ok) {
       alert('Songs saved successfully!');
+    } else if (response.status === 401) {
+      window.location.href = '/login';
     } else {
-      throw new Error('Failed to save');
+      const data = await response.json();
+      alert(data.error || 'Error saving songs. Please try again.');
     }
   } catch (error) {
     console.error('Error saving:', error);